@@ -0,0 +1,1309 @@
+use std::sync::mpsc;
+use std::thread;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+use crate::error::ZapError;
+use crate::progress::{Progress, StreamProgress, TransferEvent};
+use crate::protocol::HelperClient;
+use crate::transport::{DataChannel, Transport};
+use crate::ssh::{
+    SessionConfig, StreamResult, connect_and_auth, negotiated_methods, remote_file_stat, prepare_remote_file,
+    rename_remote_file, copy_remote_file, copy_local, remove_remote_file, resolve_remote_destination, create_remote_dirs, set_remote_stat, local_file_stat, apply_local_stat,
+    running_as_root, preallocate_local, remote_file_hash, local_free_space, remote_free_space, hash_local_range, pull_worker, push_worker, copy_worker,
+    pull_chunks_worker, push_chunks_worker, run_remote_command, KeepAlive, STREAM_CHUNK_SIZE,
+};
+use crate::utils::{ExistingPolicy, Messages, Output, TransferOptions, TransferOutcome, TransferReport, format_size, skip_reason};
+
+struct TransferStats {
+    start_time: Instant,
+    total_bytes: usize,
+    streams_completed: usize,
+    /// Payload bytes that actually crossed the network
+    bytes_sent: u64,
+    /// Bytes never sent because they were holes in the source
+    holes_skipped: u64,
+    /// Bytes of zero blocks left as holes at the destination
+    zeros_skipped: u64,
+    /// Per stream (payload bytes, bytes on the wire), when compression was metered
+    wire_bytes: Vec<Option<(u64, u64)>>,
+}
+
+impl TransferStats {
+    /// Stats for a transfer of `total_bytes` starting now
+    fn new(total_bytes: u64, num_streams: usize) -> Self {
+        TransferStats {
+            start_time: Instant::now(),
+            total_bytes: total_bytes as usize,
+            streams_completed: 0,
+            bytes_sent: 0,
+            holes_skipped: 0,
+            zeros_skipped: 0,
+            wire_bytes: vec![None; num_streams],
+        }
+    }
+
+    /// Count what a finished stream moved
+    fn add(&mut self, stream_num: usize, stream: &StreamResult) {
+        self.streams_completed += 1;
+        self.bytes_sent += stream.bytes_sent;
+        self.holes_skipped += stream.holes_skipped;
+        self.zeros_skipped += stream.zeros_skipped;
+        self.wire_bytes[stream_num] = stream.wire_bytes.map(|wire| (stream.bytes_sent, wire));
+    }
+
+    fn report(&self, opts: &TransferOptions, source: String, destination: String) -> TransferReport {
+        let duration = self.start_time.elapsed();
+        TransferReport {
+            source,
+            destination,
+            outcome: TransferOutcome::Completed,
+            total_bytes: self.total_bytes as u64,
+            bytes_sent: self.bytes_sent,
+            duration,
+            streams: opts.num_streams,
+            transport: opts.transport,
+            holes_skipped: self.holes_skipped,
+            zeros_skipped: opts.sparse.then_some(self.zeros_skipped),
+            delta: opts.delta,
+            wire_bytes: self.wire_bytes.clone(),
+            stream_reports: opts.progress.stream_reports(),
+            peak_speed: opts.progress.peak_speed().max(self.total_bytes as f64 / duration.as_secs_f64()),
+            cipher: opts.cipher.clone(),
+            remote: None,
+        }
+    }
+}
+
+/// For the TCP and QUIC transports, have the helper open a data listener.
+/// The returned control connection keeps the remote helper (and so the
+/// listener) alive and must be held until every stream has finished.
+fn setup_data_channel(
+    sess: &ssh2::Session,
+    cfg: &mut SessionConfig,
+    opts: &mut TransferOptions,
+) -> io::Result<Option<HelperClient>> {
+    if opts.transport == Transport::Ssh {
+        return Ok(None);
+    }
+    let Some(ref command) = cfg.helper else {
+        opts.messages.warn(&format!("--transport {} needs zap on the remote host; using SSH", opts.transport.describe()));
+        opts.transport = Transport::Ssh;
+        return Ok(None);
+    };
+
+    let mut control = HelperClient::exec(sess, command)?;
+    match DataChannel::negotiate(&mut control, &cfg.host, opts.transport) {
+        Ok(channel) => {
+            if let Some(ref channel) = channel {
+                if !opts.quiet {
+                    opts.messages.info(&format!("Data channel: {} on port {}", opts.transport.describe(), channel.port()));
+                }
+            }
+            cfg.data_channel = channel;
+            Ok(Some(control))
+        },
+        Err(e) if e.kind() == io::ErrorKind::Unsupported => {
+            opts.messages.warn(&format!("{}; using SSH", e));
+            opts.transport = Transport::Ssh;
+            Ok(None)
+        },
+        Err(e) => Err(e),
+    }
+}
+
+/// Streams write here first; the file is renamed into place only once every
+/// stream has succeeded, so a partial transfer never looks complete
+fn temp_file_name(file_name: &str) -> String {
+    format!(".{}.zap-tmp", file_name)
+}
+
+/// `create_dir_all`, but reporting each directory it had to make
+fn create_local_dirs(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let missing: Vec<PathBuf> = dir.ancestors()
+        .take_while(|p| !p.as_os_str().is_empty() && !p.exists())
+        .map(Path::to_path_buf)
+        .collect();
+    fs::create_dir_all(dir).map_err(ZapError::local(dir))?;
+    Ok(missing.into_iter().rev().collect())
+}
+
+/// The directory a destination names: itself when written with a trailing
+/// slash, otherwise its parent
+fn destination_dir(dest: &str) -> Option<&str> {
+    if dest.ends_with('/') {
+        return Some(dest);
+    }
+    dest.rsplit_once('/').map(|(dir, _)| dir).filter(|dir| !dir.is_empty())
+}
+
+/// Temp file next to a remote target path
+fn remote_temp_path(target: &str) -> String {
+    match target.rsplit_once('/') {
+        Some((dir, name)) => format!("{}/{}", dir, temp_file_name(name)),
+        None => temp_file_name(target),
+    }
+}
+
+/// Refuse to start a transfer that cannot fit, unless `--force` was given.
+/// `free` is None when the destination can't tell us.
+fn check_free_space(free: Option<u64>, needed: u64, destination: &str, opts: &TransferOptions) -> io::Result<()> {
+    let Some(free) = free else {
+        if opts.verbose {
+            opts.messages.info(&format!("Free space for {} unknown; skipping the check", destination));
+        }
+        return Ok(());
+    };
+    if free >= needed {
+        return Ok(());
+    }
+    if opts.force {
+        opts.messages.warn(&format!("Not enough free space for {}: {} needed, {} available (continuing because of --force)",
+                                    destination, format_size(needed as usize), format_size(free as usize)));
+        return Ok(());
+    }
+    Err(ZapError::NoSpace { destination: destination.to_string(), needed, available: free }.into())
+}
+
+/// The error for a transfer stopped by its CancelToken, saying how far it got
+fn cancelled(opts: &TransferOptions, total: Option<u64>) -> io::Error {
+    ZapError::Cancelled { done: opts.progress.position(), total }.into()
+}
+
+/// One slot per stream for the error it gave up with
+fn no_failures(num_streams: usize) -> Vec<Option<io::Error>> {
+    (0..num_streams).map(|_| None).collect()
+}
+
+/// Fail the transfer if any stream did, keeping the first stream's error
+fn streams_failed(failures: Vec<Option<io::Error>>) -> io::Result<()> {
+    let streams = failures.len();
+    let mut failed = failures.into_iter().flatten().map(ZapError::from);
+    let Some(first) = failed.next() else {
+        return Ok(());
+    };
+    let count = 1 + failed.count();
+    Err(ZapError::Partial { failed: count, streams, first: Box::new(first) }.into())
+}
+
+/// Log what `run_segments` is about to do with a `file_size` byte file
+fn describe_segments(file_size: u64, opts: &TransferOptions) {
+    let num_streams = opts.num_streams as u64;
+    opts.messages.info(&format!("Using {} streams of approximately {} each",
+                                num_streams, format_size((file_size / num_streams) as usize)));
+    let extra_bytes = file_size % num_streams;
+    if extra_bytes > 0 {
+        opts.messages.info(&format!("Last stream will have an additional {} bytes", extra_bytes));
+    }
+}
+
+/// Split a `file_size` byte file into one segment per stream, the last
+/// one taking the remainder, and run `worker(stream_num, start, end,
+/// progress)` on each segment in a thread of its own. Returns what the
+/// streams moved, or the transfer's error if any of them failed.
+fn run_segments<F>(file_size: u64, opts: &TransferOptions, worker: F) -> io::Result<TransferStats>
+where
+    F: Fn(usize, u64, u64, StreamProgress) -> io::Result<StreamResult> + Sync,
+{
+    let num_streams = opts.num_streams;
+    let stream_size = file_size / num_streams as u64;
+    let mut stats = TransferStats::new(file_size, num_streams);
+    let mut failures = no_failures(num_streams);
+
+    opts.progress.started(num_streams, Some(file_size));
+    thread::scope(|scope| {
+        let worker = &worker;
+        let handles: Vec<_> = (0..num_streams)
+            .map(|stream_num| {
+                let start = stream_num as u64 * stream_size;
+                let end = if stream_num == num_streams - 1 { file_size } else { start + stream_size };
+                let progress = opts.progress.stream(stream_num, Some(end - start));
+                scope.spawn(move || worker(stream_num, start, end, progress))
+            })
+            .collect();
+        for (stream_num, handle) in handles.into_iter().enumerate() {
+            match joined(handle.join()) {
+                Ok(stream) => stats.add(stream_num, &stream),
+                Err(e) => stream_failed(opts, &mut failures, stream_num, e),
+            }
+        }
+    });
+    streams_failed(failures)?;
+    Ok(stats)
+}
+
+/// A stream thread's result, with a panic failing the stream like any error
+fn joined<T>(result: thread::Result<io::Result<T>>) -> io::Result<T> {
+    result.unwrap_or_else(|_| Err(io::Error::other("Stream panicked")))
+}
+
+/// Keep the error a stream gave up with, and report it unless the transfer
+/// was cancelled, which stops every stream the same way
+fn stream_failed(opts: &TransferOptions, failures: &mut [Option<io::Error>], stream_num: usize, e: io::Error) {
+    if !opts.cancel.is_cancelled() {
+        // Streams that retry already say which stream gave up
+        if matches!(ZapError::within(&e), Some(ZapError::Stream { .. })) {
+            opts.messages.error(&e.to_string());
+        } else {
+            opts.messages.error(&format!("Stream {}: {}", stream_num, e));
+        }
+    }
+    failures[stream_num] = Some(e);
+}
+
+/// Keep the first session's cipher for the report, and with --verbose show
+/// everything the session negotiated
+fn note_negotiated_methods(sess: &ssh2::Session, opts: &mut TransferOptions) {
+    let methods = negotiated_methods(sess);
+    if opts.cipher.is_none() {
+        opts.cipher = methods.iter().find(|(name, _)| *name == "Cipher").map(|(_, value)| value.clone());
+    }
+    if opts.verbose {
+        for (name, value) in methods {
+            opts.messages.info(&format!("SSH {:<10} {}", format!("{}:", name), value));
+        }
+    }
+}
+
+/// --delta hashes blocks on the remote side, so it needs the helper. Quietly
+/// sending the whole file instead would defeat the point of asking for it.
+fn check_delta(cfg: &SessionConfig, opts: &TransferOptions) -> io::Result<()> {
+    if opts.delta && cfg.helper.is_none() {
+        return Err(ZapError::Usage("--delta needs zap on the remote host; install it there, point --remote-zap at it, or drop --delta".to_string()).into());
+    }
+    Ok(())
+}
+
+
+/// Reserving space needs the helper; over SFTP the file is extended sparsely
+fn warn_sftp_preallocate(cfg: &SessionConfig, path: &str, opts: &TransferOptions) {
+    if opts.preallocate && cfg.helper.is_none() {
+        opts.messages.warn(&format!("cannot preallocate over SFTP; extending {} sparsely", path));
+    }
+}
+
+/// Decide whether this transfer can use `zap --server` on the remote side
+fn resolve_helper(sess: &ssh2::Session, cfg: &mut SessionConfig, opts: &TransferOptions) {
+    if let Some(command) = cfg.helper.take() {
+        if HelperClient::probe(sess, &command) {
+            if !opts.quiet {
+                opts.messages.info(&format!("Using remote zap helper ({})", command));
+            }
+            cfg.helper = Some(command);
+        } else if !opts.quiet {
+            opts.messages.info("Remote zap helper not available, falling back to SFTP");
+        }
+    }
+}
+
+/// Pull transfer: remote → local using the zap helper or SFTP
+pub fn split_and_copy_from_remote(
+    remote_file: &str,
+    local_path: &str,
+    cfg: &SessionConfig,
+    opts: &TransferOptions,
+) -> io::Result<TransferReport> {
+    let quiet_mode = opts.quiet;
+    let mut cfg = cfg.clone();
+    let mut opts = opts.clone();
+    let opts = &mut opts;
+    let source = format!("{}:{}", cfg.host, remote_file);
+
+    if !quiet_mode {
+        opts.messages.info(&format!("Preparing to transfer {}...", remote_file));
+    }
+
+    // Get remote file metadata
+    let (remote_stat, _data_control) = {
+        let sess = connect_and_auth(&cfg)?;
+        note_negotiated_methods(&sess, opts);
+        resolve_helper(&sess, &mut cfg, opts);
+        check_delta(&cfg, opts)?;
+        let data_control = setup_data_channel(&sess, &mut cfg, opts)?;
+        (remote_file_stat(&sess, &cfg, remote_file)?, data_control)
+    };
+    let file_size = remote_stat.size;
+
+    // Create missing destination directories
+    if opts.mkdir {
+        if let Some(dir) = destination_dir(local_path) {
+            for created in create_local_dirs(Path::new(dir))? {
+                if !quiet_mode {
+                    opts.messages.info(&format!("Created directory {}", created.display()));
+                }
+            }
+        }
+    }
+
+    // Determine output file path
+    let file_name = Path::new(remote_file)
+        .file_name()
+        .ok_or_else(|| ZapError::Usage("Invalid remote file path".to_string()))?
+        .to_str()
+        .ok_or_else(|| ZapError::Usage("Invalid file name".to_string()))?;
+    let output_path = if Path::new(local_path).is_dir() {
+        Path::new(local_path).join(file_name)
+    } else {
+        Path::new(local_path).to_path_buf()
+    };
+    let output_name = output_path
+        .file_name()
+        .and_then(|n| n.to_str())
+        .ok_or_else(|| ZapError::Usage("Invalid destination file name".to_string()))?;
+    let temp_path = output_path.with_file_name(temp_file_name(output_name));
+
+    // Leave an existing destination alone if the policy says so
+    let skip = skip_reason(opts.existing, &remote_stat, local_file_stat(&output_path).ok(), || {
+        let sess = connect_and_auth(&cfg)?;
+        let Some(remote_hash) = remote_file_hash(&sess, &cfg, remote_file, file_size)? else {
+            return Ok(None);
+        };
+        Ok(Some(remote_hash == hash_local_range(&fs::File::open(&output_path)?, 0, file_size)?))
+    })?;
+    if let Some(reason) = skip {
+        if !quiet_mode {
+            opts.messages.info(&format!("Skipping {}: {}", output_path.display(), reason));
+        }
+        return Ok(TransferReport::skipped(opts, source, output_path.display().to_string(), reason));
+    }
+
+    // The old destination stays until the final rename, so the whole file
+    // must fit next to it. That covers the copy --delta patches, which
+    // needs the full size where it can't be a clone; a delta run reusing an
+    // earlier temp file only grows it.
+    let dest_dir = match output_path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let needed = match fs::metadata(&temp_path) {
+        Ok(meta) if opts.delta && meta.is_file() => file_size.saturating_sub(meta.len()),
+        _ => file_size,
+    };
+    check_free_space(local_free_space(dest_dir).ok(), needed, &output_path.display().to_string(), opts)?;
+
+    if !quiet_mode {
+        opts.messages.info(&format!("Remote file size: {} ({})", format_size(file_size as usize), file_size));
+        describe_segments(file_size, opts);
+        opts.messages.info("Initializing transfer...");
+    }
+
+    // With --delta the temp file starts as a copy of the existing file and
+    // is patched in place, so the destination is untouched until the rename.
+    // A temp file left by an interrupted delta run is reused as is.
+    if opts.delta && output_path.is_file() && !temp_path.exists() {
+        copy_local(&output_path, &temp_path).map_err(ZapError::local(&temp_path))?;
+    }
+
+    // Create local temp file and extend to full size (sparse)
+    let local_file = fs::OpenOptions::new()
+        .create(true)
+        .truncate(!opts.delta)
+        .write(true)
+        .read(true)
+        .open(&temp_path)
+        .map_err(ZapError::local(&temp_path))?;
+    local_file.set_len(file_size)?;
+    if opts.preallocate {
+        preallocate_local(&local_file, file_size).map_err(|e| {
+            let _ = fs::remove_file(&temp_path);
+            io::Error::new(e.kind(), format!("Failed to preallocate {} for {}: {}", format_size(file_size as usize), output_path.display(), e))
+        })?;
+    }
+
+    let result = run_segments(file_size, opts, |stream_num, start, end, progress| {
+        pull_worker(stream_num, start, end, remote_file, &cfg, &local_file, opts.delta, opts.sparse, &opts.cancel, progress)
+    });
+    let stats = match result {
+        Ok(stats) => stats,
+        Err(e) => {
+            if opts.cancel.is_cancelled() {
+                // Only --delta can resume from the partial file, and that needs the helper
+                if cfg.helper.is_some() {
+                    local_file.sync_all()?;
+                    opts.messages.warn(&format!("Partial file kept at {}; run again with --delta to resume", temp_path.display()));
+                } else {
+                    let _ = fs::remove_file(&temp_path);
+                }
+                return Err(cancelled(opts, Some(file_size)));
+            }
+            if opts.delta {
+                opts.messages.warn(&format!("Partial file kept at {} for the next --delta run", temp_path.display()));
+            } else {
+                let _ = fs::remove_file(&temp_path);
+            }
+            return Err(e);
+        },
+    };
+
+    // Sync file to disk, then move it into place
+    if opts.fsync {
+        local_file.sync_all()?;
+    }
+    if opts.preserve {
+        apply_local_stat(&temp_path, &remote_stat, running_as_root())?;
+    }
+    fs::rename(&temp_path, &output_path).map_err(ZapError::local(&output_path))?;
+
+    Ok(stats.report(opts, source, output_path.display().to_string()))
+}
+
+/// Push transfer: local → remote using the zap helper or SFTP
+pub fn split_and_copy_binary_file(
+    input_file: &str,
+    remote_path: &str,
+    cfg: &SessionConfig,
+    opts: &TransferOptions,
+) -> io::Result<TransferReport> {
+    let quiet_mode = opts.quiet;
+    let mut cfg = cfg.clone();
+    let mut opts = opts.clone();
+    let opts = &mut opts;
+
+    if !quiet_mode {
+        opts.messages.info(&format!("Preparing to transfer {}...", input_file));
+    }
+
+    // Get local file metadata
+    let local_stat = local_file_stat(Path::new(input_file))?;
+    let file_size = local_stat.size;
+
+    if !quiet_mode {
+        opts.messages.info(&format!("Local file size: {} ({})", format_size(file_size as usize), file_size));
+        describe_segments(file_size, opts);
+        opts.messages.info("Initializing transfer...");
+    }
+
+    // Determine remote file path
+    let file_name = Path::new(input_file)
+        .file_name()
+        .ok_or_else(|| ZapError::Usage("Invalid input file path".to_string()))?
+        .to_str()
+        .ok_or_else(|| ZapError::Usage("Invalid file name".to_string()))?;
+
+    // Create and extend remote file
+    let (remote_file, temp_file, _data_control) = {
+        let sess = connect_and_auth(&cfg)?;
+        note_negotiated_methods(&sess, opts);
+        resolve_helper(&sess, &mut cfg, opts);
+        check_delta(&cfg, opts)?;
+        if opts.mkdir {
+            if let Some(dir) = destination_dir(remote_path) {
+                for created in create_remote_dirs(&sess, dir)? {
+                    if !quiet_mode {
+                        opts.messages.info(&format!("Created remote directory {}", created));
+                    }
+                }
+            }
+        }
+        let remote_file = resolve_remote_destination(&sess, &cfg, remote_path, file_name)?;
+        let skip = skip_reason(opts.existing, &local_stat, remote_file_stat(&sess, &cfg, &remote_file).ok(), || {
+            let Some(remote_hash) = remote_file_hash(&sess, &cfg, &remote_file, file_size)? else {
+                return Ok(None);
+            };
+            Ok(Some(remote_hash == hash_local_range(&fs::File::open(input_file)?, 0, file_size)?))
+        })?;
+        if let Some(reason) = skip {
+            if !quiet_mode {
+                opts.messages.info(&format!("Skipping {}: {}", remote_file, reason));
+            }
+            return Ok(TransferReport::skipped(opts, input_file.to_string(), format!("{}:{}", cfg.host, remote_file), reason));
+        }
+        let temp_file = remote_temp_path(&remote_file);
+        let leftover = if opts.delta {
+            remote_file_stat(&sess, &cfg, &temp_file).ok().filter(|st| !st.is_dir)
+        } else {
+            None
+        };
+        let needed = leftover.map_or(file_size, |st| file_size.saturating_sub(st.size));
+        let destination = format!("{}:{}", cfg.host, remote_file);
+        check_free_space(remote_free_space(&sess, &cfg, &temp_file, &remote_file)?, needed, &destination, opts)?;
+        // As for pulls, --delta patches a copy and the destination stays put
+        if opts.delta && leftover.is_none() && remote_file_stat(&sess, &cfg, &remote_file).is_ok_and(|st| !st.is_dir) {
+            copy_remote_file(&sess, &cfg, &remote_file, &temp_file)?;
+        }
+        warn_sftp_preallocate(&cfg, &temp_file, opts);
+        prepare_remote_file(&sess, &cfg, &temp_file, file_size, opts.delta, opts.preallocate)?;
+        (remote_file, temp_file, setup_data_channel(&sess, &mut cfg, opts)?)
+    };
+
+    let result = run_segments(file_size, opts, |stream_num, start, end, progress| {
+        push_worker(stream_num, start, end, input_file, &temp_file, &cfg, opts.delta, opts.sparse, opts.fsync, &opts.cancel, progress)
+    });
+    let stats = match result {
+        Ok(stats) => stats,
+        Err(e) => {
+            if opts.cancel.is_cancelled() {
+                if cfg.helper.is_some() {
+                    opts.messages.warn(&format!("Partial file kept at {}:{}; run again with --delta to resume", cfg.host, temp_file));
+                } else if let Ok(sess) = connect_and_auth(&cfg) {
+                    remove_remote_file(&sess, &temp_file);
+                }
+                return Err(cancelled(opts, Some(file_size)));
+            }
+            if opts.delta {
+                opts.messages.warn(&format!("Partial file kept at {} for the next --delta run", temp_file));
+            } else if let Ok(sess) = connect_and_auth(&cfg) {
+                remove_remote_file(&sess, &temp_file);
+            }
+            return Err(e);
+        },
+    };
+
+    // Move the finished file into place
+    let sess = connect_and_auth(&cfg)?;
+    if opts.preserve {
+        set_remote_stat(&sess, &cfg, &temp_file, &local_stat, cfg.user == "root")?;
+    }
+    rename_remote_file(&sess, &cfg, &temp_file, &remote_file)?;
+
+    Ok(stats.report(opts, input_file.to_string(), format!("{}:{}", cfg.host, remote_file)))
+}
+
+/// Bytes handled by stream `stream_num` when chunks are dealt round-robin
+fn chunked_stream_len(stream_num: usize, num_streams: usize, size: u64) -> u64 {
+    let chunk = STREAM_CHUNK_SIZE as u64;
+    (stream_num as u64..size.div_ceil(chunk))
+        .step_by(num_streams)
+        .map(|k| std::cmp::min(size, (k + 1) * chunk) - k * chunk)
+        .sum()
+}
+
+/// Fill `buf` from `input` as far as it will go, returning how much was read;
+/// less than `buf.len()` only at end of input
+fn read_full<R: Read>(input: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match input.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {},
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+/// Push transfer from stdin, which has no size and no random access: chunks
+/// are read in order and dealt round-robin to the streams, each writing its
+/// chunks at their offsets in the remote file
+pub fn split_and_copy_stdin(
+    remote_path: &str,
+    cfg: &SessionConfig,
+    opts: &TransferOptions,
+) -> io::Result<TransferReport> {
+    let quiet_mode = opts.quiet;
+    let num_streams = opts.num_streams;
+    let mut cfg = cfg.clone();
+    let mut opts = opts.clone();
+    let opts = &mut opts;
+    let destination = format!("{}:{}", cfg.host, remote_path);
+
+    if !quiet_mode {
+        opts.messages.info("Preparing to transfer stdin...");
+    }
+
+    let (remote_file, temp_file, _data_control) = {
+        let sess = connect_and_auth(&cfg)?;
+        note_negotiated_methods(&sess, opts);
+        resolve_helper(&sess, &mut cfg, opts);
+        if opts.mkdir {
+            if let Some(dir) = destination_dir(remote_path) {
+                for created in create_remote_dirs(&sess, dir)? {
+                    if !quiet_mode {
+                        opts.messages.info(&format!("Created remote directory {}", created));
+                    }
+                }
+            }
+        }
+        let existing = remote_file_stat(&sess, &cfg, remote_path).ok();
+        if remote_path.ends_with('/') || existing.as_ref().is_some_and(|st| st.is_dir) {
+            return Err(ZapError::Usage("Reading stdin needs a destination file name, not a directory".to_string()).into());
+        }
+        if existing.is_some() && opts.existing == ExistingPolicy::NoClobber {
+            if !quiet_mode {
+                opts.messages.info(&format!("Skipping {}: destination exists", remote_path));
+            }
+            return Ok(TransferReport::skipped(opts, "-".to_string(), destination, "destination exists"));
+        }
+        let temp_file = remote_temp_path(remote_path);
+        prepare_remote_file(&sess, &cfg, &temp_file, 0, false, false)?;
+        (remote_path.to_string(), temp_file, setup_data_channel(&sess, &mut cfg, opts)?)
+    };
+
+    if !quiet_mode {
+        opts.messages.info(&format!("Using {} streams of {} chunks", num_streams, format_size(STREAM_CHUNK_SIZE)));
+    }
+    let mut stats = TransferStats::new(0, num_streams);
+
+    opts.progress.started(num_streams, None);
+
+    // One slot per stream bounds what is held in memory
+    let mut senders = Vec::with_capacity(num_streams);
+    let mut handles = Vec::with_capacity(num_streams);
+    for stream_num in 0..num_streams {
+        let (tx, rx) = mpsc::sync_channel(1);
+        let cfg_clone = cfg.clone();
+        let temp_file = temp_file.clone();
+        let fsync = opts.fsync;
+        let cancel = opts.cancel.clone();
+        let progress = opts.progress.stream(stream_num, None);
+        senders.push(tx);
+        handles.push(thread::spawn(move || {
+            push_chunks_worker(stream_num, num_streams, &temp_file, &cfg_clone, rx, fsync, &cancel, progress)
+        }));
+    }
+
+    let mut input = io::stdin().lock();
+    let mut total_bytes = 0;
+    let mut read_result = Ok(());
+    for k in 0.. {
+        if let Err(e) = opts.cancel.check() {
+            read_result = Err(e);
+            break;
+        }
+        let mut chunk = vec![0u8; STREAM_CHUNK_SIZE];
+        let n = match read_full(&mut input, &mut chunk) {
+            Ok(n) => n,
+            Err(e) => {
+                read_result = Err(e);
+                break;
+            },
+        };
+        if n == 0 {
+            break;
+        }
+        chunk.truncate(n);
+        // A closed channel means that stream failed; its error is reported below
+        if senders[k % num_streams].send(chunk).is_err() {
+            break;
+        }
+        total_bytes += n;
+        if n < STREAM_CHUNK_SIZE {
+            break;
+        }
+    }
+    drop(senders);
+
+    let mut failures = no_failures(num_streams);
+    for (stream_num, handle) in handles.into_iter().enumerate() {
+        match joined(handle.join()) {
+            Ok(stream) => stats.add(stream_num, &stream),
+            Err(e) => stream_failed(opts, &mut failures, stream_num, e),
+        }
+    }
+    read_result = read_result.and(streams_failed(failures));
+    if let Err(e) = read_result {
+        if let Ok(sess) = connect_and_auth(&cfg) {
+            remove_remote_file(&sess, &temp_file);
+        }
+        return Err(if opts.cancel.is_cancelled() { cancelled(opts, None) } else { e });
+    }
+
+    {
+        let sess = connect_and_auth(&cfg)?;
+        rename_remote_file(&sess, &cfg, &temp_file, &remote_file)?;
+    }
+
+    stats.total_bytes = total_bytes;
+    Ok(stats.report(opts, "-".to_string(), destination))
+}
+
+/// Pull transfer to stdout: streams fetch chunks round-robin and the chunks
+/// are written out in order. Stdout carries only the data, so messages
+/// must not be printed there.
+pub fn split_and_copy_to_stdout(
+    remote_file: &str,
+    cfg: &SessionConfig,
+    opts: &TransferOptions,
+) -> io::Result<TransferReport> {
+    let quiet_mode = opts.quiet;
+    let num_streams = opts.num_streams;
+    let mut cfg = cfg.clone();
+    let mut opts = opts.clone();
+    let opts = &mut opts;
+    let source = format!("{}:{}", cfg.host, remote_file);
+
+    if !quiet_mode {
+        opts.messages.info(&format!("Preparing to transfer {}...", remote_file));
+    }
+
+    let (remote_stat, _data_control) = {
+        let sess = connect_and_auth(&cfg)?;
+        note_negotiated_methods(&sess, opts);
+        resolve_helper(&sess, &mut cfg, opts);
+        let data_control = setup_data_channel(&sess, &mut cfg, opts)?;
+        (remote_file_stat(&sess, &cfg, remote_file)?, data_control)
+    };
+    if remote_stat.is_dir {
+        return Err(ZapError::Usage(format!("{} is a directory", remote_file)).into());
+    }
+    let file_size = remote_stat.size;
+    if !quiet_mode {
+        opts.messages.info(&format!("Remote file size: {} ({})", format_size(file_size as usize), file_size));
+    }
+    let mut stats = TransferStats::new(file_size, num_streams);
+
+    opts.progress.started(num_streams, Some(file_size));
+
+    // Each stream's chunks arrive in order on its own channel, so taking
+    // them round-robin restores the file order; one slot per stream bounds
+    // what is held in memory
+    let mut receivers = Vec::with_capacity(num_streams);
+    let mut handles = Vec::with_capacity(num_streams);
+    for stream_num in 0..num_streams {
+        let (tx, rx) = mpsc::sync_channel(1);
+        let cfg_clone = cfg.clone();
+        let remote_file = remote_file.to_string();
+        let cancel = opts.cancel.clone();
+        let progress = opts.progress.stream(stream_num, Some(chunked_stream_len(stream_num, num_streams, file_size)));
+        receivers.push(rx);
+        handles.push(thread::spawn(move || {
+            pull_chunks_worker(stream_num, num_streams, file_size, &remote_file, &cfg_clone, tx, &cancel, progress)
+        }));
+    }
+
+    let mut output = io::stdout().lock();
+    let mut total_bytes = 0;
+    let mut write_result = Ok(());
+    let num_chunks = file_size.div_ceil(STREAM_CHUNK_SIZE as u64) as usize;
+    for k in 0..num_chunks {
+        // A closed channel means that stream failed; its error is reported below
+        let Ok(chunk) = receivers[k % num_streams].recv() else {
+            break;
+        };
+        if let Err(e) = output.write_all(&chunk) {
+            write_result = Err(e);
+            break;
+        }
+        total_bytes += chunk.len();
+    }
+    write_result = write_result.and_then(|_| output.flush());
+    drop(receivers);
+
+    let mut failures = no_failures(num_streams);
+    for (stream_num, handle) in handles.into_iter().enumerate() {
+        match joined(handle.join()) {
+            Ok(stream) => stats.add(stream_num, &stream),
+            // Streams cut off by a failed write only report the write error
+            Err(e) if write_result.is_err() && e.kind() == io::ErrorKind::BrokenPipe => {},
+            Err(e) => stream_failed(opts, &mut failures, stream_num, e),
+        }
+    }
+    let result = write_result.and(streams_failed(failures));
+    if result.is_err() && opts.cancel.is_cancelled() {
+        return Err(cancelled(opts, Some(file_size)));
+    }
+    result?;
+
+    stats.total_bytes = total_bytes;
+    Ok(stats.report(opts, source, "-".to_string()))
+}
+
+/// Remote-to-remote transfer relayed through this machine: each pull
+/// stream feeds its chunks straight into a push stream through a bounded
+/// channel, so nothing touches local disk
+pub fn split_and_copy_between_remotes(
+    remote_file: &str,
+    src_cfg: &SessionConfig,
+    remote_path: &str,
+    dest_cfg: &SessionConfig,
+    opts: &TransferOptions,
+) -> io::Result<TransferReport> {
+    let quiet_mode = opts.quiet;
+    let num_streams = opts.num_streams;
+    let mut src_cfg = src_cfg.clone();
+    let mut dest_cfg = dest_cfg.clone();
+    let mut opts = opts.clone();
+    let opts = &mut opts;
+
+    if !quiet_mode {
+        opts.messages.info(&format!("Preparing to relay {} from {} to {}...", remote_file, src_cfg.host, dest_cfg.host));
+    }
+
+    let file_name = Path::new(remote_file)
+        .file_name()
+        .ok_or_else(|| ZapError::Usage("Invalid remote file path".to_string()))?
+        .to_str()
+        .ok_or_else(|| ZapError::Usage("Invalid file name".to_string()))?;
+
+    // Both sessions stay up for the whole transfer, for the data channels,
+    // for hashing both ends and for the final rename
+    let src_sess = connect_and_auth(&src_cfg)?;
+    note_negotiated_methods(&src_sess, opts);
+    resolve_helper(&src_sess, &mut src_cfg, opts);
+    let src_stat = remote_file_stat(&src_sess, &src_cfg, remote_file)?;
+    if src_stat.is_dir {
+        return Err(ZapError::Usage(format!("{} is a directory", remote_file)).into());
+    }
+    let file_size = src_stat.size;
+
+    let dest_sess = connect_and_auth(&dest_cfg)?;
+    note_negotiated_methods(&dest_sess, opts);
+    resolve_helper(&dest_sess, &mut dest_cfg, opts);
+    if opts.mkdir {
+        if let Some(dir) = destination_dir(remote_path) {
+            for created in create_remote_dirs(&dest_sess, dir)? {
+                if !quiet_mode {
+                    opts.messages.info(&format!("Created remote directory {}", created));
+                }
+            }
+        }
+    }
+    let dest_file = resolve_remote_destination(&dest_sess, &dest_cfg, remote_path, file_name)?;
+    let skip = skip_reason(opts.existing, &src_stat, remote_file_stat(&dest_sess, &dest_cfg, &dest_file).ok(), || {
+        let src_hash = remote_file_hash(&src_sess, &src_cfg, remote_file, file_size)?;
+        let dest_hash = remote_file_hash(&dest_sess, &dest_cfg, &dest_file, file_size)?;
+        Ok(src_hash.zip(dest_hash).map(|(a, b)| a == b))
+    })?;
+    if let Some(reason) = skip {
+        if !quiet_mode {
+            opts.messages.info(&format!("Skipping {}:{}: {}", dest_cfg.host, dest_file, reason));
+        }
+        let source = format!("{}:{}", src_cfg.host, remote_file);
+        return Ok(TransferReport::skipped(opts, source, format!("{}:{}", dest_cfg.host, dest_file), reason));
+    }
+    let destination = format!("{}:{}", dest_cfg.host, dest_file);
+    let temp_file = remote_temp_path(&dest_file);
+    check_free_space(remote_free_space(&dest_sess, &dest_cfg, &temp_file, &dest_file)?, file_size, &destination, opts)?;
+    warn_sftp_preallocate(&dest_cfg, &temp_file, opts);
+    prepare_remote_file(&dest_sess, &dest_cfg, &temp_file, file_size, false, opts.preallocate)?;
+    let _src_data = setup_data_channel(&src_sess, &mut src_cfg, opts)?;
+    let _dest_data = setup_data_channel(&dest_sess, &mut dest_cfg, opts)?;
+    // Nothing else goes over either session until the streams are done
+    let _keepalive = KeepAlive::start(&[&src_sess, &dest_sess]);
+
+    if !quiet_mode {
+        opts.messages.info(&format!("Remote file size: {} ({})", format_size(file_size as usize), file_size));
+        opts.messages.info(&format!("Using {} streams of {} chunks", num_streams, format_size(STREAM_CHUNK_SIZE)));
+        opts.messages.info("Initializing transfer...");
+    }
+    let mut stats = TransferStats::new(file_size, num_streams);
+
+    opts.progress.started(num_streams, Some(file_size));
+
+    let mut handles = Vec::with_capacity(num_streams);
+    for stream_num in 0..num_streams {
+        // One slot per stream bounds what is held in memory
+        let (tx, rx) = mpsc::sync_channel(1);
+        let progress = opts.progress.stream(stream_num, Some(chunked_stream_len(stream_num, num_streams, file_size)));
+
+        let src_cfg = src_cfg.clone();
+        let remote_file = remote_file.to_string();
+        let cancel = opts.cancel.clone();
+        let pull = thread::spawn(move || {
+            pull_chunks_worker(stream_num, num_streams, file_size, &remote_file, &src_cfg, tx, &cancel, Progress::hidden(stream_num))
+        });
+        let dest_cfg = dest_cfg.clone();
+        let temp_file = temp_file.clone();
+        let fsync = opts.fsync;
+        let cancel = opts.cancel.clone();
+        let push = thread::spawn(move || {
+            push_chunks_worker(stream_num, num_streams, &temp_file, &dest_cfg, rx, fsync, &cancel, progress)
+        });
+        handles.push((pull, push));
+    }
+
+    let mut failures = no_failures(num_streams);
+    for (stream_num, (pull, push)) in handles.into_iter().enumerate() {
+        match (joined(pull.join()), joined(push.join())) {
+            (Ok(_), Ok(stream)) => stats.add(stream_num, &stream),
+            // A failed push cuts its pull stream off; only report the cause
+            (_, Err(e)) => stream_failed(opts, &mut failures, stream_num, e),
+            // The push side saw its input end early and reported done
+            (Err(e), Ok(_)) => {
+                if !opts.cancel.is_cancelled() {
+                    opts.progress.send(TransferEvent::StreamFailed { stream: stream_num, error: e.to_string() });
+                }
+                stream_failed(opts, &mut failures, stream_num, e);
+            },
+        }
+    }
+
+    let result = if let Err(e) = streams_failed(failures) {
+        Err(if opts.cancel.is_cancelled() { cancelled(opts, Some(file_size)) } else { e })
+    } else {
+        Ok(())
+    };
+    if let Err(e) = result {
+        remove_remote_file(&dest_sess, &temp_file);
+        return Err(e);
+    }
+
+    if opts.preserve {
+        set_remote_stat(&dest_sess, &dest_cfg, &temp_file, &src_stat, dest_cfg.user == "root")?;
+    }
+    rename_remote_file(&dest_sess, &dest_cfg, &temp_file, &dest_file)?;
+
+    Ok(stats.report(opts, format!("{}:{}", src_cfg.host, remote_file), destination))
+}
+
+/// Sorts the output of a zap run with --json or --json-progress, which has
+/// its stderr merged in: progress lines go on to `out`, the final report
+/// (or error) is kept back, and text such as warnings goes to `messages`
+struct RemoteJson<'a, W: Write> {
+    out: W,
+    messages: &'a Messages,
+    line: Vec<u8>,
+    report: Option<String>,
+}
+
+impl<'a, W: Write> RemoteJson<'a, W> {
+    fn new(out: W, messages: &'a Messages) -> Self {
+        RemoteJson { out, messages, line: Vec::new(), report: None }
+    }
+
+    fn sort_line(&mut self) -> io::Result<()> {
+        let line = String::from_utf8_lossy(&self.line).into_owned();
+        self.line.clear();
+        let line = line.trim_end_matches('\r');
+        if line.starts_with("{\"event\":") {
+            writeln!(self.out, "{}", line)?;
+            self.out.flush()?;
+        } else if line.starts_with('{') && line.ends_with('}') {
+            self.report = Some(line.to_string());
+        } else if !line.trim().is_empty() {
+            self.messages.warn(line.strip_prefix("Warning: ").unwrap_or(line));
+        }
+        Ok(())
+    }
+
+    /// The report, once the remote zap is done
+    fn finish(mut self) -> io::Result<Option<String>> {
+        self.sort_line()?;
+        Ok(self.report)
+    }
+}
+
+impl<W: Write> Write for RemoteJson<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for &byte in buf {
+            if byte == b'\n' {
+                self.sort_line()?;
+            } else {
+                self.line.push(byte);
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Remote-to-remote transfer run by zap on the source host, pushing
+/// straight to the destination. Its output is passed on to `output`;
+/// a failure there comes back as `ZapError::RemoteExit`. Cancelling
+/// interrupts the remote zap, which cleans up as it would for Ctrl-C.
+/// The report only has the endpoints and the time taken: the figures are
+/// in the remote zap's own output, which isn't parsed, or with `json` in
+/// its JSON report, which is kept as `remote`. `json` means `args` ask
+/// for --json or --json-progress.
+#[allow(clippy::too_many_arguments)]
+pub fn copy_direct(
+    command: &str,
+    args: &[String],
+    json: bool,
+    cfg: &SessionConfig,
+    opts: &TransferOptions,
+    output: &Output,
+    source: String,
+    destination: String,
+) -> io::Result<TransferReport> {
+    let mut opts = opts.clone();
+    let opts = &mut opts;
+    let start_time = Instant::now();
+    let sess = connect_and_auth(cfg)?;
+    note_negotiated_methods(&sess, opts);
+    if !HelperClient::probe(&sess, command) {
+        return Err(io::Error::new(io::ErrorKind::NotFound, format!("--direct needs zap on {} ({} not found)", cfg.host, command)));
+    }
+    if !opts.quiet {
+        opts.messages.info(&format!("Running {} on {}", command, cfg.host));
+    }
+    let (status, report) = if json {
+        let mut sorted = RemoteJson::new(output.clone(), &opts.messages);
+        let status = run_remote_command(&sess, command, args, &opts.cancel, &mut sorted)?;
+        (status, sorted.finish()?)
+    } else {
+        (run_remote_command(&sess, command, args, &opts.cancel, &mut output.clone())?, None)
+    };
+    match status {
+        0 => Ok(TransferReport::delegated(opts, source, destination, start_time.elapsed(), report)),
+        status => Err(ZapError::RemoteExit { host: cfg.host.clone(), status, report }.into()),
+    }
+}
+
+/// Local copy: the same segment layout as a network transfer, with each
+/// stream reading and writing its segment of two local files. Worthwhile
+/// between network filesystem mounts, where a single-threaded copy is
+/// bound by latency.
+pub fn split_and_copy_local(
+    input_file: &str,
+    local_path: &str,
+    opts: &TransferOptions,
+) -> io::Result<TransferReport> {
+    let quiet_mode = opts.quiet;
+
+    if !quiet_mode {
+        opts.messages.info(&format!("Preparing to copy {}...", input_file));
+    }
+
+    let source_stat = local_file_stat(Path::new(input_file))?;
+    let file_size = source_stat.size;
+
+    // Create missing destination directories
+    if opts.mkdir {
+        if let Some(dir) = destination_dir(local_path) {
+            for created in create_local_dirs(Path::new(dir))? {
+                if !quiet_mode {
+                    opts.messages.info(&format!("Created directory {}", created.display()));
+                }
+            }
+        }
+    }
+
+    // Determine output file path
+    let file_name = Path::new(input_file)
+        .file_name()
+        .ok_or_else(|| ZapError::Usage("Invalid input file path".to_string()))?
+        .to_str()
+        .ok_or_else(|| ZapError::Usage("Invalid file name".to_string()))?;
+    let output_path = if Path::new(local_path).is_dir() {
+        Path::new(local_path).join(file_name)
+    } else {
+        Path::new(local_path).to_path_buf()
+    };
+    let output_name = output_path
+        .file_name()
+        .and_then(|n| n.to_str())
+        .ok_or_else(|| ZapError::Usage("Invalid destination file name".to_string()))?;
+    let temp_path = output_path.with_file_name(temp_file_name(output_name));
+    if fs::canonicalize(&output_path).ok() == Some(fs::canonicalize(input_file)?) {
+        return Err(ZapError::Usage(format!("{} and {} are the same file", input_file, output_path.display())).into());
+    }
+
+    // Leave an existing destination alone if the policy says so
+    let skip = skip_reason(opts.existing, &source_stat, local_file_stat(&output_path).ok(), || {
+        let source_hash = hash_local_range(&fs::File::open(input_file)?, 0, file_size)?;
+        Ok(Some(source_hash == hash_local_range(&fs::File::open(&output_path)?, 0, file_size)?))
+    })?;
+    if let Some(reason) = skip {
+        if !quiet_mode {
+            opts.messages.info(&format!("Skipping {}: {}", output_path.display(), reason));
+        }
+        return Ok(TransferReport::skipped(opts, input_file.to_string(), output_path.display().to_string(), reason));
+    }
+
+    let dest_dir = match output_path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    check_free_space(local_free_space(dest_dir).ok(), file_size, &output_path.display().to_string(), opts)?;
+
+    if !quiet_mode {
+        opts.messages.info(&format!("Local file size: {} ({})", format_size(file_size as usize), file_size));
+        describe_segments(file_size, opts);
+        opts.messages.info("Initializing transfer...");
+    }
+
+    // Create local temp file and extend to full size (sparse)
+    let source = fs::File::open(input_file).map_err(ZapError::local(Path::new(input_file)))?;
+    let dest = fs::OpenOptions::new()
+        .create(true)
+        .truncate(true)
+        .write(true)
+        .read(true)
+        .open(&temp_path)
+        .map_err(ZapError::local(&temp_path))?;
+    dest.set_len(file_size)?;
+    if opts.preallocate {
+        preallocate_local(&dest, file_size).map_err(|e| {
+            let _ = fs::remove_file(&temp_path);
+            io::Error::new(e.kind(), format!("Failed to preallocate {} for {}: {}", format_size(file_size as usize), output_path.display(), e))
+        })?;
+    }
+
+    let result = run_segments(file_size, opts, |_, start, end, progress| {
+        copy_worker(start, end, &source, &dest, opts.sparse, &opts.cancel, progress)
+    });
+    let stats = match result {
+        Ok(stats) => stats,
+        Err(e) => {
+            let _ = fs::remove_file(&temp_path);
+            if opts.cancel.is_cancelled() {
+                return Err(cancelled(opts, Some(file_size)));
+            }
+            return Err(e);
+        },
+    };
+
+    // Sync file to disk, then move it into place
+    if opts.fsync {
+        dest.sync_all()?;
+    }
+    if opts.preserve {
+        apply_local_stat(&temp_path, &source_stat, running_as_root())?;
+    }
+    fs::rename(&temp_path, &output_path).map_err(ZapError::local(&output_path))?;
+
+    Ok(stats.report(opts, input_file.to_string(), output_path.display().to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn chunked_streams_add_up_to_the_size() {
+        let chunk = STREAM_CHUNK_SIZE as u64;
+        for size in [0, 1, chunk - 1, chunk, chunk + 1, 7 * chunk + 12345] {
+            for streams in [1, 2, 3, 8, 20] {
+                let total: u64 = (0..streams).map(|s| chunked_stream_len(s, streams, size)).sum();
+                assert_eq!(total, size, "size {} over {} streams", size, streams);
+            }
+        }
+    }
+
+    #[test]
+    fn chunks_are_dealt_round_robin() {
+        let chunk = STREAM_CHUNK_SIZE as u64;
+        // Chunks 0..=4, the last one short: stream 0 gets 0, 2 and the tail
+        let size = 4 * chunk + 10;
+        assert_eq!(chunked_stream_len(0, 2, size), 2 * chunk + 10);
+        assert_eq!(chunked_stream_len(1, 2, size), 2 * chunk);
+        assert_eq!(chunked_stream_len(5, 8, size), 0);
+    }
+
+    #[test]
+    fn remote_json_keeps_the_report_back() {
+        let warnings = Arc::new(Mutex::new(Vec::new()));
+        let seen = warnings.clone();
+        let messages = Messages::new(move |_, msg| seen.lock().unwrap().push(msg.to_string()));
+        let mut out = Vec::new();
+        let mut sorted = RemoteJson::new(&mut out, &messages);
+        sorted.write_all(b"Warning: no helper\r\n{\"event\":\"started\"}\n{\"event\":").unwrap();
+        sorted.write_all(b"\"done\"}\n\n{\"outcome\":\"failed\",\"exit_code\":7}").unwrap();
+        let report = sorted.finish().unwrap();
+
+        assert_eq!(report.as_deref(), Some(r#"{"outcome":"failed","exit_code":7}"#));
+        assert_eq!(out, b"{\"event\":\"started\"}\n{\"event\":\"done\"}\n");
+        assert_eq!(*warnings.lock().unwrap(), ["no helper"]);
+    }
+
+    #[test]
+    fn a_failed_direct_run_is_one_json_object() {
+        let messages = Messages::default();
+        let mut sorted = RemoteJson::new(io::sink(), &messages);
+        sorted.write_all(b"Warning: no helper\n{\"outcome\":\"failed\",\"error\":\"gone\",\"exit_code\":7}\n").unwrap();
+        let e = ZapError::RemoteExit { host: "src".to_string(), status: 7, report: sorted.finish().unwrap() };
+        assert_eq!(
+            e.to_json(),
+            r#"{"outcome":"failed","error":"zap on src exited with status 7","exit_code":7,"remote":{"outcome":"failed","error":"gone","exit_code":7}}"#,
+        );
+    }
+
+    fn exit_code(failures: Vec<Option<ZapError>>) -> i32 {
+        let failures = failures.into_iter().map(|e| e.map(io::Error::from)).collect();
+        ZapError::from(streams_failed(failures).unwrap_err()).exit_code()
+    }
+
+    fn auth() -> Option<ZapError> {
+        Some(ZapError::Auth { host: "host".to_string(), user: "user".to_string() })
+    }
+
+    fn no_space() -> Option<ZapError> {
+        Some(ZapError::NoSpace { destination: "dest".to_string(), needed: 10, available: 0 })
+    }
+
+    #[test]
+    fn no_failures_is_success() {
+        assert!(streams_failed(no_failures(4)).is_ok());
+    }
+
+    #[test]
+    fn streams_that_all_fail_exit_as_their_cause() {
+        assert_eq!(exit_code(vec![no_space()]), crate::exit::NO_SPACE);
+        assert_eq!(exit_code(vec![auth(), auth(), auth()]), crate::exit::AUTH);
+        let stream = ZapError::Stream { stream: 2, retries: 3, source: Box::new(no_space().unwrap()) };
+        assert_eq!(exit_code(vec![no_space(), no_space(), Some(stream)]), crate::exit::NO_SPACE);
+    }
+
+    #[test]
+    fn some_streams_failing_exit_partial() {
+        assert_eq!(exit_code(vec![None, no_space(), None]), crate::exit::PARTIAL);
+        assert_eq!(exit_code(vec![auth(), auth(), None]), crate::exit::PARTIAL);
+    }
+
+    fn options(num_streams: usize) -> TransferOptions {
+        TransferOptions {
+            quiet: true,
+            num_streams,
+            transport: Transport::Local,
+            verbose: false,
+            preserve: false,
+            mkdir: false,
+            existing: ExistingPolicy::Overwrite,
+            delta: false,
+            sparse: false,
+            preallocate: false,
+            force: false,
+            fsync: false,
+            messages: Default::default(),
+            progress: Progress::default(),
+            cancel: Default::default(),
+            cipher: None,
+        }
+    }
+
+    fn moved(start: u64, end: u64) -> io::Result<StreamResult> {
+        Ok(StreamResult { bytes_sent: end - start, wire_bytes: None, holes_skipped: 0, zeros_skipped: 0 })
+    }
+
+    #[test]
+    fn segments_cover_the_file_once() {
+        let covered = std::sync::Mutex::new(Vec::new());
+        let stats = run_segments(1003, &options(4), |stream_num, start, end, _| {
+            covered.lock().unwrap().push((stream_num, start, end));
+            moved(start, end)
+        }).unwrap();
+        let mut covered = covered.into_inner().unwrap();
+        covered.sort();
+        assert_eq!(covered, vec![(0, 0, 250), (1, 250, 500), (2, 500, 750), (3, 750, 1003)]);
+        assert_eq!((stats.streams_completed, stats.bytes_sent), (4, 1003));
+    }
+
+    #[test]
+    fn every_segment_out_of_space_exits_no_space() {
+        let result = run_segments(1000, &options(4), |_, _, _, _| Err(no_space().unwrap().into()));
+        assert_eq!(ZapError::from(result.err().unwrap()).exit_code(), crate::exit::NO_SPACE);
+    }
+
+    #[test]
+    fn one_failed_segment_exits_partial() {
+        let result = run_segments(1000, &options(4), |stream_num, start, end, _| match stream_num {
+            2 => Err(no_space().unwrap().into()),
+            _ => moved(start, end),
+        });
+        assert_eq!(ZapError::from(result.err().unwrap()).exit_code(), crate::exit::PARTIAL);
+    }
+
+    #[test]
+    fn segments_failing_differently_exit_partial() {
+        let result = run_segments(1000, &options(4), |stream_num, start, end, _| match stream_num {
+            1 => Err(no_space().unwrap().into()),
+            3 => Err(ZapError::Local { path: PathBuf::from("dest"), source: io::Error::other("disk on fire") }.into()),
+            _ => moved(start, end),
+        });
+        assert_eq!(ZapError::from(result.err().unwrap()).exit_code(), crate::exit::PARTIAL);
+    }
+
+    #[test]
+    fn streams_failing_differently_exit_partial() {
+        assert_eq!(exit_code(vec![no_space(), auth(), None]), crate::exit::PARTIAL);
+        // Unless none got through
+        assert_eq!(exit_code(vec![auth(), no_space()]), crate::exit::AUTH);
+    }
+}
@@ -0,0 +1,281 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use ssh2::ErrorCode;
+use crate::json;
+use crate::utils::format_size;
+
+/// libssh2 session errors from the key exchange and host key checks
+const LIBSSH2_ERROR_KEX_FAILURE: i32 = -5;
+const LIBSSH2_ERROR_HOSTKEY_INIT: i32 = -10;
+const LIBSSH2_ERROR_HOSTKEY_SIGN: i32 = -11;
+
+/// SFTP status codes (SSH_FX_*) worth telling apart
+const SSH_FX_NO_SUCH_FILE: i32 = 2;
+const SSH_FX_PERMISSION_DENIED: i32 = 3;
+const SSH_FX_NO_SUCH_PATH: i32 = 10;
+const SSH_FX_NO_SPACE_ON_FILESYSTEM: i32 = 14;
+const SSH_FX_QUOTA_EXCEEDED: i32 = 15;
+
+/// Process exit statuses of the zap command, one per kind of failure. These
+/// are stable; scripts can rely on them.
+pub mod exit {
+    /// The transfer completed, or was skipped by --no-clobber, --update or
+    /// --skip-identical
+    pub const OK: i32 = 0;
+    /// Anything not covered below
+    pub const FAILURE: i32 = 1;
+    /// Bad arguments, locations or option combinations
+    pub const USAGE: i32 = 2;
+    /// The host name did not resolve
+    pub const RESOLVE: i32 = 3;
+    /// Couldn't connect, or the connection dropped; worth retrying later
+    pub const CONNECT: i32 = 4;
+    /// Key exchange or the host key check failed
+    pub const HOST_KEY: i32 = 5;
+    /// No key or agent identity was accepted
+    pub const AUTH: i32 = 6;
+    /// The source, or the destination directory, does not exist, locally or
+    /// on the remote end
+    pub const NOT_FOUND: i32 = 7;
+    /// A local file operation failed
+    pub const LOCAL_IO: i32 = 8;
+    /// An SFTP request or the remote helper failed
+    pub const REMOTE_IO: i32 = 9;
+    /// The destination is out of space or over quota
+    pub const NO_SPACE: i32 = 10;
+    /// Some streams failed while others got through; a partial file may
+    /// be left for --delta. When every stream fails, the first failure's
+    /// code is used instead.
+    pub const PARTIAL: i32 = 11;
+    /// Stopped by SIGINT or SIGTERM, or a cancelled `CancelToken`
+    pub const CANCELLED: i32 = 130;
+}
+
+/// Why a transfer failed
+#[derive(Debug)]
+pub enum ZapError {
+    /// Bad locations or options
+    Usage(String),
+    /// The host name did not resolve
+    Resolve { host: String, source: io::Error },
+    /// No TCP connection to the SSH port, or the SSH handshake failed
+    Connect { host: String, source: io::Error },
+    /// Key exchange failed: no host key algorithm in common, or the host
+    /// key's signature didn't check out
+    HostKey { host: String, source: ssh2::Error },
+    /// Neither the key file, the default keys nor the agent were accepted
+    Auth { host: String, user: String },
+    /// An SFTP request failed; `sftp_status` has its SSH_FX code
+    Sftp { op: &'static str, path: String, source: ssh2::Error },
+    /// The remote zap helper reported an error, with its errno when it was
+    /// an OS error
+    Remote { message: String, errno: Option<i32> },
+    /// A local path does not exist
+    NotFound { path: PathBuf },
+    /// A local file operation failed
+    Local { path: PathBuf, source: io::Error },
+    /// The destination doesn't have room for the file
+    NoSpace { destination: String, needed: u64, available: u64 },
+    /// A stream gave up after its retries
+    Stream { stream: usize, retries: u32, source: Box<ZapError> },
+    /// Some streams failed; the first failure is kept
+    Partial { failed: usize, streams: usize, first: Box<ZapError> },
+    /// The transfer's `CancelToken` was cancelled after `done` of `total`
+    /// bytes
+    Cancelled { done: u64, total: Option<u64> },
+    /// zap on the source host of a `--direct` transfer failed with this
+    /// status; it has already said why on its own output, or in `report`
+    /// when it was asked for JSON
+    RemoteExit { host: String, status: i32, report: Option<String> },
+    /// Anything else, e.g. the network dropping in the middle of a stream
+    Io(io::Error),
+}
+
+impl ZapError {
+    /// The SSH_FX status of a failed SFTP request
+    pub fn sftp_status(&self) -> Option<i32> {
+        match self {
+            ZapError::Sftp { source, .. } => match source.code() {
+                ErrorCode::SFTP(status) => Some(status),
+                ErrorCode::Session(_) => None,
+            },
+            _ => None,
+        }
+    }
+
+    /// The ZapError an io::Error from the transfer code carries, if any
+    pub(crate) fn within(e: &io::Error) -> Option<&ZapError> {
+        e.get_ref().and_then(|inner| inner.downcast_ref())
+    }
+
+    /// Closest io::ErrorKind, for callers that only look at that
+    pub fn kind(&self) -> io::ErrorKind {
+        match self {
+            ZapError::Usage(_) => io::ErrorKind::InvalidInput,
+            ZapError::Auth { .. } => io::ErrorKind::PermissionDenied,
+            ZapError::NotFound { .. } => io::ErrorKind::NotFound,
+            ZapError::NoSpace { .. } => io::ErrorKind::StorageFull,
+            ZapError::Cancelled { .. } => io::ErrorKind::Interrupted,
+            ZapError::Sftp { .. } => match self.sftp_status() {
+                Some(SSH_FX_NO_SUCH_FILE | SSH_FX_NO_SUCH_PATH) => io::ErrorKind::NotFound,
+                Some(SSH_FX_PERMISSION_DENIED) => io::ErrorKind::PermissionDenied,
+                Some(SSH_FX_NO_SPACE_ON_FILESYSTEM | SSH_FX_QUOTA_EXCEEDED) => io::ErrorKind::StorageFull,
+                _ => io::ErrorKind::Other,
+            },
+            ZapError::Remote { errno: Some(errno), .. } => io::Error::from_raw_os_error(*errno).kind(),
+            ZapError::Resolve { source, .. } | ZapError::Connect { source, .. } | ZapError::Local { source, .. } | ZapError::Io(source) => {
+                source.kind()
+            },
+            ZapError::Stream { source, .. } => source.kind(),
+            ZapError::Partial { first, .. } => first.kind(),
+            ZapError::HostKey { .. } | ZapError::Remote { .. } | ZapError::RemoteExit { .. } => io::ErrorKind::Other,
+        }
+    }
+
+    /// Exit status for the zap command, from the table in [`exit`]. A
+    /// stream's failure exits like its cause, and so do failed streams that
+    /// all share one; a `--direct` run passes the remote zap's status
+    /// through.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            ZapError::Usage(_) => exit::USAGE,
+            ZapError::Resolve { .. } => exit::RESOLVE,
+            ZapError::Connect { .. } => exit::CONNECT,
+            ZapError::HostKey { .. } => exit::HOST_KEY,
+            ZapError::Auth { .. } => exit::AUTH,
+            ZapError::NotFound { .. } => exit::NOT_FOUND,
+            // Nothing got through, so the streams most likely share one
+            // cause, say auth
+            ZapError::Partial { failed, streams, first } if failed == streams => first.exit_code(),
+            ZapError::Partial { .. } => exit::PARTIAL,
+            ZapError::Cancelled { .. } => exit::CANCELLED,
+            ZapError::Stream { source, .. } => source.exit_code(),
+            ZapError::RemoteExit { status, .. } => *status,
+            ZapError::NoSpace { .. } => exit::NO_SPACE,
+            ZapError::Local { .. } | ZapError::Sftp { .. } | ZapError::Remote { .. } | ZapError::Io(_) => {
+                match (self.kind(), self) {
+                    (io::ErrorKind::NotFound, _) => exit::NOT_FOUND,
+                    (io::ErrorKind::StorageFull | io::ErrorKind::QuotaExceeded, _) => exit::NO_SPACE,
+                    (_, ZapError::Local { .. }) => exit::LOCAL_IO,
+                    (_, ZapError::Sftp { .. } | ZapError::Remote { .. }) => exit::REMOTE_IO,
+                    (kind, _) if is_network(kind) => exit::CONNECT,
+                    _ => exit::FAILURE,
+                }
+            },
+        }
+    }
+
+    /// The error as one line of JSON, as `--json` prints it in place of a
+    /// report
+    pub fn to_json(&self) -> String {
+        let line = json::Object::new()
+            .field("outcome", "failed")
+            .field("error", &self.to_string())
+            .field("exit_code", &self.exit_code());
+        match self {
+            ZapError::RemoteExit { report: Some(report), .. } => line.field("remote", &json::Raw(report)).finish(),
+            _ => line.finish(),
+        }
+    }
+
+    /// Error message from a remote helper. Its OS errors end in
+    /// "(os error N)", which is all the errno the protocol carries.
+    pub(crate) fn remote(message: &str) -> Self {
+        let errno = message.rsplit_once("(os error ")
+            .and_then(|(_, rest)| rest.strip_suffix(')'))
+            .and_then(|n| n.parse().ok());
+        ZapError::Remote { message: message.to_string(), errno }
+    }
+
+    /// For map_err on a local file operation
+    pub(crate) fn local(path: &Path) -> impl FnOnce(io::Error) -> Self + '_ {
+        move |source| ZapError::Local { path: path.to_path_buf(), source }
+    }
+
+    /// A failed SSH handshake, which is about the host key if key exchange
+    /// is where it went wrong
+    pub(crate) fn handshake(host: &str, e: ssh2::Error) -> Self {
+        match e.code() {
+            ErrorCode::Session(LIBSSH2_ERROR_KEX_FAILURE | LIBSSH2_ERROR_HOSTKEY_INIT | LIBSSH2_ERROR_HOSTKEY_SIGN) => {
+                ZapError::HostKey { host: host.to_string(), source: e }
+            },
+            _ => ZapError::Connect { host: host.to_string(), source: e.into() },
+        }
+    }
+}
+
+/// Kinds of a connection that dropped mid-transfer
+fn is_network(kind: io::ErrorKind) -> bool {
+    matches!(kind,
+        io::ErrorKind::ConnectionReset | io::ErrorKind::ConnectionAborted | io::ErrorKind::BrokenPipe
+        | io::ErrorKind::TimedOut | io::ErrorKind::UnexpectedEof | io::ErrorKind::NotConnected)
+}
+
+impl fmt::Display for ZapError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ZapError::Usage(msg) => f.write_str(msg),
+            ZapError::Resolve { host, source } => write!(f, "Failed to resolve host {}: {}", host, source),
+            ZapError::Connect { host, source } => write!(f, "Failed to connect to {}: {}", host, source),
+            ZapError::HostKey { host, source } => write!(f, "Host key exchange with {} failed: {}", host, source),
+            ZapError::Auth { host, user } => {
+                write!(f, "Failed to authenticate as {} on {}. Try specifying a key with --ssh-key-path", user, host)
+            },
+            ZapError::Sftp { op, path, source } => write!(f, "Failed to {} {}: {}", op, path, source),
+            ZapError::Remote { message, .. } => write!(f, "Remote helper: {}", message),
+            ZapError::NotFound { path } => write!(f, "'{}' does not exist", path.display()),
+            ZapError::Local { path, source } => write!(f, "{}: {}", path.display(), source),
+            ZapError::NoSpace { destination, needed, available } => {
+                write!(f, "Not enough free space for {}: {} needed, {} available; use --force to try anyway",
+                       destination, format_size(*needed as usize), format_size(*available as usize))
+            },
+            ZapError::Stream { stream, retries, source } => {
+                write!(f, "Stream {} failed after {} retries: {}", stream, retries, source)
+            },
+            ZapError::Partial { failed, streams, first, .. } => {
+                write!(f, "{} of {} streams failed to transfer; the first: {}", failed, streams, first)
+            },
+            ZapError::Cancelled { done, total: Some(total) } => {
+                write!(f, "Cancelled after {} of {}", format_size(*done as usize), format_size(*total as usize))
+            },
+            ZapError::Cancelled { done, total: None } => write!(f, "Cancelled after {}", format_size(*done as usize)),
+            ZapError::RemoteExit { host, status, .. } => write!(f, "zap on {} exited with status {}", host, status),
+            ZapError::Io(e) => e.fmt(f),
+        }
+    }
+}
+
+impl Error for ZapError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ZapError::Resolve { source, .. } | ZapError::Connect { source, .. } | ZapError::Local { source, .. } => Some(source),
+            ZapError::HostKey { source, .. } | ZapError::Sftp { source, .. } => Some(source),
+            ZapError::Stream { source, .. } => Some(source.as_ref()),
+            ZapError::Partial { first, .. } => Some(first.as_ref()),
+            ZapError::Io(e) => e.source(),
+            _ => None,
+        }
+    }
+}
+
+/// Inside the transfer code errors travel as io::Error, carrying a ZapError
+/// where the cause is known
+impl From<ZapError> for io::Error {
+    fn from(e: ZapError) -> io::Error {
+        match e {
+            ZapError::Io(e) => e,
+            e => io::Error::new(e.kind(), e),
+        }
+    }
+}
+
+impl From<io::Error> for ZapError {
+    fn from(e: io::Error) -> ZapError {
+        if e.get_ref().is_some_and(|inner| inner.is::<ZapError>()) {
+            return *e.into_inner().and_then(|inner| inner.downcast().ok()).expect("checked above");
+        }
+        ZapError::Io(e)
+    }
+}
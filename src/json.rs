@@ -0,0 +1,181 @@
+use std::fmt::Write;
+
+/// A value that can be written as JSON
+pub(crate) trait Json {
+    fn write_json(&self, out: &mut String);
+}
+
+impl Json for str {
+    fn write_json(&self, out: &mut String) {
+        out.push('"');
+        for c in self.chars() {
+            match c {
+                '"' => out.push_str("\\\""),
+                '\\' => out.push_str("\\\\"),
+                '\n' => out.push_str("\\n"),
+                '\r' => out.push_str("\\r"),
+                '\t' => out.push_str("\\t"),
+                c if c < ' ' => { let _ = write!(out, "\\u{:04x}", c as u32); },
+                c => out.push(c),
+            }
+        }
+        out.push('"');
+    }
+}
+
+impl Json for String {
+    fn write_json(&self, out: &mut String) {
+        self.as_str().write_json(out);
+    }
+}
+
+impl Json for bool {
+    fn write_json(&self, out: &mut String) {
+        out.push_str(if *self { "true" } else { "false" });
+    }
+}
+
+macro_rules! json_integer {
+    ($($t:ty),*) => {
+        $(impl Json for $t {
+            fn write_json(&self, out: &mut String) {
+                let _ = write!(out, "{}", self);
+            }
+        })*
+    };
+}
+
+json_integer!(u32, u64, usize, i32);
+
+/// Rounded to the millisecond or byte/s; JSON has no NaN or infinity
+impl Json for f64 {
+    fn write_json(&self, out: &mut String) {
+        if self.is_finite() {
+            let _ = write!(out, "{}", (self * 1000.0).round() / 1000.0);
+        } else {
+            out.push_str("null");
+        }
+    }
+}
+
+impl<T: Json> Json for Option<T> {
+    fn write_json(&self, out: &mut String) {
+        match self {
+            Some(value) => value.write_json(out),
+            None => out.push_str("null"),
+        }
+    }
+}
+
+impl<T: Json> Json for [T] {
+    fn write_json(&self, out: &mut String) {
+        out.push('[');
+        for (i, value) in self.iter().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            value.write_json(out);
+        }
+        out.push(']');
+    }
+}
+
+impl<T: Json> Json for Vec<T> {
+    fn write_json(&self, out: &mut String) {
+        self.as_slice().write_json(out);
+    }
+}
+
+impl<T: Json + ?Sized> Json for &T {
+    fn write_json(&self, out: &mut String) {
+        (**self).write_json(out);
+    }
+}
+
+/// JSON that is already written, such as another zap's report, passed on
+/// as it is
+pub(crate) struct Raw<'a>(pub &'a str);
+
+impl Json for Raw<'_> {
+    fn write_json(&self, out: &mut String) {
+        out.push_str(self.0);
+    }
+}
+
+/// A JSON object written on one line, fields in the order they are added
+pub(crate) struct Object(String);
+
+impl Object {
+    pub fn new() -> Self {
+        Object(String::from("{"))
+    }
+
+    pub fn field<T: Json + ?Sized>(mut self, key: &str, value: &T) -> Self {
+        if self.0.len() > 1 {
+            self.0.push(',');
+        }
+        key.write_json(&mut self.0);
+        self.0.push(':');
+        value.write_json(&mut self.0);
+        self
+    }
+
+    pub fn finish(mut self) -> String {
+        self.0.push('}');
+        self.0
+    }
+}
+
+/// Nested objects are written as they are
+impl Json for Object {
+    fn write_json(&self, out: &mut String) {
+        out.push_str(&self.0);
+        out.push('}');
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn json<T: Json + ?Sized>(value: &T) -> String {
+        let mut out = String::new();
+        value.write_json(&mut out);
+        out
+    }
+
+    #[test]
+    fn strings_are_escaped() {
+        assert_eq!(json("plain"), r#""plain""#);
+        assert_eq!(json(r#"say "hi" \ bye"#), r#""say \"hi\" \\ bye""#);
+        assert_eq!(json("a\nb\tc\rd"), r#""a\nb\tc\rd""#);
+        assert_eq!(json("\u{1}\u{1f}"), r#""\u0001\u001f""#);
+        assert_eq!(json("ünïcødé ✓"), "\"ünïcødé ✓\"");
+    }
+
+    #[test]
+    fn numbers_and_nulls() {
+        assert_eq!(json(&u64::MAX), "18446744073709551615");
+        assert_eq!(json(&-3i32), "-3");
+        assert_eq!(json(&1.23456f64), "1.235");
+        assert_eq!(json(&2.0f64), "2");
+        assert_eq!(json(&f64::NAN), "null");
+        assert_eq!(json(&f64::INFINITY), "null");
+        assert_eq!(json(&None::<u32>), "null");
+        assert_eq!(json(&Some(true)), "true");
+    }
+
+    #[test]
+    fn objects_and_arrays() {
+        let inner = Object::new().field("n", &1u32);
+        let line = Object::new()
+            .field("name", "x")
+            .field("list", &vec![1u32, 2])
+            .field("empty", &Vec::<u32>::new())
+            .field("inner", &vec![inner])
+            .field("raw", &Some(Raw(r#"{"a":[true]}"#)))
+            .finish();
+        assert_eq!(line, r#"{"name":"x","list":[1,2],"empty":[],"inner":[{"n":1}],"raw":{"a":[true]}}"#);
+        assert_eq!(Object::new().finish(), "{}");
+    }
+}
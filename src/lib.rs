@@ -0,0 +1,36 @@
+//! Parallel file transfers over SSH.
+//!
+//! ```no_run
+//! use zap::{MessageKind, TransferBuilder};
+//!
+//! let report = TransferBuilder::new("user@host:/data/big.img", "/tmp/")
+//!     .streams(8)
+//!     .preserve(true)
+//!     .on_message(|kind, msg| if kind != MessageKind::Info { eprintln!("{}", msg) })
+//!     .run()?;
+//! println!("{} bytes in {:?}", report.total_bytes, report.duration);
+//! # Ok::<(), zap::ZapError>(())
+//! ```
+
+mod utils;
+mod drivers;
+mod cancel;
+mod error;
+mod json;
+mod ssh;
+mod progress;
+mod protocol;
+mod server;
+mod tcp;
+mod transfer;
+mod transport;
+#[cfg(feature = "quic")]
+mod quic;
+
+pub use cancel::CancelToken;
+pub use error::{exit, ZapError};
+pub use progress::{IndicatifProgress, JsonProgress, Phase, ProgressSink, StreamReport, TransferEvent};
+pub use server::run_server;
+pub use transfer::TransferBuilder;
+pub use transport::{Congestion, Transport};
+pub use utils::{ExistingPolicy, MessageKind, TransferOutcome, TransferReport};
@@ -1,154 +1,7 @@
-mod utils;
-mod ssh;
-
 use clap::{App, Arg};
-use utils::{split_and_copy_binary_file, split_and_copy_from_remote};
-use std::env;
+use zap::{exit, CancelToken, Congestion, ExistingPolicy, IndicatifProgress, JsonProgress, MessageKind, TransferBuilder, TransferOutcome, Transport, ZapError};
+use std::io::{self, Write};
 use std::process;
-use std::path::Path;
-
-fn parse_location(loc: &str) -> Option<(Option<(String, String)>, String)> {
-    // Check for Windows drive letter (C:\, D:\, etc.) - always local
-    if loc.len() >= 2 {
-        let chars: Vec<char> = loc.chars().collect();
-        if chars.len() >= 2 && chars[0].is_ascii_alphabetic() && chars[1] == ':' {
-            // Could be Windows drive (C:\) or just happen to have : at position 1
-            // Check if followed by \ or / (Windows path separator)
-            if chars.len() >= 3 && (chars[2] == '\\' || chars[2] == '/') {
-                return Some((None, loc.to_string()));
-            }
-        }
-    }
-
-    // Check for UNC path (\\server\share) - always local on Windows
-    if loc.starts_with("\\\\") || loc.starts_with("//") {
-        return Some((None, loc.to_string()));
-    }
-
-    // Check for IPv6 literal: [user@][IPv6]:path
-    if let Some(bracket_start) = loc.find('[') {
-        if let Some(bracket_end) = loc.find(']') {
-            if bracket_end > bracket_start {
-                let before_bracket = &loc[..bracket_start];
-                let ipv6_host = &loc[bracket_start + 1..bracket_end];
-                let after_bracket = &loc[bracket_end + 1..];
-
-                // after_bracket should be ":path" or empty
-                if after_bracket.is_empty() || after_bracket.starts_with(':') {
-                    let path = if after_bracket.is_empty() {
-                        ".".to_string()
-                    } else {
-                        let p = &after_bracket[1..];
-                        if p.is_empty() { "." } else { p }.to_string()
-                    };
-
-                    // Extract user from before_bracket
-                    let user = if before_bracket.is_empty() {
-                        env::var("USER").ok()?
-                    } else if before_bracket.ends_with('@') {
-                        let u = before_bracket.trim_end_matches('@');
-                        if u.is_empty() {
-                            return None; // Empty user
-                        }
-                        u.to_string()
-                    } else {
-                        return None; // Invalid format
-                    };
-
-                    return Some((Some((user, ipv6_host.to_string())), path));
-                }
-            }
-        }
-    }
-
-    // Standard parsing: [user@]host:path
-    if loc.contains(':') {
-        let parts: Vec<&str> = loc.splitn(2, ':').collect();
-        if parts.len() != 2 {
-            return None;
-        }
-
-        let user_host = parts[0];
-        let path = if parts[1].is_empty() {
-            ".".to_string()
-        } else {
-            parts[1].to_string()
-        };
-
-        // Disallow empty user with @ present
-        if user_host.starts_with('@') {
-            return None;
-        }
-
-        // Disallow empty host with @ present
-        if user_host.ends_with('@') {
-            return None;
-        }
-
-        let host_parts: Vec<&str> = user_host.split('@').collect();
-        match host_parts.as_slice() {
-            [user, host] => {
-                if host.is_empty() {
-                    return None;
-                }
-                Some((Some((user.to_string(), host.to_string())), path))
-            }
-            [host] => {
-                if host.is_empty() {
-                    return None;
-                }
-                let user = env::var("USER").ok()?;
-                Some((Some((user, host.to_string())), path))
-            }
-            _ => None,
-        }
-    } else {
-        // No colon = local path
-        Some((None, loc.to_string()))
-    }
-}
-
-fn validate_paths(source: &str, destination: &str) -> Result<(), String> {
-    let (source_remote, source_path) = parse_location(source)
-        .ok_or_else(|| "Invalid source format. Expected either a local path or user@host:path".to_string())?;
-    
-    let (dest_remote, dest_path) = parse_location(destination)
-        .ok_or_else(|| "Invalid destination format. Expected either a local path or user@host:path".to_string())?;
-
-    // Check that exactly one location is remote
-    match (source_remote.is_some(), dest_remote.is_some()) {
-        (true, true) => {
-            Err("Cannot copy from remote to remote".to_string())
-        },
-        (false, false) => {
-            Err("At least one location must be remote".to_string())
-        },
-        _ => {
-            // For local paths, verify they exist and are valid
-            if source_remote.is_none() {
-                let path = Path::new(&source_path);
-                if !path.exists() {
-                    return Err(format!("Source file '{}' does not exist", source_path));
-                }
-                if !path.is_file() {
-                    return Err(format!("Source path '{}' is not a file", source_path));
-                }
-            }
-            
-            if dest_remote.is_none() {
-                let path = Path::new(&dest_path);
-                if !path.exists() {
-                    return Err(format!("Destination directory '{}' does not exist", dest_path));
-                }
-                if !path.is_dir() {
-                    return Err(format!("Destination path '{}' is not a directory", dest_path));
-                }
-            }
-            
-            Ok(())
-        }
-    }
-}
 
 fn main() {
     let matches = App::new("Zap")
@@ -156,13 +9,14 @@ fn main() {
         .author("Allan Clark. <napta2k@gmail.com>")
         .about("Transfers a file in parallel streams over SSH")
         .arg_required_else_help(true)
+        .override_usage("zap [OPTIONS] <source> <destination>")
         .arg(Arg::new("source")
-            .help("Source file (local file or user@host:remote_path)")
-            .required(true)
+            .help("Source file (local file, user@host:remote_path or - for stdin)")
+            .required_unless_present("server")
             .index(1))
         .arg(Arg::new("destination")
-            .help("Destination (local file or user@host:remote_path)")
-            .required(true)
+            .help("Destination (local file, user@host:remote_path or - for stdout)")
+            .required_unless_present("server")
             .index(2))
         .arg(Arg::new("streams")
             .short('s')
@@ -193,6 +47,113 @@ fn main() {
             .long("quiet")
             .help("Suppress progress output")
             .takes_value(false))
+        .arg(Arg::new("verbose")
+            .short('v')
+            .long("verbose")
+            .help("Show negotiated SSH algorithms and other details")
+            .takes_value(false))
+        .arg(Arg::new("json")
+            .long("json")
+            .help("Print a JSON report, or the error, when done instead of text")
+            .takes_value(false))
+        .arg(Arg::new("json_progress")
+            .long("json-progress")
+            .help("Print progress as newline-delimited JSON instead of bars")
+            .takes_value(false))
+        .arg(Arg::new("cipher")
+            .short('c')
+            .long("cipher")
+            .help("SSH cipher preference, comma separated")
+            .takes_value(true))
+        .arg(Arg::new("mac")
+            .long("mac")
+            .help("SSH MAC preference, comma separated")
+            .takes_value(true))
+        .arg(Arg::new("compress")
+            .short('C')
+            .long("compress")
+            .help("Enable zlib compression on SSH sessions")
+            .takes_value(false))
+        .arg(Arg::new("mkdir")
+            .short('D')
+            .long("mkdir")
+            .help("Create missing destination directories")
+            .takes_value(false))
+        .arg(Arg::new("no_clobber")
+            .long("no-clobber")
+            .help("Never overwrite an existing destination")
+            .conflicts_with_all(&["update", "skip_identical"])
+            .takes_value(false))
+        .arg(Arg::new("update")
+            .long("update")
+            .help("Overwrite only if the source is newer")
+            .conflicts_with("skip_identical")
+            .takes_value(false))
+        .arg(Arg::new("skip_identical")
+            .long("skip-identical")
+            .help("Skip if size and mtime or hash match")
+            .takes_value(false))
+        .arg(Arg::new("delta")
+            .long("delta")
+            .help("Only send changed blocks, patching a destination copy")
+            .takes_value(false))
+        .arg(Arg::new("sparse")
+            .long("sparse")
+            .help("Don't write all-zero blocks; keep the destination sparse")
+            .takes_value(false))
+        .arg(Arg::new("preallocate")
+            .long("preallocate")
+            .help("Reserve the destination's disk space up front")
+            .conflicts_with("sparse")
+            .takes_value(false))
+        .arg(Arg::new("force")
+            .long("force")
+            .help("Transfer even if the destination looks too full")
+            .takes_value(false))
+        .arg(Arg::new("no_fsync")
+            .long("no-fsync")
+            .help("Don't flush the destination to disk at the end")
+            .takes_value(false))
+        .arg(Arg::new("preserve")
+            .long("preserve")
+            .help("Preserve mode, times and (as root) ownership")
+            .takes_value(false))
+        .arg(Arg::new("remote_zap")
+            .long("remote-zap")
+            .help("Remote zap binary to run as a helper")
+            .takes_value(true)
+            .default_value("zap"))
+        .arg(Arg::new("no_helper")
+            .long("no-helper")
+            .help("Always use plain SFTP")
+            .takes_value(false))
+        .arg(Arg::new("transport")
+            .short('t')
+            .long("transport")
+            .help("Data transport: ssh, tcp or quic")
+            .takes_value(true)
+            .possible_values(["ssh", "tcp", "quic"])
+            .hide_possible_values(true)
+            .default_value("ssh"))
+        .arg(Arg::new("unencrypted")
+            .long("unencrypted")
+            .help("Send tcp transport data in the clear")
+            .takes_value(false))
+        .arg(Arg::new("congestion")
+            .long("congestion")
+            .help("QUIC congestion control: cubic, newreno or bbr")
+            .takes_value(true)
+            .possible_values(["cubic", "newreno", "bbr"])
+            .hide_possible_values(true))
+        .arg(Arg::new("direct")
+            .long("direct")
+            .help("Remote to remote: run zap on the source host")
+            .takes_value(false))
+        .arg(Arg::new("server")
+            .long("server")
+            .help("Run as a remote helper speaking the zap protocol on stdin/stdout")
+            .hide(true)
+            .takes_value(false))
         .after_help(
             "EXAMPLES:\n\
             \tPull a file from remote to local:\n\
@@ -203,92 +164,153 @@ fn main() {
         )
         .get_matches();
 
+    if matches.is_present("server") {
+        if let Err(e) = zap::run_server() {
+            eprintln!("zap server: {}", e);
+            process::exit(exit::FAILURE);
+        }
+        return;
+    }
+
     let source = matches.value_of("source").unwrap();
     let destination = matches.value_of("destination").unwrap();
 
-    // Validate source and destination paths
-    if let Err(e) = validate_paths(source, destination) {
-        eprintln!("Error: {}", e);
-        process::exit(1);
-    }
-
-    let (source_remote, source_path) = parse_location(source).unwrap();
-    let (dest_remote, dest_path) = parse_location(destination).unwrap();
+    // With the data on stdout, everything else goes to stderr
+    let to_stdout = destination == "-";
+    let quiet = matches.is_present("quiet");
+    let json = matches.is_present("json");
+    let json_progress = matches.is_present("json_progress");
 
     // Parse common arguments
     let num_streams: usize = matches.value_of("streams").unwrap()
         .parse()
-        .unwrap_or_else(|_| {
-            eprintln!("Error: streams must be a positive integer");
-            process::exit(1);
-        });
-
-    if num_streams == 0 {
-        eprintln!("Error: streams must be at least 1");
-        process::exit(1);
-    }
+        .unwrap_or_else(|_| fail(ZapError::Usage("streams must be a positive integer".to_string()), json, to_stdout));
 
     let ssh_port: u16 = matches.value_of("port").unwrap()
         .parse::<u16>()
-        .unwrap_or_else(|_| {
-            eprintln!("Error: port must be between 1 and 65535");
-            process::exit(1);
-        });
-
-    if ssh_port == 0 {
-        eprintln!("Error: port must be between 1 and 65535");
-        process::exit(1);
-    }
+        .unwrap_or_else(|_| fail(ZapError::Usage("port must be between 1 and 65535".to_string()), json, to_stdout));
 
     let retries: u32 = matches.value_of("retries").unwrap()
         .parse()
-        .unwrap_or_else(|_| {
-            eprintln!("Error: retries must be a positive integer");
-            process::exit(1);
+        .unwrap_or_else(|_| fail(ZapError::Usage("retries must be a positive integer".to_string()), json, to_stdout));
+
+    let unencrypted = matches.is_present("unencrypted");
+    let congestion = matches.value_of("congestion").and_then(Congestion::from_name);
+    let transport = match matches.value_of("transport").unwrap() {
+        "tcp" => Transport::Tcp { encrypt: !unencrypted },
+        "quic" => Transport::Quic { congestion: congestion.unwrap_or(Congestion::Cubic) },
+        _ => Transport::Ssh,
+    };
+    if unencrypted && !matches!(transport, Transport::Tcp { .. }) {
+        fail(ZapError::Usage("--unencrypted only applies to --transport tcp".to_string()), json, to_stdout);
+    }
+    if congestion.is_some() && !matches!(transport, Transport::Quic { .. }) {
+        fail(ZapError::Usage("--congestion only applies to --transport quic".to_string()), json, to_stdout);
+    }
+    if matches!(transport, Transport::Quic { .. }) && !cfg!(feature = "quic") {
+        fail(ZapError::Usage("this zap was built without QUIC support (rebuild with --features quic)".to_string()), json, to_stdout);
+    }
+
+    let mut transfer = TransferBuilder::new(source, destination)
+        .streams(num_streams)
+        .retries(retries)
+        .port(ssh_port)
+        .remote_zap(matches.value_of("remote_zap").unwrap())
+        .use_helper(!matches.is_present("no_helper"))
+        .compress(matches.is_present("compress"))
+        .transport(transport)
+        .direct(matches.is_present("direct"))
+        .direct_output(io::stdout())
+        .direct_json(json)
+        .direct_json_progress(json_progress)
+        .preserve(matches.is_present("preserve"))
+        .mkdir(matches.is_present("mkdir"))
+        .existing(if matches.is_present("no_clobber") {
+            ExistingPolicy::NoClobber
+        } else if matches.is_present("update") {
+            ExistingPolicy::Update
+        } else if matches.is_present("skip_identical") {
+            ExistingPolicy::SkipIdentical
+        } else {
+            ExistingPolicy::Overwrite
+        })
+        .delta(matches.is_present("delta"))
+        .sparse(matches.is_present("sparse"))
+        .preallocate(matches.is_present("preallocate"))
+        .force(matches.is_present("force"))
+        .fsync(!matches.is_present("no_fsync"))
+        .quiet(quiet || json || json_progress)
+        .verbose(matches.is_present("verbose"))
+        .on_message(move |kind, msg| match kind {
+            MessageKind::Info if to_stdout => eprintln!("{}", msg),
+            MessageKind::Info => println!("{}", msg),
+            MessageKind::Warning => eprintln!("Warning: {}", msg),
+            MessageKind::Error => eprintln!("{}", msg),
         });
+    if json_progress {
+        transfer = if to_stdout {
+            transfer.progress(JsonProgress::new(io::stderr()))
+        } else {
+            transfer.progress(JsonProgress::new(io::stdout()))
+        };
+    } else if !quiet && !json {
+        transfer = transfer.progress(IndicatifProgress::new());
+    }
 
-    let ssh_key_path = matches.value_of("ssh_key_path");
-    let quiet_mode = matches.is_present("quiet");
+    // The first SIGINT or SIGTERM stops the streams and cleans up; a second
+    // one quits on the spot
+    let cancel = CancelToken::new();
+    let on_signal = cancel.clone();
+    let _ = ctrlc::set_handler(move || {
+        if on_signal.is_cancelled() {
+            process::exit(exit::CANCELLED);
+        }
+        eprintln!("\nCancelling; press Ctrl-C again to quit now");
+        on_signal.cancel();
+    });
+    transfer = transfer.cancel(cancel);
+    if let Some(key_path) = matches.value_of("ssh_key_path") {
+        transfer = transfer.key_path(key_path);
+    }
+    if let Some(ciphers) = matches.value_of("cipher") {
+        transfer = transfer.ciphers(ciphers);
+    }
+    if let Some(macs) = matches.value_of("mac") {
+        transfer = transfer.macs(macs);
+    }
 
-    match (source_remote, dest_remote) {
-        (Some((remote_user, remote_host)), None) => {
-            // Pull transfer
-            if let Err(e) = split_and_copy_from_remote(
-                quiet_mode,
-                &source_path,
-                num_streams,
-                &remote_user,
-                &remote_host,
-                &dest_path,
-                ssh_key_path,
-                retries,
-                ssh_port,
-            ) {
-                eprintln!("Error during pull transfer: {}", e);
-                process::exit(1);
-            }
-        },
-        (None, Some((remote_user, remote_host))) => {
-            // Push transfer
-            if let Err(e) = split_and_copy_binary_file(
-                quiet_mode,
-                &source_path,
-                num_streams,
-                &remote_user,
-                &remote_host,
-                &dest_path,
-                ssh_key_path,
-                retries,
-                ssh_port,
-            ) {
-                eprintln!("Error during push transfer: {}", e);
-                process::exit(1);
+    match transfer.run() {
+        Ok(report) if json => emit(&report.to_json(), to_stdout),
+        // NDJSON progress keeps its output free of text too
+        Ok(report) if report.outcome == TransferOutcome::Completed && !quiet && !json_progress => {
+            if to_stdout {
+                eprintln!("Transfer completed successfully!\n\n{}", report);
+            } else {
+                println!("Transfer completed successfully!\n\n{}", report);
             }
         },
-        _ => {
-            // This shouldn't happen due to validate_paths, but handle it anyway
-            eprintln!("Error: Either source or destination must be remote, but not both");
-            process::exit(1);
-        }
+        Ok(_) => {},
+        Err(e) => fail(e, json, to_stdout),
+    }
+}
+
+/// Machine-readable output goes where the text would have, and replaces it
+fn emit(line: &str, to_stdout: bool) {
+    if to_stdout {
+        eprintln!("{}", line);
+    } else {
+        println!("{}", line);
+    }
+}
+
+/// Report a failure, as text or as JSON, and exit with its code
+fn fail(e: ZapError, json: bool, to_stdout: bool) -> ! {
+    let _ = io::stdout().flush();
+    match e {
+        _ if json => emit(&e.to_json(), to_stdout),
+        // zap on the source host of --direct has already said what went wrong
+        ZapError::RemoteExit { .. } => {},
+        _ => eprintln!("Error: {}", e),
     }
+    process::exit(e.exit_code());
 }
@@ -0,0 +1,418 @@
+use std::cell::Cell;
+use std::fmt;
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, Instant};
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use crate::json::Object;
+
+/// What a stream is busy with besides moving data
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    /// Hashing blocks on both ends to find what --delta has to send
+    Comparing,
+    /// Moving data
+    Transferring,
+    /// Flushing the destination to disk
+    Syncing,
+}
+
+impl Phase {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Phase::Comparing => "comparing",
+            Phase::Transferring => "transferring",
+            Phase::Syncing => "syncing",
+        }
+    }
+}
+
+/// Progress of a running transfer, reported from its worker threads
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TransferEvent {
+    /// Setup is done and `streams` streams are about to start. `total` is
+    /// the file size, unknown when reading stdin.
+    Started { streams: usize, total: Option<u64> },
+    /// A stream is taking on `len` bytes, if known up front
+    StreamStarted { stream: usize, len: Option<u64> },
+    /// How far into its part a stream is, counting holes and unchanged
+    /// --delta blocks as done
+    Bytes { stream: usize, position: u64 },
+    Phase { stream: usize, phase: Phase },
+    /// A stream failed and will reconnect and resume; `attempt` counts from 1
+    Retry { stream: usize, attempt: u32, error: String },
+    /// A stream gave up, which fails the transfer
+    StreamFailed { stream: usize, error: String },
+    StreamDone { stream: usize },
+    /// The transfer is over, successfully or not; nothing follows
+    Done,
+}
+
+/// Receives a transfer's progress. Called from every worker thread, so it
+/// must be quick.
+pub trait ProgressSink: Send + Sync {
+    fn event(&self, event: &TransferEvent);
+}
+
+/// Events can be sent to a channel and handled on another thread
+impl ProgressSink for mpsc::Sender<TransferEvent> {
+    fn event(&self, event: &TransferEvent) {
+        let _ = self.send(event.clone());
+    }
+}
+
+/// What one stream did
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StreamReport {
+    pub stream: usize,
+    /// How far into its part the stream got, counting holes and unchanged
+    /// --delta blocks
+    pub bytes: u64,
+    pub duration: Duration,
+    pub retries: u32,
+}
+
+/// Throughput is sampled over windows this long for the peak
+const PEAK_WINDOW: Duration = Duration::from_secs(1);
+
+/// What the streams of one run did, for its report
+#[derive(Default)]
+struct Tally {
+    position: AtomicU64,
+    streams: Mutex<Vec<StreamReport>>,
+    /// Start of the current window (time, position) and the fastest so far
+    window: Mutex<(Option<(Instant, u64)>, f64)>,
+}
+
+impl Tally {
+    fn sample(&self) {
+        // A stream that finds the window busy leaves it to the other
+        let Ok(mut window) = self.window.try_lock() else { return };
+        let now = Instant::now();
+        let position = self.position.load(Ordering::Relaxed);
+        match window.0 {
+            Some((since, from)) if now.duration_since(since) >= PEAK_WINDOW => {
+                let speed = position.saturating_sub(from) as f64 / now.duration_since(since).as_secs_f64();
+                window.1 = window.1.max(speed);
+                window.0 = Some((now, position));
+            },
+            Some(_) => {},
+            None => window.0 = Some((now, position)),
+        }
+    }
+}
+
+/// Where progress goes; nowhere unless a sink is set. Also tallies what the
+/// streams did for the report.
+#[derive(Clone, Default)]
+pub struct Progress {
+    sink: Option<Arc<dyn ProgressSink>>,
+    tally: Arc<Tally>,
+}
+
+impl Progress {
+    pub fn new<S: ProgressSink + 'static>(sink: S) -> Self {
+        Progress { sink: Some(Arc::new(sink)), tally: Arc::default() }
+    }
+
+    /// The same sink with nothing tallied yet, for another run
+    pub fn restart(&self) -> Self {
+        Progress { sink: self.sink.clone(), tally: Arc::default() }
+    }
+
+    pub fn send(&self, event: TransferEvent) {
+        if let Some(ref sink) = self.sink {
+            sink.event(&event);
+        }
+    }
+
+    /// How far all streams together have got
+    pub fn position(&self) -> u64 {
+        self.tally.position.load(Ordering::Relaxed)
+    }
+
+    /// What each finished stream did, in stream order
+    pub fn stream_reports(&self) -> Vec<StreamReport> {
+        let mut streams = self.tally.streams.lock().unwrap().clone();
+        streams.sort_by_key(|s| s.stream);
+        streams
+    }
+
+    /// Fastest throughput over a whole window, in bytes per second; zero if
+    /// the transfer took less than one
+    pub fn peak_speed(&self) -> f64 {
+        self.tally.window.lock().unwrap().1
+    }
+
+    pub fn started(&self, streams: usize, total: Option<u64>) {
+        self.send(TransferEvent::Started { streams, total });
+    }
+
+    /// Handle for one stream's worker, announcing the stream
+    pub fn stream(&self, stream: usize, len: Option<u64>) -> StreamProgress {
+        self.send(TransferEvent::StreamStarted { stream, len });
+        StreamProgress::new(self.clone(), stream)
+    }
+
+    /// Handle for a worker whose progress is reported by another
+    pub fn hidden(stream: usize) -> StreamProgress {
+        StreamProgress::new(Progress::default(), stream)
+    }
+}
+
+impl fmt::Debug for Progress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(if self.sink.is_some() { "Progress(sink)" } else { "Progress(none)" })
+    }
+}
+
+/// One stream's view of the progress sink, owned by its worker. Its report
+/// is tallied when the worker drops it.
+pub struct StreamProgress {
+    progress: Progress,
+    stream: usize,
+    position: Cell<u64>,
+    started: Instant,
+    retries: Cell<u32>,
+}
+
+impl StreamProgress {
+    fn new(progress: Progress, stream: usize) -> Self {
+        StreamProgress { progress, stream, position: Cell::new(0), started: Instant::now(), retries: Cell::new(0) }
+    }
+
+    pub fn set_position(&self, position: u64) {
+        // Retries and --delta comparisons can move a stream back
+        let previous = self.position.replace(position);
+        if position >= previous {
+            self.progress.tally.position.fetch_add(position - previous, Ordering::Relaxed);
+        } else {
+            self.progress.tally.position.fetch_sub(previous - position, Ordering::Relaxed);
+        }
+        self.progress.tally.sample();
+        self.progress.send(TransferEvent::Bytes { stream: self.stream, position });
+    }
+
+    pub fn inc(&self, delta: u64) {
+        self.set_position(self.position.get() + delta);
+    }
+
+    pub fn phase(&self, phase: Phase) {
+        self.progress.send(TransferEvent::Phase { stream: self.stream, phase });
+    }
+
+    pub fn retry(&self, attempt: u32, error: &io::Error) {
+        self.retries.set(attempt);
+        self.progress.send(TransferEvent::Retry { stream: self.stream, attempt, error: error.to_string() });
+    }
+
+    pub fn failed(&self, error: &io::Error) {
+        self.progress.send(TransferEvent::StreamFailed { stream: self.stream, error: error.to_string() });
+    }
+
+    pub fn done(&self) {
+        self.progress.send(TransferEvent::StreamDone { stream: self.stream });
+    }
+}
+
+impl Drop for StreamProgress {
+    fn drop(&mut self) {
+        self.progress.tally.streams.lock().unwrap().push(StreamReport {
+            stream: self.stream,
+            bytes: self.position.get(),
+            duration: self.started.elapsed(),
+            retries: self.retries.get(),
+        });
+    }
+}
+
+struct StreamBar {
+    bar: ProgressBar,
+    started: Instant,
+    last_update: Instant,
+}
+
+#[derive(Default)]
+struct Bars {
+    streams: Vec<Option<StreamBar>>,
+    /// Single byte counter, with each stream's position, when the total
+    /// isn't known
+    counter: Option<(ProgressBar, Vec<u64>)>,
+}
+
+/// Terminal progress bars on stderr: one per stream, or a running byte
+/// count when the size isn't known
+pub struct IndicatifProgress {
+    multi: MultiProgress,
+    bars: Mutex<Bars>,
+}
+
+impl IndicatifProgress {
+    pub fn new() -> Self {
+        IndicatifProgress { multi: MultiProgress::new(), bars: Mutex::new(Bars::default()) }
+    }
+}
+
+impl Default for IndicatifProgress {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ProgressSink for IndicatifProgress {
+    fn event(&self, event: &TransferEvent) {
+        let mut bars = self.bars.lock().unwrap();
+        match *event {
+            TransferEvent::Started { streams, total: None } => {
+                let counter = self.multi.add(ProgressBar::new_spinner());
+                counter.set_style(ProgressStyle::with_template("[{elapsed_precise}] {spinner} {bytes} ({bytes_per_sec})").unwrap());
+                counter.enable_steady_tick(Duration::from_millis(100));
+                bars.counter = Some((counter, vec![0; streams]));
+            },
+            TransferEvent::Started { streams, total: Some(_) } => {
+                bars.streams = (0..streams).map(|_| None).collect();
+            },
+            TransferEvent::StreamStarted { stream, len: Some(len) } if bars.counter.is_none() => {
+                let bar = self.multi.add(ProgressBar::new(len));
+                bar.set_style(ProgressStyle::with_template(
+                    "[{elapsed_precise}] {bar:40.cyan/blue} {pos:>7}/{len:7} {msg}",
+                )
+                .unwrap()
+                .progress_chars("##-"));
+                bar.set_message(format!("Stream {}", stream));
+                let now = Instant::now();
+                if let Some(slot) = bars.streams.get_mut(stream) {
+                    *slot = Some(StreamBar { bar, started: now, last_update: now });
+                }
+            },
+            TransferEvent::Bytes { stream, position } => {
+                if let Some((ref counter, ref mut positions)) = bars.counter {
+                    positions[stream] = position;
+                    counter.set_position(positions.iter().sum());
+                } else if let Some(Some(s)) = bars.streams.get_mut(stream) {
+                    s.bar.set_position(position);
+
+                    // Update throughput display
+                    let now = Instant::now();
+                    if now.duration_since(s.last_update) > Duration::from_secs(1) {
+                        let elapsed = now.duration_since(s.started).as_secs_f64();
+                        let throughput = (position as f64 / 1024.0 / 1024.0) / elapsed;
+                        s.bar.set_message(format!("{:.2} MB/s", throughput));
+                        s.last_update = now;
+                    }
+                }
+            },
+            TransferEvent::Phase { stream, phase } => {
+                if let Some(Some(s)) = bars.streams.get(stream) {
+                    s.bar.set_message(phase.name());
+                }
+            },
+            TransferEvent::Retry { stream, attempt, .. } => {
+                if let Some(Some(s)) = bars.streams.get(stream) {
+                    s.bar.set_message(format!("retry {}", attempt));
+                }
+            },
+            TransferEvent::StreamFailed { stream, .. } => {
+                if let Some(Some(s)) = bars.streams.get(stream) {
+                    s.bar.finish_with_message("failed");
+                }
+            },
+            TransferEvent::StreamDone { stream } => {
+                if let Some(Some(s)) = bars.streams.get(stream) {
+                    s.bar.finish_with_message("done");
+                }
+            },
+            TransferEvent::Done => {
+                if let Some((ref counter, _)) = bars.counter {
+                    counter.finish_and_clear();
+                }
+            },
+            TransferEvent::StreamStarted { .. } => {},
+        }
+    }
+}
+
+/// How often `JsonProgress` writes a progress line
+const JSON_PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+
+struct JsonState<W> {
+    out: W,
+    started: Instant,
+    total: Option<u64>,
+    positions: Vec<u64>,
+    /// When the last progress line was written, and the bytes done then
+    last_line: (Instant, u64),
+}
+
+impl<W> JsonState<W> {
+    fn progress_line(&mut self, event: &str) -> Object {
+        let now = Instant::now();
+        let bytes = self.positions.iter().sum::<u64>();
+        let (since, from) = self.last_line;
+        let speed = bytes.saturating_sub(from) as f64 / now.duration_since(since).as_secs_f64();
+        self.last_line = (now, bytes);
+        Object::new()
+            .field("event", event)
+            .field("elapsed", &now.duration_since(self.started).as_secs_f64())
+            .field("bytes", &bytes)
+            .field("total", &self.total)
+            .field("speed", &speed)
+            .field("streams", &self.positions)
+    }
+}
+
+/// Progress as newline-delimited JSON for dashboards and scripts: a line
+/// when the transfer starts, one a second while it runs with the bytes done
+/// overall and per stream, one per retry or failed stream, and one when it
+/// is done. Durations are in seconds and speeds in bytes per second.
+pub struct JsonProgress<W: Write + Send> {
+    state: Mutex<JsonState<W>>,
+}
+
+impl<W: Write + Send> JsonProgress<W> {
+    pub fn new(out: W) -> Self {
+        let now = Instant::now();
+        JsonProgress {
+            state: Mutex::new(JsonState { out, started: now, total: None, positions: Vec::new(), last_line: (now, 0) }),
+        }
+    }
+}
+
+impl<W: Write + Send> ProgressSink for JsonProgress<W> {
+    fn event(&self, event: &TransferEvent) {
+        let mut state = self.state.lock().unwrap();
+        let line = match *event {
+            TransferEvent::Started { streams, total } => {
+                let now = Instant::now();
+                state.started = now;
+                state.last_line = (now, 0);
+                state.total = total;
+                state.positions = vec![0; streams];
+                Some(Object::new().field("event", "started").field("streams", &streams).field("total", &total))
+            },
+            TransferEvent::Bytes { stream, position } => {
+                if let Some(p) = state.positions.get_mut(stream) {
+                    *p = position;
+                }
+                let due = state.last_line.0.elapsed() >= JSON_PROGRESS_INTERVAL;
+                due.then(|| state.progress_line("progress"))
+            },
+            TransferEvent::Retry { stream, attempt, ref error } => Some(Object::new()
+                .field("event", "retry")
+                .field("stream", &stream)
+                .field("attempt", &attempt)
+                .field("error", error)),
+            TransferEvent::StreamFailed { stream, ref error } => Some(Object::new()
+                .field("event", "stream_failed")
+                .field("stream", &stream)
+                .field("error", error)),
+            TransferEvent::Done => Some(state.progress_line("done")),
+            TransferEvent::StreamStarted { .. } | TransferEvent::Phase { .. } | TransferEvent::StreamDone { .. } => None,
+        };
+        if let Some(line) = line {
+            let _ = writeln!(state.out, "{}", line.finish());
+            let _ = state.out.flush();
+        }
+    }
+}
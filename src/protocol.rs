@@ -0,0 +1,661 @@
+use std::io::{self, Read, Write};
+use ssh2::{Channel, Session};
+use crate::error::ZapError;
+
+/// Bumped whenever a frame layout changes; both sides must agree exactly.
+pub const PROTOCOL_VERSION: u32 = 2;
+pub const MAGIC: &[u8; 4] = b"ZAP\0";
+const MAX_FRAME_LEN: usize = 16 * 1024 * 1024;
+const PROBE_TIMEOUT_MS: u32 = 10_000;
+/// Most block hashes asked for in one BLOCK_HASHES request, to stay well
+/// under MAX_FRAME_LEN
+const MAX_HASH_BLOCKS: u64 = 64 * 1024;
+
+// Requests (client → server)
+pub const OP_HELLO: u8 = 0x01;
+pub const OP_STAT: u8 = 0x02;
+pub const OP_OPEN: u8 = 0x03;
+pub const OP_SET_LEN: u8 = 0x04;
+pub const OP_READ: u8 = 0x05;
+pub const OP_WRITE: u8 = 0x06;
+pub const OP_HASH: u8 = 0x07;
+pub const OP_LISTEN: u8 = 0x08;
+pub const OP_QUIC_LISTEN: u8 = 0x09;
+pub const OP_RENAME: u8 = 0x0a;
+pub const OP_SET_STAT: u8 = 0x0b;
+pub const OP_BLOCK_HASHES: u8 = 0x0c;
+pub const OP_ALLOCATE: u8 = 0x0d;
+pub const OP_STATVFS: u8 = 0x0e;
+pub const OP_FSYNC: u8 = 0x0f;
+pub const OP_COPY: u8 = 0x10;
+
+// Responses (server → client) and data frames (either direction)
+pub const OP_OK: u8 = 0x80;
+pub const OP_ERROR: u8 = 0x81;
+pub const OP_DATA: u8 = 0x82;
+pub const OP_END: u8 = 0x83;
+/// A run of zeros inside a READ or WRITE stream that is not sent as data
+pub const OP_HOLE: u8 = 0x84;
+
+// OPEN flags
+pub const OPEN_READ: u8 = 0x01;
+pub const OPEN_WRITE: u8 = 0x02;
+pub const OPEN_CREATE: u8 = 0x04;
+pub const OPEN_TRUNCATE: u8 = 0x08;
+
+/// Anything a helper conversation can run over: an SSH exec channel, a raw
+/// TCP data connection or a QUIC stream
+pub trait Duplex: Read + Write + Send {}
+impl<T: Read + Write + Send> Duplex for T {}
+
+/// Separate read and write halves presented as one duplex stream
+pub struct Split<R, W> {
+    pub reader: R,
+    pub writer: W,
+}
+
+impl<R: Read, W> Read for Split<R, W> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.reader.read(buf)
+    }
+}
+
+impl<R, W: Write> Write for Split<R, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.writer.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// File metadata as reported by the remote helper, also used for local files
+#[derive(Debug, Clone, Copy)]
+pub struct RemoteStat {
+    pub size: u64,
+    pub mode: u32,
+    pub mtime: u64,
+    pub atime: u64,
+    pub uid: u32,
+    pub gid: u32,
+    pub is_dir: bool,
+}
+
+/// Read one frame, returning None on a clean EOF between frames
+pub fn read_frame<R: Read + ?Sized>(r: &mut R) -> io::Result<Option<(u8, Vec<u8>)>> {
+    let mut header = [0u8; 5];
+    let mut filled = 0;
+    while filled < header.len() {
+        let n = r.read(&mut header[filled..])?;
+        if n == 0 {
+            if filled == 0 {
+                return Ok(None);
+            }
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Truncated frame header"));
+        }
+        filled += n;
+    }
+
+    let len = u32::from_be_bytes([header[1], header[2], header[3], header[4]]) as usize;
+    if len > MAX_FRAME_LEN {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Frame too large: {} bytes", len)));
+    }
+
+    let mut payload = vec![0u8; len];
+    r.read_exact(&mut payload)?;
+    Ok(Some((header[0], payload)))
+}
+
+pub fn write_frame<W: Write + ?Sized>(w: &mut W, op: u8, payload: &[u8]) -> io::Result<()> {
+    let mut header = [0u8; 5];
+    header[0] = op;
+    header[1..].copy_from_slice(&(payload.len() as u32).to_be_bytes());
+    w.write_all(&header)?;
+    w.write_all(payload)
+}
+
+/// Cursor over a frame payload
+pub struct Decoder<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Decoder<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        Decoder { buf }
+    }
+
+    fn take(&mut self, n: usize) -> io::Result<&'a [u8]> {
+        if self.buf.len() < n {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Short frame payload"));
+        }
+        let (head, rest) = self.buf.split_at(n);
+        self.buf = rest;
+        Ok(head)
+    }
+
+    pub fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub fn u64(&mut self) -> io::Result<u64> {
+        Ok(u64::from_be_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    pub fn bytes(&mut self, n: usize) -> io::Result<&'a [u8]> {
+        self.take(n)
+    }
+
+    /// Whatever is left, e.g. a trailing path
+    pub fn rest(&mut self) -> &'a [u8] {
+        std::mem::take(&mut self.buf)
+    }
+}
+
+/// Builder for a frame payload
+#[derive(Default)]
+pub struct Encoder {
+    pub buf: Vec<u8>,
+}
+
+impl Encoder {
+    pub fn new() -> Self {
+        Encoder { buf: Vec::new() }
+    }
+
+    pub fn u8(mut self, v: u8) -> Self {
+        self.buf.push(v);
+        self
+    }
+
+    pub fn u32(mut self, v: u32) -> Self {
+        self.buf.extend_from_slice(&v.to_be_bytes());
+        self
+    }
+
+    pub fn u64(mut self, v: u64) -> Self {
+        self.buf.extend_from_slice(&v.to_be_bytes());
+        self
+    }
+
+    pub fn bytes(mut self, v: &[u8]) -> Self {
+        self.buf.extend_from_slice(v);
+        self
+    }
+}
+
+pub fn encode_stat(st: &RemoteStat) -> Vec<u8> {
+    Encoder::new()
+        .u64(st.size)
+        .u32(st.mode)
+        .u64(st.mtime)
+        .u64(st.atime)
+        .u32(st.uid)
+        .u32(st.gid)
+        .u8(st.is_dir as u8)
+        .buf
+}
+
+pub fn decode_stat(payload: &[u8]) -> io::Result<RemoteStat> {
+    let mut d = Decoder::new(payload);
+    Ok(RemoteStat {
+        size: d.u64()?,
+        mode: d.u32()?,
+        mtime: d.u64()?,
+        atime: d.u64()?,
+        uid: d.u32()?,
+        gid: d.u32()?,
+        is_dir: d.u8()? != 0,
+    })
+}
+
+/// Compare secrets without leaking where they differ
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn unexpected(op: u8) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("Unexpected frame 0x{:02x} from helper", op))
+}
+
+/// Turn an ERROR frame into an io::Error, or complain about anything else
+fn helper_error(op: u8, payload: &[u8]) -> io::Error {
+    if op == OP_ERROR {
+        ZapError::remote(&String::from_utf8_lossy(payload)).into()
+    } else {
+        unexpected(op)
+    }
+}
+
+/// Client side of a conversation with `zap --server`
+pub struct HelperClient {
+    stream: Box<dyn Duplex>,
+}
+
+impl HelperClient {
+    /// Wrap an already-established stream and perform the version handshake
+    pub fn new(stream: Box<dyn Duplex>) -> io::Result<Self> {
+        let mut client = HelperClient { stream };
+        client.hello()?;
+        Ok(client)
+    }
+
+    /// Start the helper over an SSH exec channel on an authenticated session
+    pub fn exec(sess: &Session, command: &str) -> io::Result<Self> {
+        let mut channel: Channel = sess.channel_session()?;
+        channel.exec(&format!("{} --server", command))?;
+        HelperClient::new(Box::new(channel))
+    }
+
+    /// Check whether the remote side can run the helper at all. A missing
+    /// binary just closes the channel, so any failure here means "use SFTP".
+    pub fn probe(sess: &Session, command: &str) -> bool {
+        sess.set_timeout(PROBE_TIMEOUT_MS);
+        let ok = HelperClient::exec(sess, command).is_ok();
+        sess.set_timeout(0);
+        ok
+    }
+
+    fn request(&mut self, op: u8, payload: &[u8]) -> io::Result<(u8, Vec<u8>)> {
+        write_frame(&mut self.stream, op, payload)?;
+        self.stream.flush()?;
+        self.response()
+    }
+
+    fn response(&mut self) -> io::Result<(u8, Vec<u8>)> {
+        read_frame(&mut self.stream)?
+            .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "Remote helper closed the connection"))
+    }
+
+    fn expect_ok(&mut self, op: u8, payload: &[u8]) -> io::Result<Vec<u8>> {
+        match self.request(op, payload)? {
+            (OP_OK, body) => Ok(body),
+            (op, body) => Err(helper_error(op, &body)),
+        }
+    }
+
+    fn hello(&mut self) -> io::Result<()> {
+        let payload = Encoder::new().bytes(MAGIC).u32(PROTOCOL_VERSION).buf;
+        let body = self.expect_ok(OP_HELLO, &payload)?;
+        let mut d = Decoder::new(&body);
+        if d.bytes(MAGIC.len())? != MAGIC {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Remote program is not a zap helper"));
+        }
+        let version = d.u32()?;
+        if version != PROTOCOL_VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Remote helper speaks protocol {}, expected {}", version, PROTOCOL_VERSION),
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn stat(&mut self, path: &str) -> io::Result<RemoteStat> {
+        let body = self.expect_ok(OP_STAT, path.as_bytes())?;
+        decode_stat(&body)
+    }
+
+    /// Open the file all subsequent range operations on this connection use
+    pub fn open(&mut self, path: &str, flags: u8) -> io::Result<()> {
+        let payload = Encoder::new().u8(flags).bytes(path.as_bytes()).buf;
+        self.expect_ok(OP_OPEN, &payload).map(|_| ())
+    }
+
+    /// Atomically move `from` over `to` on the remote side
+    pub fn rename(&mut self, from: &str, to: &str) -> io::Result<()> {
+        let payload = Encoder::new().u32(from.len() as u32).bytes(from.as_bytes()).bytes(to.as_bytes()).buf;
+        self.expect_ok(OP_RENAME, &payload).map(|_| ())
+    }
+
+    /// Copy `from` to `to` on the remote side, replacing `to`
+    pub fn copy(&mut self, from: &str, to: &str) -> io::Result<()> {
+        let payload = Encoder::new().u32(from.len() as u32).bytes(from.as_bytes()).bytes(to.as_bytes()).buf;
+        self.expect_ok(OP_COPY, &payload).map(|_| ())
+    }
+
+    /// Apply mode and times from `st` to `path`, and ownership too if `chown`
+    pub fn set_stat(&mut self, path: &str, st: &RemoteStat, chown: bool) -> io::Result<()> {
+        let payload = Encoder::new()
+            .u8(chown as u8)
+            .u32(path.len() as u32)
+            .bytes(path.as_bytes())
+            .bytes(&encode_stat(st))
+            .buf;
+        self.expect_ok(OP_SET_STAT, &payload).map(|_| ())
+    }
+
+    pub fn set_len(&mut self, len: u64) -> io::Result<()> {
+        self.expect_ok(OP_SET_LEN, &Encoder::new().u64(len).buf).map(|_| ())
+    }
+
+    /// Reserve disk blocks for the first `len` bytes of the open file
+    pub fn allocate(&mut self, len: u64) -> io::Result<()> {
+        self.expect_ok(OP_ALLOCATE, &Encoder::new().u64(len).buf).map(|_| ())
+    }
+
+    /// Flush the open file's data and metadata to stable storage
+    pub fn fsync(&mut self) -> io::Result<()> {
+        self.expect_ok(OP_FSYNC, &[]).map(|_| ())
+    }
+
+    /// Bytes available to us on the filesystem holding `path`
+    pub fn free_space(&mut self, path: &str) -> io::Result<u64> {
+        let body = self.expect_ok(OP_STATVFS, path.as_bytes())?;
+        Decoder::new(&body).u64()
+    }
+
+    /// BLAKE3 digest of `start..end` of the open file
+    pub fn hash(&mut self, start: u64, end: u64) -> io::Result<[u8; 32]> {
+        let body = self.expect_ok(OP_HASH, &Encoder::new().u64(start).u64(end).buf)?;
+        body.as_slice()
+            .try_into()
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Malformed hash from helper"))
+    }
+
+    /// BLAKE3 digests of each `block_size` block of `start..end` of the open file
+    pub fn block_hashes(&mut self, start: u64, end: u64, block_size: u64) -> io::Result<Vec<[u8; 32]>> {
+        let mut hashes = Vec::new();
+        let mut offset = start;
+        while offset < end {
+            let chunk_end = std::cmp::min(end, offset + block_size * MAX_HASH_BLOCKS);
+            let body = self.expect_ok(OP_BLOCK_HASHES, &Encoder::new().u64(offset).u64(chunk_end).u64(block_size).buf)?;
+            if body.len() as u64 != (chunk_end - offset).div_ceil(block_size) * 32 {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "Malformed block hashes from helper"));
+            }
+            hashes.extend(body.chunks_exact(32).map(|h| <[u8; 32]>::try_from(h).unwrap()));
+            offset = chunk_end;
+        }
+        Ok(hashes)
+    }
+
+    /// Have the helper open a raw TCP listener for data connections,
+    /// returning its port and the shared key
+    pub fn listen(&mut self, encrypt: bool) -> io::Result<(u16, [u8; 32])> {
+        let body = self.expect_ok(OP_LISTEN, &[encrypt as u8])?;
+        let mut d = Decoder::new(&body);
+        let port = d.u32()? as u16;
+        let key = d.bytes(32)?.try_into().unwrap();
+        Ok((port, key))
+    }
+
+    /// Have the helper open a QUIC endpoint, returning its port, the token
+    /// streams must present and the endpoint's self-signed certificate
+    #[cfg(feature = "quic")]
+    pub fn quic_listen(&mut self, congestion: u8) -> io::Result<(u16, [u8; 32], Vec<u8>)> {
+        let body = self.expect_ok(OP_QUIC_LISTEN, &[congestion])?;
+        let mut d = Decoder::new(&body);
+        let port = d.u32()? as u16;
+        let token = d.bytes(32)?.try_into().unwrap();
+        Ok((port, token, d.rest().to_vec()))
+    }
+
+    /// Stream `start..end` of the open file back as DATA frames
+    pub fn into_reader(mut self, start: u64, end: u64) -> io::Result<RangeReader> {
+        write_frame(&mut self.stream, OP_READ, &Encoder::new().u64(start).u64(end).buf)?;
+        self.stream.flush()?;
+        Ok(RangeReader { client: self, pending: Vec::new(), pos: 0, hole: 0, done: false })
+    }
+
+    /// Stream DATA frames into the open file starting at `offset`
+    pub fn into_writer(mut self, offset: u64) -> io::Result<RangeWriter> {
+        write_frame(&mut self.stream, OP_WRITE, &Encoder::new().u64(offset).buf)?;
+        Ok(RangeWriter { client: self })
+    }
+}
+
+/// What the next piece of a READ response is
+pub enum Chunk {
+    /// This many bytes were copied into the buffer; 0 means the range is done
+    Data(usize),
+    /// This many bytes are a hole in the source and read as zeros
+    Hole(u64),
+}
+
+/// Reader over a READ response; yields EOF once the helper sends END
+pub struct RangeReader {
+    client: HelperClient,
+    pending: Vec<u8>,
+    pos: usize,
+    hole: u64,
+    done: bool,
+}
+
+impl RangeReader {
+    /// Hand the connection back once the whole range has been consumed
+    pub fn finish(mut self) -> io::Result<HelperClient> {
+        let mut sink = [0u8; 64 * 1024];
+        while self.read(&mut sink)? > 0 {}
+        Ok(self.client)
+    }
+}
+
+impl RangeReader {
+    /// Like `read`, but reports holes (at most `buf.len()` at a time) instead
+    /// of filling the buffer with zeros
+    pub fn read_chunk(&mut self, buf: &mut [u8]) -> io::Result<Chunk> {
+        while self.pos == self.pending.len() && self.hole == 0 {
+            if self.done {
+                return Ok(Chunk::Data(0));
+            }
+            match self.client.response()? {
+                (OP_DATA, data) => {
+                    self.pending = data;
+                    self.pos = 0;
+                },
+                (OP_HOLE, body) => self.hole = Decoder::new(&body).u64()?,
+                (OP_END, _) => self.done = true,
+                (op, body) => return Err(helper_error(op, &body)),
+            }
+        }
+
+        if self.pos == self.pending.len() {
+            let n = std::cmp::min(buf.len() as u64, self.hole);
+            self.hole -= n;
+            return Ok(Chunk::Hole(n));
+        }
+        let n = std::cmp::min(buf.len(), self.pending.len() - self.pos);
+        buf[..n].copy_from_slice(&self.pending[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(Chunk::Data(n))
+    }
+}
+
+impl Read for RangeReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.read_chunk(buf)? {
+            Chunk::Data(n) => Ok(n),
+            Chunk::Hole(n) => {
+                buf[..n as usize].fill(0);
+                Ok(n as usize)
+            },
+        }
+    }
+}
+
+/// Writer for a WRITE request; nothing is known to be on disk until `finish`
+pub struct RangeWriter {
+    client: HelperClient,
+}
+
+impl RangeWriter {
+    /// Move past `len` bytes that should stay a hole at the destination
+    pub fn skip(&mut self, len: u64) -> io::Result<()> {
+        write_frame(&mut self.client.stream, OP_HOLE, &Encoder::new().u64(len).buf)
+    }
+
+    /// Send END and wait for the helper to confirm every byte was written
+    pub fn finish(mut self) -> io::Result<HelperClient> {
+        match self.client.request(OP_END, &[])? {
+            (OP_OK, _) => Ok(self.client),
+            (op, body) => Err(helper_error(op, &body)),
+        }
+    }
+}
+
+impl Write for RangeWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = std::cmp::min(buf.len(), MAX_FRAME_LEN);
+        write_frame(&mut self.client.stream, OP_DATA, &buf[..n])?;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.client.stream.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use std::sync::{Arc, Mutex};
+
+    /// Write half that the test can still inspect after the client owns it
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn frames(list: &[(u8, &[u8])]) -> Vec<u8> {
+        let mut buf = Vec::new();
+        for (op, payload) in list {
+            write_frame(&mut buf, *op, payload).unwrap();
+        }
+        buf
+    }
+
+    fn hello_ok() -> Vec<u8> {
+        Encoder::new().bytes(MAGIC).u32(PROTOCOL_VERSION).buf
+    }
+
+    /// A client whose replies are scripted and whose requests land in `sent`
+    fn scripted(replies: &[(u8, &[u8])]) -> (HelperClient, Shared) {
+        let hello = hello_ok();
+        let mut script = vec![(OP_OK, hello.as_slice())];
+        script.extend_from_slice(replies);
+        let sent = Shared::default();
+        let stream = Split { reader: Cursor::new(frames(&script)), writer: sent.clone() };
+        (HelperClient::new(Box::new(stream)).unwrap(), sent)
+    }
+
+    fn read_all_frames(buf: &[u8]) -> Vec<(u8, Vec<u8>)> {
+        let mut cursor = Cursor::new(buf);
+        let mut out = Vec::new();
+        while let Some(frame) = read_frame(&mut cursor).unwrap() {
+            out.push(frame);
+        }
+        out
+    }
+
+    #[test]
+    fn frame_round_trip() {
+        let buf = frames(&[(OP_DATA, b"hello"), (OP_END, b"")]);
+        assert_eq!(read_all_frames(&buf), vec![(OP_DATA, b"hello".to_vec()), (OP_END, Vec::new())]);
+    }
+
+    #[test]
+    fn truncated_frames_are_errors() {
+        let buf = frames(&[(OP_DATA, b"hello")]);
+        let err = read_frame(&mut Cursor::new(&buf[..3])).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        let err = read_frame(&mut Cursor::new(&buf[..7])).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn oversized_frames_are_rejected() {
+        let mut buf = vec![OP_DATA];
+        buf.extend_from_slice(&(MAX_FRAME_LEN as u32 + 1).to_be_bytes());
+        let err = read_frame(&mut Cursor::new(buf)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn stat_round_trip() {
+        let st = RemoteStat { size: 1 << 40, mode: 0o100644, mtime: 1_700_000_000, atime: 1_700_000_001, uid: 1000, gid: 100, is_dir: false };
+        let back = decode_stat(&encode_stat(&st)).unwrap();
+        assert_eq!((back.size, back.mode, back.mtime, back.atime, back.uid, back.gid, back.is_dir), (st.size, st.mode, st.mtime, st.atime, st.uid, st.gid, st.is_dir));
+        assert_eq!(decode_stat(&encode_stat(&st)[..10]).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn hello_rejects_other_versions() {
+        let reply = Encoder::new().bytes(MAGIC).u32(PROTOCOL_VERSION + 1).buf;
+        let stream = Split { reader: Cursor::new(frames(&[(OP_OK, &reply)])), writer: Vec::new() };
+        let err = HelperClient::new(Box::new(stream)).err().unwrap();
+        assert!(err.to_string().contains("protocol"), "{}", err);
+    }
+
+    #[test]
+    fn range_reader_reports_holes() {
+        let hole = Encoder::new().u64(5).buf;
+        let (client, _) = scripted(&[(OP_DATA, b"abc"), (OP_HOLE, &hole), (OP_DATA, b"de"), (OP_END, b"")]);
+        let mut reader = client.into_reader(0, 10).unwrap();
+        let mut buf = [0u8; 4];
+
+        assert!(matches!(reader.read_chunk(&mut buf).unwrap(), Chunk::Data(3)));
+        assert_eq!(&buf[..3], b"abc");
+        assert!(matches!(reader.read_chunk(&mut buf).unwrap(), Chunk::Hole(4)));
+        assert!(matches!(reader.read_chunk(&mut buf).unwrap(), Chunk::Hole(1)));
+        assert!(matches!(reader.read_chunk(&mut buf).unwrap(), Chunk::Data(2)));
+        assert!(matches!(reader.read_chunk(&mut buf).unwrap(), Chunk::Data(0)));
+    }
+
+    #[test]
+    fn range_reader_fills_holes_with_zeros() {
+        let hole = Encoder::new().u64(3).buf;
+        let (client, _) = scripted(&[(OP_DATA, b"ab"), (OP_HOLE, &hole), (OP_DATA, b"c"), (OP_END, b"")]);
+        let mut data = Vec::new();
+        client.into_reader(0, 6).unwrap().read_to_end(&mut data).unwrap();
+        assert_eq!(data, b"ab\0\0\0c");
+    }
+
+    #[test]
+    fn range_reader_surfaces_error_replies() {
+        let (client, _) = scripted(&[(OP_DATA, b"ab"), (OP_ERROR, b"Input/output error")]);
+        let mut data = Vec::new();
+        let err = client.into_reader(0, 10).unwrap().read_to_end(&mut data).unwrap_err();
+        assert!(err.to_string().contains("Input/output error"), "{}", err);
+    }
+
+    #[test]
+    fn range_writer_sends_data_holes_and_end() {
+        let (client, sent) = scripted(&[(OP_OK, b"")]);
+        let mut writer = client.into_writer(100).unwrap();
+        writer.write_all(b"xy").unwrap();
+        writer.skip(7).unwrap();
+        writer.write_all(b"z").unwrap();
+        writer.finish().unwrap();
+
+        let sent = read_all_frames(&sent.0.lock().unwrap());
+        assert_eq!(sent[0].0, OP_HELLO);
+        assert_eq!(sent[1], (OP_WRITE, 100u64.to_be_bytes().to_vec()));
+        assert_eq!(sent[2], (OP_DATA, b"xy".to_vec()));
+        assert_eq!(sent[3], (OP_HOLE, 7u64.to_be_bytes().to_vec()));
+        assert_eq!(sent[4], (OP_DATA, b"z".to_vec()));
+        assert_eq!(sent[5], (OP_END, Vec::new()));
+    }
+
+    #[test]
+    fn range_writer_surfaces_error_replies() {
+        let (client, _) = scripted(&[(OP_ERROR, b"No space left on device")]);
+        let mut writer = client.into_writer(0).unwrap();
+        writer.write_all(b"data").unwrap();
+        let err = writer.finish().err().unwrap();
+        assert!(err.to_string().contains("No space left"), "{}", err);
+    }
+}
@@ -0,0 +1,277 @@
+use std::fmt;
+use std::io::{self, BufWriter, Read, Write};
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread;
+use std::time::Duration;
+use quinn::congestion::{BbrConfig, CubicConfig, NewRenoConfig};
+use quinn::crypto::rustls::{QuicClientConfig, QuicServerConfig};
+use quinn::{Connection, Endpoint, RecvStream, SendStream, TransportConfig, VarInt};
+use rand::RngCore;
+use rand::rngs::OsRng;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+use tokio::runtime::{Handle, Runtime};
+use crate::protocol::{self, HelperClient, Split};
+use crate::server;
+use crate::transport::Congestion;
+
+const SERVER_NAME: &str = "zap";
+const ALPN: &[u8] = b"zap/1";
+const MAX_STREAMS: u32 = 4096;
+const STREAM_WINDOW: u32 = 64 * 1024 * 1024;
+const CONNECTION_WINDOW: u64 = 1024 * 1024 * 1024;
+const WRITE_BUFFER_SIZE: usize = 256 * 1024;
+const KEEP_ALIVE_SECS: u64 = 5;
+const IDLE_TIMEOUT_SECS: u32 = 60;
+
+/// One QUIC connection to the remote helper; every stream opens its own
+/// bidirectional QUIC stream on it
+#[derive(Clone)]
+pub struct QuicChannel {
+    pub addr: SocketAddr,
+    endpoint: Endpoint,
+    connection: Arc<Mutex<Connection>>,
+    token: [u8; 32],
+}
+
+impl fmt::Debug for QuicChannel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("QuicChannel")
+            .field("addr", &self.addr)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Quinn is async; zap's workers are plain threads that block on this runtime
+fn runtime() -> &'static Runtime {
+    static RUNTIME: OnceLock<Runtime> = OnceLock::new();
+    RUNTIME.get_or_init(|| {
+        tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .expect("Failed to start QUIC runtime")
+    })
+}
+
+fn crypto_provider() -> Arc<rustls::crypto::CryptoProvider> {
+    Arc::new(rustls::crypto::ring::default_provider())
+}
+
+/// Windows sized for long fat pipes, with the requested congestion controller
+fn transport_config(congestion: Congestion) -> TransportConfig {
+    let mut config = TransportConfig::default();
+    config
+        .max_concurrent_bidi_streams(VarInt::from_u32(MAX_STREAMS))
+        .stream_receive_window(VarInt::from_u32(STREAM_WINDOW))
+        .receive_window(VarInt::from_u64(CONNECTION_WINDOW).unwrap())
+        .send_window(CONNECTION_WINDOW)
+        .keep_alive_interval(Some(Duration::from_secs(KEEP_ALIVE_SECS)))
+        .max_idle_timeout(Some(VarInt::from_u32(IDLE_TIMEOUT_SECS * 1000).into()));
+    match congestion {
+        Congestion::Cubic => config.congestion_controller_factory(Arc::new(CubicConfig::default())),
+        Congestion::NewReno => config.congestion_controller_factory(Arc::new(NewRenoConfig::default())),
+        Congestion::Bbr => config.congestion_controller_factory(Arc::new(BbrConfig::default())),
+    };
+    config
+}
+
+impl QuicChannel {
+    /// Ask the helper on `control` to open a QUIC endpoint and connect to it,
+    /// trusting only the certificate it handed back over SSH
+    pub fn negotiate(control: &mut HelperClient, host: &str, congestion: Congestion) -> io::Result<Self> {
+        let (port, token, cert) = control.quic_listen(congestion.to_u8())?;
+
+        let addr = (host, port)
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("Could not resolve hostname: {}", host)))?;
+        QuicChannel::dial(addr, token, cert, congestion)
+    }
+
+    /// Connect to the endpoint a helper opened at `addr`, trusting only `cert`
+    fn dial(addr: SocketAddr, token: [u8; 32], cert: Vec<u8>, congestion: Congestion) -> io::Result<Self> {
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(CertificateDer::from(cert)).map_err(io::Error::other)?;
+        let mut tls = rustls::ClientConfig::builder_with_provider(crypto_provider())
+            .with_protocol_versions(&[&rustls::version::TLS13])
+            .map_err(io::Error::other)?
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        tls.alpn_protocols = vec![ALPN.to_vec()];
+
+        let mut client_config = quinn::ClientConfig::new(Arc::new(
+            QuicClientConfig::try_from(tls).map_err(io::Error::other)?,
+        ));
+        client_config.transport_config(Arc::new(transport_config(congestion)));
+
+        let rt = runtime();
+        let _guard = rt.enter();
+        let bind: SocketAddr = if addr.is_ipv6() { "[::]:0" } else { "0.0.0.0:0" }.parse().unwrap();
+        let mut endpoint = Endpoint::client(bind)?;
+        endpoint.set_default_client_config(client_config);
+        let connection = rt.block_on(dial(&endpoint, addr))?;
+
+        Ok(QuicChannel { addr, endpoint, connection: Arc::new(Mutex::new(connection)), token })
+    }
+
+    /// Open a new QUIC stream and start a helper conversation on it,
+    /// redialling first if the connection has gone away
+    pub fn connect(&self) -> io::Result<HelperClient> {
+        let rt = runtime();
+        let connection = {
+            let mut connection = self.connection.lock().unwrap();
+            if connection.close_reason().is_some() {
+                *connection = rt.block_on(dial(&self.endpoint, self.addr))?;
+            }
+            connection.clone()
+        };
+
+        let (send, recv) = rt.block_on(connection.open_bi()).map_err(io::Error::other)?;
+        let handle = rt.handle().clone();
+        let mut writer = BufWriter::with_capacity(WRITE_BUFFER_SIZE, QuicWriter { handle: handle.clone(), send });
+        writer.write_all(&self.token)?;
+
+        HelperClient::new(Box::new(Split { reader: QuicReader { handle, recv }, writer }))
+    }
+}
+
+async fn dial(endpoint: &Endpoint, addr: SocketAddr) -> io::Result<Connection> {
+    endpoint
+        .connect(addr, SERVER_NAME)
+        .map_err(io::Error::other)?
+        .await
+        .map_err(io::Error::other)
+}
+
+/// Server side of `QUIC_LISTEN`: a fresh self-signed endpoint whose streams
+/// must open with `token` before anything is served
+pub fn listen(congestion: Congestion) -> io::Result<(u16, [u8; 32], Vec<u8>)> {
+    let certified = rcgen::generate_simple_self_signed(vec![SERVER_NAME.to_string()]).map_err(io::Error::other)?;
+    let cert = certified.cert.der().to_vec();
+    let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(certified.signing_key.serialize_der()));
+
+    let mut tls = rustls::ServerConfig::builder_with_provider(crypto_provider())
+        .with_protocol_versions(&[&rustls::version::TLS13])
+        .map_err(io::Error::other)?
+        .with_no_client_auth()
+        .with_single_cert(vec![CertificateDer::from(cert.clone())], key)
+        .map_err(io::Error::other)?;
+    tls.alpn_protocols = vec![ALPN.to_vec()];
+
+    let mut server_config = quinn::ServerConfig::with_crypto(Arc::new(
+        QuicServerConfig::try_from(tls).map_err(io::Error::other)?,
+    ));
+    server_config.transport_config(Arc::new(transport_config(congestion)));
+
+    let rt = runtime();
+    let _guard = rt.enter();
+    let endpoint = Endpoint::server(server_config, SocketAddr::new(server::ssh_local_ip(), 0))?;
+    let port = endpoint.local_addr()?.port();
+
+    let mut token = [0u8; 32];
+    OsRng.fill_bytes(&mut token);
+
+    rt.spawn(async move {
+        while let Some(incoming) = endpoint.accept().await {
+            tokio::spawn(async move {
+                if let Ok(connection) = incoming.await {
+                    while let Ok((send, recv)) = connection.accept_bi().await {
+                        thread::spawn(move || {
+                            if let Err(e) = serve_stream(send, recv, &token) {
+                                eprintln!("zap server: quic stream: {}", e);
+                            }
+                        });
+                    }
+                }
+            });
+        }
+    });
+
+    Ok((port, token, cert))
+}
+
+fn serve_stream(send: SendStream, recv: RecvStream, token: &[u8; 32]) -> io::Result<()> {
+    let handle = runtime().handle().clone();
+    let mut reader = QuicReader { handle: handle.clone(), recv };
+
+    let mut presented = [0u8; 32];
+    reader.read_exact(&mut presented)?;
+    if !protocol::constant_time_eq(&presented, token) {
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied, "QUIC stream presented the wrong token"));
+    }
+
+    server::serve(reader, BufWriter::with_capacity(WRITE_BUFFER_SIZE, QuicWriter { handle, send }))
+}
+
+/// Blocking view of a QUIC receive stream
+struct QuicReader {
+    handle: Handle,
+    recv: RecvStream,
+}
+
+impl Read for QuicReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.handle.block_on(self.recv.read(buf)) {
+            Ok(Some(n)) => Ok(n),
+            Ok(None) => Ok(0),
+            Err(e) => Err(io::Error::other(e)),
+        }
+    }
+}
+
+/// Blocking view of a QUIC send stream
+struct QuicWriter {
+    handle: Handle,
+    send: SendStream,
+}
+
+impl Write for QuicWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.handle.block_on(self.send.write(buf)).map_err(io::Error::other)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(all(test, feature = "quic"))]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::net::Ipv4Addr;
+    use crate::protocol::{OPEN_CREATE, OPEN_READ, OPEN_TRUNCATE, OPEN_WRITE};
+
+    fn loopback(port: u16) -> SocketAddr {
+        SocketAddr::from((Ipv4Addr::LOCALHOST, port))
+    }
+
+    #[test]
+    fn streams_round_trip_over_loopback() {
+        let (port, token, cert) = listen(Congestion::Cubic).unwrap();
+        let channel = QuicChannel::dial(loopback(port), token, cert, Congestion::Cubic).unwrap();
+        let path = std::env::temp_dir().join(format!("zap-quic-{}-roundtrip", std::process::id()));
+        let path = path.to_str().unwrap();
+
+        let mut client = channel.connect().unwrap();
+        client.open(path, OPEN_READ | OPEN_WRITE | OPEN_CREATE | OPEN_TRUNCATE).unwrap();
+        let mut writer = client.into_writer(0).unwrap();
+        writer.write_all(b"over quic").unwrap();
+        writer.finish().unwrap();
+
+        // A second stream on the same connection reads it back
+        let mut client = channel.connect().unwrap();
+        client.open(path, OPEN_READ).unwrap();
+        let mut read = Vec::new();
+        client.into_reader(0, 9).unwrap().read_to_end(&mut read).unwrap();
+        fs::remove_file(path).unwrap();
+        assert_eq!(read, b"over quic");
+    }
+
+    #[test]
+    fn streams_need_the_token() {
+        let (port, _, cert) = listen(Congestion::Bbr).unwrap();
+        let channel = QuicChannel::dial(loopback(port), [0u8; 32], cert, Congestion::Bbr).unwrap();
+        assert!(channel.connect().is_err());
+    }
+}
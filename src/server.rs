@@ -0,0 +1,411 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::net::IpAddr;
+use std::path::Path;
+use crate::protocol::{
+    self, Decoder, Encoder, read_frame, write_frame,
+    OP_HELLO, OP_STAT, OP_OPEN, OP_SET_LEN, OP_READ, OP_WRITE, OP_HASH, OP_LISTEN, OP_QUIC_LISTEN, OP_RENAME, OP_SET_STAT, OP_BLOCK_HASHES, OP_ALLOCATE, OP_STATVFS, OP_FSYNC, OP_COPY,
+    OP_OK, OP_ERROR, OP_DATA, OP_END, OP_HOLE,
+    OPEN_READ, OPEN_WRITE, OPEN_CREATE, OPEN_TRUNCATE,
+};
+use crate::ssh::{apply_local_stat, block_hashes_local, copy_local, data_extents, hash_local_range, local_file_stat, local_free_space, preallocate_local, read_at_local, write_at_local};
+use crate::tcp;
+use crate::transport::Congestion;
+
+const CHUNK_SIZE: usize = 1024 * 1024; // 1MB
+
+/// A path from a request, byte for byte: Unix file names needn't be UTF-8
+#[cfg(unix)]
+fn path(bytes: &[u8]) -> io::Result<&Path> {
+    use std::os::unix::ffi::OsStrExt;
+
+    Ok(Path::new(std::ffi::OsStr::from_bytes(bytes)))
+}
+
+#[cfg(not(unix))]
+fn path(bytes: &[u8]) -> io::Result<&Path> {
+    std::str::from_utf8(bytes)
+        .map(Path::new)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Path is not valid UTF-8"))
+}
+
+/// Entry point for `zap --server`: speak the helper protocol on stdin/stdout
+pub fn run_server() -> io::Result<()> {
+    let stdin = io::stdin();
+    let stdout = io::stdout();
+    serve(BufReader::new(stdin.lock()), BufWriter::new(stdout.lock()))
+}
+
+/// Serve requests until the client closes its end
+pub fn serve<R: Read, W: Write>(mut input: R, mut output: W) -> io::Result<()> {
+    let mut file: Option<File> = None;
+    // Data listeners live as long as the connection that opened them
+    let mut listeners: Vec<tcp::Listener> = Vec::new();
+
+    while let Some((op, payload)) = read_frame(&mut input)? {
+        let result = match op {
+            OP_HELLO => Ok(Encoder::new().bytes(protocol::MAGIC).u32(protocol::PROTOCOL_VERSION).buf),
+            OP_STAT => path(&payload).and_then(local_file_stat).map(|st| protocol::encode_stat(&st)),
+            OP_OPEN => open_file(&payload).map(|f| {
+                file = Some(f);
+                Vec::new()
+            }),
+            OP_SET_LEN => with_file(&file, |f| {
+                f.set_len(Decoder::new(&payload).u64()?)?;
+                Ok(Vec::new())
+            }),
+            OP_ALLOCATE => with_file(&file, |f| {
+                preallocate_local(f, Decoder::new(&payload).u64()?)?;
+                Ok(Vec::new())
+            }),
+            OP_FSYNC => with_file(&file, |f| {
+                f.sync_all()?;
+                Ok(Vec::new())
+            }),
+            OP_STATVFS => path(&payload).and_then(local_free_space).map(|free| Encoder::new().u64(free).buf),
+            OP_HASH => with_file(&file, |f| {
+                let mut d = Decoder::new(&payload);
+                let (start, end) = (d.u64()?, d.u64()?);
+                Ok(hash_local_range(f, start, end)?.to_vec())
+            }),
+            OP_BLOCK_HASHES => with_file(&file, |f| {
+                let mut d = Decoder::new(&payload);
+                let (start, end, block_size) = (d.u64()?, d.u64()?, d.u64()?);
+                if block_size == 0 {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput, "Block size must be positive"));
+                }
+                Ok(block_hashes_local(f, start, end, block_size)?.concat())
+            }),
+            OP_LISTEN => {
+                let encrypt = Decoder::new(&payload).u8()? != 0;
+                tcp::listen(encrypt).map(|(listener, key)| {
+                    let body = Encoder::new().u32(listener.port() as u32).bytes(&key).buf;
+                    listeners.push(listener);
+                    body
+                })
+            },
+            OP_RENAME => rename_path(&payload),
+            OP_COPY => copy_path(&payload),
+            OP_SET_STAT => set_stat(&payload),
+            OP_QUIC_LISTEN => Congestion::from_u8(Decoder::new(&payload).u8()?).and_then(quic_listen),
+            OP_READ => {
+                // Success is signalled by END after the data, not OK
+                let mut d = Decoder::new(&payload);
+                let (start, end) = (d.u64()?, d.u64()?);
+                match with_file(&file, |f| send_range(f, start, end, &mut output).map(|_| Vec::new())) {
+                    Ok(_) => write_frame(&mut output, OP_END, &[])?,
+                    Err(e) => write_frame(&mut output, OP_ERROR, e.to_string().as_bytes())?,
+                }
+                output.flush()?;
+                continue;
+            },
+            OP_WRITE => {
+                let offset = Decoder::new(&payload).u64()?;
+                receive_range(&file, offset, &mut input)
+            },
+            _ => Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Unknown request 0x{:02x}", op))),
+        };
+
+        match result {
+            Ok(body) => write_frame(&mut output, OP_OK, &body)?,
+            Err(e) => write_frame(&mut output, OP_ERROR, e.to_string().as_bytes())?,
+        }
+        output.flush()?;
+    }
+
+    Ok(())
+}
+
+#[cfg(feature = "quic")]
+fn quic_listen(congestion: Congestion) -> io::Result<Vec<u8>> {
+    let (port, token, cert) = crate::quic::listen(congestion)?;
+    Ok(Encoder::new().u32(port as u32).bytes(&token).bytes(&cert).buf)
+}
+
+#[cfg(not(feature = "quic"))]
+fn quic_listen(_congestion: Congestion) -> io::Result<Vec<u8>> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "this zap was built without QUIC support"))
+}
+
+/// The address the SSH client reached us on, so data listeners are not
+/// exposed on every interface
+pub fn ssh_local_ip() -> IpAddr {
+    std::env::var("SSH_CONNECTION")
+        .ok()
+        .and_then(|v| v.split_whitespace().nth(2).and_then(|ip| ip.parse::<IpAddr>().ok()))
+        .unwrap_or(IpAddr::from([0, 0, 0, 0]))
+}
+
+fn with_file<F>(file: &Option<File>, f: F) -> io::Result<Vec<u8>>
+where
+    F: FnOnce(&File) -> io::Result<Vec<u8>>,
+{
+    match file {
+        Some(file) => f(file),
+        None => Err(io::Error::new(io::ErrorKind::InvalidInput, "No file is open")),
+    }
+}
+
+fn open_file(payload: &[u8]) -> io::Result<File> {
+    let mut d = Decoder::new(payload);
+    let flags = d.u8()?;
+    let path = path(d.rest())?;
+
+    OpenOptions::new()
+        .read(flags & OPEN_READ != 0)
+        .write(flags & OPEN_WRITE != 0)
+        .create(flags & OPEN_CREATE != 0)
+        .truncate(flags & OPEN_TRUNCATE != 0)
+        .open(path)
+}
+
+fn rename_path(payload: &[u8]) -> io::Result<Vec<u8>> {
+    let mut d = Decoder::new(payload);
+    let len = d.u32()? as usize;
+    let from = path(d.bytes(len)?)?;
+    fs::rename(from, path(d.rest())?)?;
+    Ok(Vec::new())
+}
+
+/// A clone where the filesystem can make one, so --delta doesn't
+/// duplicate the data
+fn copy_path(payload: &[u8]) -> io::Result<Vec<u8>> {
+    let mut d = Decoder::new(payload);
+    let len = d.u32()? as usize;
+    let from = path(d.bytes(len)?)?;
+    copy_local(from, path(d.rest())?)?;
+    Ok(Vec::new())
+}
+
+fn set_stat(payload: &[u8]) -> io::Result<Vec<u8>> {
+    let mut d = Decoder::new(payload);
+    let chown = d.u8()? != 0;
+    let len = d.u32()? as usize;
+    let path = path(d.bytes(len)?)?;
+    apply_local_stat(path, &protocol::decode_stat(d.rest())?, chown)?;
+    Ok(Vec::new())
+}
+
+/// Send the data extents of `start..end` as DATA frames and the holes
+/// between them as HOLE frames
+fn send_range<W: Write>(file: &File, start: u64, end: u64, output: &mut W) -> io::Result<()> {
+    if file.metadata()?.len() < end {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "File is shorter than requested range"));
+    }
+    let mut buffer = vec![0u8; CHUNK_SIZE];
+    let mut offset = start;
+
+    for (data_start, data_end) in data_extents(file, start, end)? {
+        if data_start > offset {
+            write_frame(output, OP_HOLE, &Encoder::new().u64(data_start - offset).buf)?;
+            offset = data_start;
+        }
+        while offset < data_end {
+            let to_read = std::cmp::min(CHUNK_SIZE as u64, data_end - offset) as usize;
+            let n = read_at_local(file, &mut buffer[..to_read], offset)?;
+            if n == 0 {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "File is shorter than requested range"));
+            }
+            write_frame(output, OP_DATA, &buffer[..n])?;
+            offset += n as u64;
+        }
+    }
+    if end > offset {
+        write_frame(output, OP_HOLE, &Encoder::new().u64(end - offset).buf)?;
+    }
+
+    Ok(())
+}
+
+/// Consume DATA frames until END. Write errors are remembered rather than
+/// returned immediately so the stream stays in sync with the client.
+fn receive_range<R: Read>(file: &Option<File>, offset: u64, input: &mut R) -> io::Result<Vec<u8>> {
+    let mut offset = offset;
+    let mut failure: Option<io::Error> = None;
+
+    loop {
+        match read_frame(input)? {
+            Some((OP_DATA, data)) => {
+                if failure.is_some() {
+                    continue;
+                }
+                let written = with_file(file, |f| {
+                    let mut done = 0;
+                    while done < data.len() {
+                        done += write_at_local(f, &data[done..], offset + done as u64)?;
+                    }
+                    Ok(Vec::new())
+                });
+                match written {
+                    Ok(_) => offset += data.len() as u64,
+                    Err(e) => failure = Some(e),
+                }
+            },
+            // The file was extended sparse, so a hole is just a jump ahead
+            Some((OP_HOLE, len)) => offset += Decoder::new(&len).u64()?,
+            Some((OP_END, _)) => break,
+            Some((op, _)) => {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Unexpected frame 0x{:02x} during write", op)))
+            },
+            None => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Client went away mid-write")),
+        }
+    }
+
+    match failure {
+        Some(e) => Err(e),
+        None => Ok(Vec::new()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use std::path::PathBuf;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("zap-server-{}-{}", std::process::id(), name))
+    }
+
+    /// Feed `requests` to the server and collect every frame it sends back
+    fn converse(requests: &[(u8, Vec<u8>)]) -> Vec<(u8, Vec<u8>)> {
+        let mut input = Vec::new();
+        for (op, payload) in requests {
+            write_frame(&mut input, *op, payload).unwrap();
+        }
+        let mut output = Vec::new();
+        serve(Cursor::new(input), &mut output).unwrap();
+
+        let mut cursor = Cursor::new(output);
+        let mut replies = Vec::new();
+        while let Some(frame) = read_frame(&mut cursor).unwrap() {
+            replies.push(frame);
+        }
+        replies
+    }
+
+    fn open(path: &Path, flags: u8) -> (u8, Vec<u8>) {
+        (OP_OPEN, Encoder::new().u8(flags).bytes(path.to_str().unwrap().as_bytes()).buf)
+    }
+
+    #[test]
+    fn write_with_a_hole_then_read_back() {
+        let path = temp_path("roundtrip");
+        let replies = converse(&[
+            (OP_HELLO, Encoder::new().bytes(protocol::MAGIC).u32(protocol::PROTOCOL_VERSION).buf),
+            open(&path, OPEN_READ | OPEN_WRITE | OPEN_CREATE | OPEN_TRUNCATE),
+            (OP_SET_LEN, Encoder::new().u64(12).buf),
+            (OP_WRITE, Encoder::new().u64(0).buf),
+            (OP_DATA, b"hello".to_vec()),
+            (OP_HOLE, Encoder::new().u64(2).buf),
+            (OP_DATA, b"world".to_vec()),
+            (OP_END, Vec::new()),
+            (OP_READ, Encoder::new().u64(0).u64(12).buf),
+            (OP_HASH, Encoder::new().u64(0).u64(12).buf),
+        ]);
+        let contents = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(contents, b"hello\0\0world");
+
+        let ops: Vec<u8> = replies.iter().map(|(op, _)| *op).collect();
+        assert_eq!(&ops[..4], &[OP_OK; 4]);
+        assert_eq!(ops[ops.len() - 2], OP_END);
+        assert_eq!(replies.last().unwrap(), &(OP_OK, blake3::hash(b"hello\0\0world").as_bytes().to_vec()));
+
+        // The READ reply is data and hole frames adding up to the file
+        let mut read = Vec::new();
+        for (op, body) in &replies[4..replies.len() - 2] {
+            match *op {
+                OP_DATA => read.extend_from_slice(body),
+                OP_HOLE => read.resize(read.len() + Decoder::new(body).u64().unwrap() as usize, 0),
+                op => panic!("unexpected frame 0x{:02x}", op),
+            }
+        }
+        assert_eq!(read, b"hello\0\0world");
+    }
+
+    #[test]
+    fn errors_are_replied_and_the_session_continues() {
+        let missing = temp_path("missing");
+        let replies = converse(&[
+            (OP_READ, Encoder::new().u64(0).u64(4).buf),
+            (OP_STAT, missing.to_str().unwrap().as_bytes().to_vec()),
+            (0x7f, Vec::new()),
+            (OP_HELLO, Vec::new()),
+        ]);
+
+        assert_eq!(replies.len(), 4);
+        assert_eq!(replies[0], (OP_ERROR, b"No file is open".to_vec()));
+        assert_eq!(replies[1].0, OP_ERROR);
+        assert_eq!(replies[2], (OP_ERROR, b"Unknown request 0x7f".to_vec()));
+        assert_eq!(replies[3].0, OP_OK);
+    }
+
+    #[test]
+    fn non_utf8_names_are_kept() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let dir = temp_path("latin1");
+        fs::create_dir_all(&dir).unwrap();
+        let mut name = dir.as_os_str().as_bytes().to_vec();
+        name.extend_from_slice(b"/caf\xe9.bin");
+        let replies = converse(&[
+            (OP_OPEN, Encoder::new().u8(OPEN_WRITE | OPEN_CREATE).bytes(&name).buf),
+            (OP_STAT, name.clone()),
+        ]);
+        let entries: Vec<_> = fs::read_dir(&dir).unwrap().map(|e| e.unwrap().file_name()).collect();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(replies[0], (OP_OK, Vec::new()));
+        assert_eq!(replies[1].0, OP_OK);
+        assert_eq!(entries, vec![std::ffi::OsStr::from_bytes(b"caf\xe9.bin").to_os_string()]);
+    }
+
+    #[test]
+    fn copy_leaves_the_source_in_place() {
+        let (from, to) = (temp_path("copy-from"), temp_path("copy-to"));
+        fs::write(&from, b"original").unwrap();
+        fs::write(&to, b"stale contents").unwrap();
+        let (from_str, to_str) = (from.to_str().unwrap(), to.to_str().unwrap());
+        let payload = Encoder::new().u32(from_str.len() as u32).bytes(from_str.as_bytes()).bytes(to_str.as_bytes()).buf;
+        let replies = converse(&[(OP_COPY, payload)]);
+        let (kept, copied) = (fs::read(&from).unwrap(), fs::read(&to).unwrap());
+        fs::remove_file(&from).unwrap();
+        fs::remove_file(&to).unwrap();
+
+        assert_eq!(replies, vec![(OP_OK, Vec::new())]);
+        assert_eq!(kept, b"original");
+        assert_eq!(copied, b"original");
+    }
+
+    #[test]
+    fn reading_past_the_end_is_an_error() {
+        let path = temp_path("short");
+        fs::write(&path, b"abc").unwrap();
+        let replies = converse(&[open(&path, OPEN_READ), (OP_READ, Encoder::new().u64(0).u64(10).buf)]);
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(replies[0].0, OP_OK);
+        assert_eq!(replies[1].0, OP_ERROR);
+    }
+
+    #[test]
+    fn failed_writes_drain_the_stream() {
+        // The file is read-only, so the write fails, but the frames after it
+        // must still be consumed and answered in order
+        let path = temp_path("readonly");
+        fs::write(&path, b"abc").unwrap();
+        let replies = converse(&[
+            open(&path, OPEN_READ),
+            (OP_WRITE, Encoder::new().u64(0).buf),
+            (OP_DATA, b"xyz".to_vec()),
+            (OP_END, Vec::new()),
+            (OP_HASH, Encoder::new().u64(0).u64(3).buf),
+        ]);
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(replies.len(), 3);
+        assert_eq!(replies[1].0, OP_ERROR);
+        assert_eq!(replies[2], (OP_OK, blake3::hash(b"abc").as_bytes().to_vec()));
+    }
+}
@@ -2,16 +2,45 @@ use std::fs::File;
 use std::io::{self, Read, Write, Seek, SeekFrom};
 use std::net::{TcpStream, ToSocketAddrs};
 use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, SyncSender};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use std::env;
-use ssh2::{Session, Sftp, OpenFlags, OpenType};
-use indicatif::ProgressBar;
+use ssh2::{ExtendedData, FileStat, MethodType, PtyModeOpcode, PtyModes, Session, Sftp, OpenFlags, OpenType};
+use crate::cancel::CancelToken;
+use crate::error::ZapError;
+use crate::progress::{Phase, StreamProgress};
+use crate::protocol::{Chunk, HelperClient, RemoteStat, RangeReader, RangeWriter, OPEN_CREATE, OPEN_READ, OPEN_TRUNCATE, OPEN_WRITE};
+use crate::transport::DataChannel;
+use crate::utils::Messages;
 
-const BUFFER_SIZE: usize = 1 * 1024 * 1024; // 1MB
+const BUFFER_SIZE: usize = 1024 * 1024; // 1MB
 const CONNECTION_TIMEOUT_SECS: u64 = 30;
 const BASE_RETRY_DELAY_MS: u64 = 1000;
 const MAX_RETRY_DELAY_MS: u64 = 30000;
+/// Granularity of --delta comparisons
+const DELTA_BLOCK_SIZE: u64 = 1024 * 1024; // 1MB
+/// Granularity of --sparse zero detection, one filesystem block
+const ZERO_BLOCK_SIZE: usize = 4096;
+/// Stdin and stdout transfers are cut into chunks of this size; chunk `k`
+/// lives at offset `k * STREAM_CHUNK_SIZE` and is moved by stream `k % streams`
+pub const STREAM_CHUNK_SIZE: usize = 4 * 1024 * 1024; // 4MB
+
+/// Cipher order when none is given: AEAD modes first, since they skip the
+/// separate MAC pass and are markedly faster than aes-ctr with HMAC
+const DEFAULT_CIPHERS: &[&str] = &[
+    "aes128-gcm@openssh.com",
+    "aes256-gcm@openssh.com",
+    "chacha20-poly1305@openssh.com",
+    "aes128-ctr",
+    "aes256-ctr",
+];
+const DEFAULT_MACS: &[&str] = &[
+    "hmac-sha2-256-etm@openssh.com",
+    "hmac-sha2-512-etm@openssh.com",
+    "hmac-sha2-256",
+    "hmac-sha2-512",
+];
 
 #[derive(Debug, Clone)]
 pub struct SessionConfig {
@@ -20,36 +49,173 @@ pub struct SessionConfig {
     pub user: String,
     pub key_path: Option<String>,
     pub retries: u32,
+    /// Remote zap command to run as a helper; None means plain SFTP
+    pub helper: Option<String>,
+    /// TCP or QUIC listener negotiated with the helper, used instead of SSH for data
+    pub data_channel: Option<DataChannel>,
+    /// Comma-separated cipher preference; None means DEFAULT_CIPHERS
+    pub ciphers: Option<String>,
+    /// Comma-separated MAC preference; None means DEFAULT_MACS
+    pub macs: Option<String>,
+    /// Ask for zlib compression on every SSH session
+    pub compress: bool,
+    /// Where warnings about connecting go
+    pub messages: Messages,
+}
+
+/// Order `requested` (or our defaults followed by everything else libssh2
+/// supports) as the preference for one method type
+fn method_prefs(sess: &Session, method: MethodType, kind: &str, requested: Option<&str>, defaults: &[&str]) -> io::Result<String> {
+    let supported = sess.supported_algs(method).map_err(io::Error::other)?;
+
+    let prefs: Vec<&str> = match requested {
+        Some(list) => {
+            let wanted: Vec<&str> = list.split(',').map(str::trim).filter(|s| !s.is_empty()).collect();
+            if let Some(bad) = wanted.iter().find(|w| !supported.contains(w)) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Unsupported {} '{}'. Supported: {}", kind, bad, supported.join(", ")),
+                ));
+            }
+            wanted
+        },
+        None => defaults.iter()
+            .copied()
+            .filter(|d| supported.contains(d))
+            .chain(supported.iter().copied().filter(|s| !defaults.contains(s)))
+            .collect(),
+    };
+
+    Ok(prefs.join(","))
+}
+
+/// Apply cipher and MAC preferences; must happen before the handshake
+fn apply_method_prefs(sess: &Session, cfg: &SessionConfig) -> io::Result<()> {
+    let ciphers = method_prefs(sess, MethodType::CryptCs, "cipher", cfg.ciphers.as_deref(), DEFAULT_CIPHERS)?;
+    let macs = method_prefs(sess, MethodType::MacCs, "MAC", cfg.macs.as_deref(), DEFAULT_MACS)?;
+    for (method, prefs) in [
+        (MethodType::CryptCs, &ciphers),
+        (MethodType::CryptSc, &ciphers),
+        (MethodType::MacCs, &macs),
+        (MethodType::MacSc, &macs),
+    ] {
+        sess.method_pref(method, prefs).map_err(io::Error::other)?;
+    }
+    Ok(())
+}
+
+/// Check --cipher/--mac against what libssh2 supports without connecting
+pub fn validate_methods(ciphers: Option<&str>, macs: Option<&str>) -> io::Result<()> {
+    let sess = Session::new()?;
+    method_prefs(&sess, MethodType::CryptCs, "cipher", ciphers, DEFAULT_CIPHERS)?;
+    method_prefs(&sess, MethodType::MacCs, "MAC", macs, DEFAULT_MACS)?;
+    Ok(())
+}
+
+/// Algorithms agreed during the handshake, for verbose output
+pub fn negotiated_methods(sess: &Session) -> Vec<(&'static str, String)> {
+    let method = |m| sess.methods(m).unwrap_or("unknown").to_string();
+    let pair = |cs, sc| {
+        let (cs, sc) = (method(cs), method(sc));
+        if cs == sc { cs } else { format!("{} / {}", cs, sc) }
+    };
+    vec![
+        ("Kex", method(MethodType::Kex)),
+        ("Host key", method(MethodType::HostKey)),
+        ("Cipher", pair(MethodType::CryptCs, MethodType::CryptSc)),
+        ("MAC", pair(MethodType::MacCs, MethodType::MacSc)),
+        ("Compress", pair(MethodType::CompCs, MethodType::CompSc)),
+    ]
+}
+
+/// Bytes a session has actually put on and taken off its socket, read from
+/// the kernel so the effect of SSH compression can be reported
+pub struct WireMeter {
+    socket: TcpStream,
+    base: (u64, u64),
+}
+
+impl WireMeter {
+    /// None where the platform does not expose TCP byte counters
+    fn new(socket: TcpStream) -> Option<Self> {
+        let base = tcp_byte_counts(&socket)?;
+        Some(WireMeter { socket, base })
+    }
+
+    /// (sent, received) since the meter was created
+    pub fn counts(&self) -> Option<(u64, u64)> {
+        let (sent, received) = tcp_byte_counts(&self.socket)?;
+        Some((sent.saturating_sub(self.base.0), received.saturating_sub(self.base.1)))
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn tcp_byte_counts(socket: &TcpStream) -> Option<(u64, u64)> {
+    use std::os::unix::io::AsRawFd;
+
+    // SAFETY: tcp_info is plain old data and the kernel writes at most `len` bytes
+    let mut info: libc::tcp_info = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::tcp_info>() as libc::socklen_t;
+    let rc = unsafe {
+        libc::getsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_TCP,
+            libc::TCP_INFO,
+            &mut info as *mut libc::tcp_info as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    // Older kernels return a shorter struct without the byte counters
+    let needed = std::mem::offset_of!(libc::tcp_info, tcpi_bytes_received) + std::mem::size_of::<u64>();
+    if rc != 0 || (len as usize) < needed {
+        return None;
+    }
+    Some((info.tcpi_bytes_acked, info.tcpi_bytes_received))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn tcp_byte_counts(_socket: &TcpStream) -> Option<(u64, u64)> {
+    None
 }
 
 /// Connect to SSH server and authenticate
 pub fn connect_and_auth(cfg: &SessionConfig) -> io::Result<Session> {
+    connect_metered(cfg).map(|(sess, _)| sess)
+}
+
+/// Like `connect_and_auth`, plus a meter on the session's socket when
+/// compression is on, started after authentication
+pub fn connect_metered(cfg: &SessionConfig) -> io::Result<(Session, Option<WireMeter>)> {
     let addr = format!("{}:{}", cfg.host, cfg.port);
 
     // Resolve hostname to socket addresses (handles DNS and mDNS)
     let socket_addrs: Vec<_> = addr
         .to_socket_addrs()
-        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, format!("Failed to resolve host {}: {}", cfg.host, e)))?
+        .map_err(|source| ZapError::Resolve { host: cfg.host.clone(), source })?
         .collect();
 
     let socket_addr = socket_addrs.first()
-        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("Could not resolve hostname: {}", cfg.host)))?;
+        .ok_or_else(|| ZapError::Resolve { host: cfg.host.clone(), source: io::Error::new(io::ErrorKind::NotFound, "no addresses") })?;
 
     // TCP connect with timeout
-    let tcp = TcpStream::connect_timeout(socket_addr, Duration::from_secs(CONNECTION_TIMEOUT_SECS))?;
-    tcp.set_nodelay(true)?;
+    let connect_err = |source| ZapError::Connect { host: cfg.host.clone(), source };
+    let tcp = TcpStream::connect_timeout(socket_addr, Duration::from_secs(CONNECTION_TIMEOUT_SECS)).map_err(connect_err)?;
+    tcp.set_nodelay(true).map_err(connect_err)?;
+    let meter_socket = if cfg.compress { Some(tcp.try_clone()?) } else { None };
 
     // SSH handshake
     let mut sess = Session::new()?;
+    apply_method_prefs(&sess, cfg)?;
+    sess.set_compress(cfg.compress);
     sess.set_tcp_stream(tcp);
-    sess.handshake()?;
+    sess.handshake().map_err(|e| ZapError::handshake(&cfg.host, e))?;
 
     // Authentication: try key file, then default keys, then agent
 
     // 1. Try explicit key path if provided
     if let Some(ref key_path) = cfg.key_path {
         if let Err(e) = sess.userauth_pubkey_file(&cfg.user, None, Path::new(key_path), None) {
-            eprintln!("Warning: Specified key auth failed: {}. Trying defaults...", e);
+            cfg.messages.warn(&format!("Specified key auth failed: {}. Trying defaults...", e));
         }
     }
 
@@ -67,10 +233,8 @@ pub fn connect_and_auth(cfg: &SessionConfig) -> io::Result<Session> {
         };
 
         for key_path in default_keys {
-            if key_path.exists() {
-                if sess.userauth_pubkey_file(&cfg.user, None, &key_path, None).is_ok() {
-                    break;
-                }
+            if key_path.exists() && sess.userauth_pubkey_file(&cfg.user, None, &key_path, None).is_ok() {
+                break;
             }
         }
     }
@@ -81,29 +245,15 @@ pub fn connect_and_auth(cfg: &SessionConfig) -> io::Result<Session> {
     }
 
     if !sess.authenticated() {
-        return Err(io::Error::new(
-            io::ErrorKind::PermissionDenied,
-            "Failed to authenticate with SSH server. Try specifying a key with --ssh-key-path",
-        ));
+        return Err(ZapError::Auth { host: cfg.host.clone(), user: cfg.user.clone() }.into());
     }
 
-    Ok(sess)
+    Ok((sess, meter_socket.and_then(WireMeter::new)))
 }
 
 /// Open SFTP channel
 pub fn open_sftp(sess: &Session) -> io::Result<Sftp> {
-    sess.sftp().map_err(|e| io::Error::new(io::ErrorKind::Other, e))
-}
-
-/// Get remote file size via SFTP stat
-pub fn stat_remote_file(sftp: &Sftp, path: &str) -> io::Result<u64> {
-    let stat = sftp.stat(Path::new(path))
-        .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("Failed to stat remote file: {}", e)))?;
-
-    stat.size.ok_or_else(|| io::Error::new(
-        io::ErrorKind::Other,
-        "Remote file stat did not return size",
-    ))
+    sess.sftp().map_err(io::Error::other)
 }
 
 /// Extend remote file to specified size (sparse allocation)
@@ -114,7 +264,7 @@ pub fn extend_remote_file(sftp: &Sftp, path: &str, size: u64) -> io::Result<()>
         OpenFlags::CREATE | OpenFlags::WRITE | OpenFlags::TRUNCATE,
         0o644,
         OpenType::File,
-    ).map_err(|e| io::Error::new(io::ErrorKind::Other, format!("Failed to open remote file: {}", e)))?;
+    ).map_err(|source| ZapError::Sftp { op: "open remote file", path: path.to_string(), source })?;
 
     // Extend file by seeking to size-1 and writing a single byte
     // This creates a sparse file on most filesystems
@@ -126,6 +276,381 @@ pub fn extend_remote_file(sftp: &Sftp, path: &str, size: u64) -> io::Result<()>
     Ok(())
 }
 
+/// Remote file metadata, asking the helper when there is one
+pub fn remote_file_stat(sess: &Session, cfg: &SessionConfig, path: &str) -> io::Result<RemoteStat> {
+    match cfg.helper {
+        Some(ref command) => HelperClient::exec(sess, command)?.stat(path),
+        None => {
+            let stat = open_sftp(sess)?.stat(Path::new(path))
+                .map_err(|source| ZapError::Sftp { op: "stat remote file", path: path.to_string(), source })?;
+            Ok(RemoteStat {
+                size: stat.size.ok_or_else(|| io::Error::other("Remote file stat did not return size"))?,
+                mode: stat.perm.unwrap_or(0o644),
+                mtime: stat.mtime.unwrap_or(0),
+                atime: stat.atime.unwrap_or(0),
+                uid: stat.uid.unwrap_or(0),
+                gid: stat.gid.unwrap_or(0),
+                is_dir: stat.is_dir(),
+            })
+        },
+    }
+}
+
+/// Create `dir` and any missing parents over SFTP, returning what was created
+pub fn create_remote_dirs(sess: &Session, dir: &str) -> io::Result<Vec<String>> {
+    let sftp = open_sftp(sess)?;
+    let mut created = Vec::new();
+    let mut prefix = String::new();
+
+    for (i, part) in dir.split('/').enumerate() {
+        if i > 0 {
+            prefix.push('/');
+        }
+        prefix.push_str(part);
+        if part.is_empty() || part == "." {
+            continue;
+        }
+        let path = Path::new(&prefix);
+        match sftp.stat(path) {
+            Ok(st) if st.is_dir() => continue,
+            Ok(_) => return Err(io::Error::other(format!("Remote path '{}' exists and is not a directory", prefix))),
+            Err(_) => {},
+        }
+        if let Err(e) = sftp.mkdir(path, 0o755) {
+            // Another stream or process may have just made it
+            if !sftp.stat(path).map(|st| st.is_dir()).unwrap_or(false) {
+                return Err(ZapError::Sftp { op: "create remote directory", path: prefix, source: e }.into());
+            }
+            continue;
+        }
+        created.push(prefix.clone());
+    }
+
+    Ok(created)
+}
+
+/// BLAKE3 of the first `size` bytes of a remote file; None without a helper,
+/// since over plain SFTP that would mean reading the whole file back
+pub fn remote_file_hash(sess: &Session, cfg: &SessionConfig, path: &str, size: u64) -> io::Result<Option<[u8; 32]>> {
+    match cfg.helper {
+        Some(ref command) => {
+            let mut helper = HelperClient::exec(sess, command)?;
+            helper.open(path, OPEN_READ)?;
+            helper.hash(0, size).map(Some)
+        },
+        None => Ok(None),
+    }
+}
+
+/// Where a pushed file should land: inside `dest` if it is an existing
+/// directory (or written as one), otherwise at `dest` itself, like scp
+pub fn resolve_remote_destination(sess: &Session, cfg: &SessionConfig, dest: &str, file_name: &str) -> io::Result<String> {
+    if dest.ends_with('/') {
+        return Ok(format!("{}{}", dest, file_name));
+    }
+    match remote_file_stat(sess, cfg, dest) {
+        Ok(st) if st.is_dir => Ok(format!("{}/{}", dest, file_name)),
+        _ => Ok(dest.to_string()),
+    }
+}
+
+/// Apply mode and times (and ownership if `chown`) from `st` to a remote file
+pub fn set_remote_stat(sess: &Session, cfg: &SessionConfig, path: &str, st: &RemoteStat, chown: bool) -> io::Result<()> {
+    if let Some(ref command) = cfg.helper {
+        return HelperClient::exec(sess, command)?.set_stat(path, st, chown);
+    }
+    let stat = FileStat {
+        size: None,
+        uid: chown.then_some(st.uid),
+        gid: chown.then_some(st.gid),
+        perm: Some(st.mode & 0o7777),
+        atime: Some(st.atime),
+        mtime: Some(st.mtime),
+    };
+    open_sftp(sess)?.setstat(Path::new(path), stat)
+        .map_err(|source| ZapError::Sftp { op: "set attributes of remote file", path: path.to_string(), source }.into())
+}
+
+fn unix_secs(t: io::Result<SystemTime>) -> u64 {
+    t.ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Local file metadata in the same shape the helper reports
+pub fn local_file_stat(path: &Path) -> io::Result<RemoteStat> {
+    let meta = std::fs::metadata(path).map_err(ZapError::local(path))?;
+
+    #[cfg(unix)]
+    let (mode, uid, gid) = {
+        use std::os::unix::fs::MetadataExt;
+        (meta.mode(), meta.uid(), meta.gid())
+    };
+    #[cfg(not(unix))]
+    let (mode, uid, gid) = (if meta.permissions().readonly() { 0o444 } else { 0o644 }, 0, 0);
+
+    Ok(RemoteStat {
+        size: meta.len(),
+        mode,
+        mtime: unix_secs(meta.modified()),
+        atime: unix_secs(meta.accessed()),
+        uid,
+        gid,
+        is_dir: meta.is_dir(),
+    })
+}
+
+/// Apply times, ownership (if `chown`) and then mode from `st` to a local
+/// file. Mode goes last because chown clears setuid/setgid bits.
+pub fn apply_local_stat(path: &Path, st: &RemoteStat, chown: bool) -> io::Result<()> {
+    let times = std::fs::FileTimes::new()
+        .set_accessed(UNIX_EPOCH + Duration::from_secs(st.atime))
+        .set_modified(UNIX_EPOCH + Duration::from_secs(st.mtime));
+    File::open(path)?.set_times(times)?;
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        if chown {
+            std::os::unix::fs::chown(path, Some(st.uid), Some(st.gid))?;
+        }
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(st.mode & 0o7777))?;
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = chown;
+        let mut perms = std::fs::metadata(path)?.permissions();
+        perms.set_readonly(st.mode & 0o200 == 0);
+        std::fs::set_permissions(path, perms)?;
+    }
+
+    Ok(())
+}
+
+/// Ownership can only be handed to someone else by root
+pub fn running_as_root() -> bool {
+    #[cfg(unix)]
+    return unsafe { libc::geteuid() } == 0;
+    #[cfg(not(unix))]
+    return false;
+}
+
+/// Create (or truncate) the remote file and extend it to its final size.
+/// With `keep_existing`, whatever is already there is kept as a delta base.
+/// With `preallocate`, the helper reserves the space; over SFTP that is
+/// impossible, the flag is ignored and only the pre-flight free-space check
+/// protects us.
+pub fn prepare_remote_file(sess: &Session, cfg: &SessionConfig, path: &str, size: u64, keep_existing: bool, preallocate: bool) -> io::Result<()> {
+    match cfg.helper {
+        Some(ref command) => {
+            let mut helper = HelperClient::exec(sess, command)?;
+            let truncate = if keep_existing { 0 } else { OPEN_TRUNCATE };
+            helper.open(path, OPEN_WRITE | OPEN_CREATE | truncate)?;
+            helper.set_len(size)?;
+            if preallocate {
+                helper.allocate(size).map_err(|e| allocation_error(e, size, path))?;
+            }
+            Ok(())
+        },
+        None => extend_remote_file(&open_sftp(sess)?, path, size),
+    }
+}
+
+fn allocation_error(e: io::Error, size: u64, path: &str) -> io::Error {
+    io::Error::new(e.kind(), format!("Failed to preallocate {} bytes for {}: {}", size, path, e))
+}
+
+/// Bytes available on the remote filesystem that will hold `temp_path` and
+/// then `dest_path`, via the helper or the statvfs@openssh.com extension;
+/// None if neither is available
+pub fn remote_free_space(sess: &Session, cfg: &SessionConfig, temp_path: &str, dest_path: &str) -> io::Result<Option<u64>> {
+    if let Some(ref command) = cfg.helper {
+        let dir = match Path::new(temp_path).parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent.to_string_lossy().into_owned(),
+            _ => ".".to_string(),
+        };
+        return HelperClient::exec(sess, command)?.free_space(&dir).map(Some);
+    }
+    // The extension only works on an open file handle (OpenSSH refuses
+    // directory handles). Read an existing temp file or destination if there
+    // is one; otherwise create the temp file, which is about to be created
+    // anyway, and never touch the destination.
+    let sftp = open_sftp(sess)?;
+    let existing = [temp_path, dest_path].into_iter().find_map(|p| sftp.open(Path::new(p)).ok());
+    let created = existing.is_none();
+    let mut file = match existing {
+        Some(file) => file,
+        None => sftp.create(Path::new(temp_path))
+            .map_err(|source| ZapError::Sftp { op: "create remote file", path: temp_path.to_string(), source })?,
+    };
+    let free = file.statvfs().ok().map(|st| st.f_bavail * st.f_frsize);
+    drop(file);
+    if created {
+        let _ = sftp.unlink(Path::new(temp_path));
+    }
+    Ok(free)
+}
+
+/// Reserve disk blocks for the first `len` bytes of a local file
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+pub fn preallocate_local(file: &File, len: u64) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    if len == 0 {
+        return Ok(());
+    }
+    // posix_fallocate returns the error number rather than setting errno
+    let rc = unsafe { libc::posix_fallocate(file.as_raw_fd(), 0, len as libc::off_t) };
+    if rc != 0 {
+        return Err(io::Error::from_raw_os_error(rc));
+    }
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "freebsd")))]
+pub fn preallocate_local(_file: &File, _len: u64) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "Preallocation is not supported on this platform"))
+}
+
+/// Bytes available to an unprivileged user on the filesystem holding `path`
+#[cfg(unix)]
+pub fn local_free_space(path: &Path) -> io::Result<u64> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    // SAFETY: statvfs is plain old data filled in by the call
+    let mut st: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut st) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // Field widths differ between platforms
+    #[allow(clippy::unnecessary_cast)]
+    Ok(st.f_bavail as u64 * st.f_frsize as u64)
+}
+
+#[cfg(not(unix))]
+pub fn local_free_space(_path: &Path) -> io::Result<u64> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "Free space checks are not supported on this platform"))
+}
+
+/// Copy `from` to `to` for --delta to patch. Filesystems with reflinks,
+/// such as Btrfs and XFS, clone it, so nothing is read or written until
+/// blocks are patched; elsewhere it is a full copy.
+pub fn copy_local(from: &Path, to: &Path) -> io::Result<()> {
+    if !clone_local(from, to) {
+        std::fs::copy(from, to)?;
+    }
+    Ok(())
+}
+
+/// Make `to` a clone of `from` sharing its blocks, if the filesystem can
+#[cfg(target_os = "linux")]
+fn clone_local(from: &Path, to: &Path) -> bool {
+    use std::os::unix::io::AsRawFd;
+
+    let (Ok(src), Ok(dst)) = (File::open(from), File::create(to)) else {
+        return false;
+    };
+    // SAFETY: FICLONE only reads the two descriptors, which stay open
+    unsafe { libc::ioctl(dst.as_raw_fd(), libc::FICLONE as _, src.as_raw_fd()) == 0 }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn clone_local(_from: &Path, _to: &Path) -> bool {
+    false
+}
+
+/// Move a finished temp file over the destination. Without a helper this
+/// uses `posix-rename@openssh.com`, which replaces the destination atomically.
+/// Servers without it only have plain SFTP v3 rename, which refuses to
+/// replace an existing file; there an existing destination is removed first,
+/// which is not atomic: for a moment nothing is at `to`, and if the rename
+/// then fails the old file is already gone.
+pub fn rename_remote_file(sess: &Session, cfg: &SessionConfig, from: &str, to: &str) -> io::Result<()> {
+    if let Some(ref command) = cfg.helper {
+        return HelperClient::exec(sess, command)?.rename(from, to);
+    }
+
+    if posix_rename(sess, from, to)? {
+        return Ok(());
+    }
+
+    let sftp = open_sftp(sess)?;
+    let (from, to) = (Path::new(from), Path::new(to));
+    if sftp.rename(from, to, None).is_ok() {
+        return Ok(());
+    }
+    if sftp.stat(to).is_ok() {
+        sftp.unlink(to).map_err(|source| ZapError::Sftp { op: "replace remote file", path: to.display().to_string(), source })?;
+    }
+    sftp.rename(from, to, None)
+        .map_err(|source| ZapError::Sftp { op: "rename remote file", path: from.display().to_string(), source }.into())
+}
+
+/// Rename with `posix-rename@openssh.com`; Ok(false) if the server doesn't
+/// offer it. ssh2 doesn't wrap the extension, so call libssh2 directly.
+fn posix_rename(sess: &Session, from: &str, to: &str) -> io::Result<bool> {
+    use libssh2_sys as raw;
+    use std::os::raw::{c_char, c_int};
+
+    extern "C" {
+        fn libssh2_sftp_posix_rename_ex(
+            sftp: *mut raw::LIBSSH2_SFTP,
+            source: *const c_char,
+            source_len: usize,
+            dest: *const c_char,
+            dest_len: usize,
+        ) -> c_int;
+    }
+
+    let session = sess.raw();
+    // SAFETY: the session stays locked for the whole exchange, and the SFTP
+    // handle is shut down before the lock is released
+    let (rc, status) = unsafe {
+        let sftp = raw::libssh2_sftp_init(&*session as *const raw::LIBSSH2_SESSION as *mut raw::LIBSSH2_SESSION);
+        if sftp.is_null() {
+            return Err(io::Error::other("Failed to start SFTP for rename"));
+        }
+        let rc = libssh2_sftp_posix_rename_ex(sftp, from.as_ptr().cast(), from.len(), to.as_ptr().cast(), to.len());
+        let status = raw::libssh2_sftp_last_error(sftp);
+        raw::libssh2_sftp_shutdown(sftp);
+        (rc, status)
+    };
+
+    match rc {
+        0 => Ok(true),
+        raw::LIBSSH2_FX_OP_UNSUPPORTED => Ok(false),
+        raw::LIBSSH2_ERROR_SFTP_PROTOCOL => Err(ZapError::Sftp {
+            op: "rename remote file",
+            path: from.to_string(),
+            source: ssh2::Error::new(ssh2::ErrorCode::SFTP(status as c_int), "posix-rename failed"),
+        }.into()),
+        rc => Err(ZapError::Sftp {
+            op: "rename remote file",
+            path: from.to_string(),
+            source: ssh2::Error::new(ssh2::ErrorCode::Session(rc), "posix-rename failed"),
+        }.into()),
+    }
+}
+
+/// Copy a remote file to another path on the same host; only the helper
+/// can do that without the data passing through us
+pub fn copy_remote_file(sess: &Session, cfg: &SessionConfig, from: &str, to: &str) -> io::Result<()> {
+    match cfg.helper {
+        Some(ref command) => HelperClient::exec(sess, command)?.copy(from, to),
+        None => Err(io::Error::new(io::ErrorKind::Unsupported, "Copying a remote file needs zap on the remote host")),
+    }
+}
+
+/// Best-effort removal of a remote temp file after a failed transfer
+pub fn remove_remote_file(sess: &Session, path: &str) {
+    if let Ok(sftp) = open_sftp(sess) {
+        let _ = sftp.unlink(Path::new(path));
+    }
+}
+
 /// Cross-platform positional write for local files
 #[cfg(unix)]
 pub fn write_at_local(file: &File, buf: &[u8], offset: u64) -> io::Result<usize> {
@@ -139,6 +664,391 @@ pub fn write_at_local(file: &File, buf: &[u8], offset: u64) -> io::Result<usize>
     file.seek_write(buf, offset)
 }
 
+/// Cross-platform positional read for local files
+#[cfg(unix)]
+pub fn read_at_local(file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    use std::os::unix::fs::FileExt;
+    file.read_at(buf, offset)
+}
+
+#[cfg(windows)]
+pub fn read_at_local(file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    use std::os::windows::fs::FileExt;
+    file.seek_read(buf, offset)
+}
+
+/// BLAKE3 digest of `start..end` of a local file, safe to call from many threads
+pub fn hash_local_range(file: &File, start: u64, end: u64) -> io::Result<[u8; 32]> {
+    let mut hasher = blake3::Hasher::new();
+    let mut buffer = vec![0u8; BUFFER_SIZE];
+    let mut offset = start;
+
+    while offset < end {
+        let to_read = std::cmp::min(BUFFER_SIZE as u64, end - offset) as usize;
+        let n = read_at_local(file, &mut buffer[..to_read], offset)?;
+        if n == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "File is shorter than the range being hashed"));
+        }
+        hasher.update(&buffer[..n]);
+        offset += n as u64;
+    }
+
+    Ok(*hasher.finalize().as_bytes())
+}
+
+/// Allocated (data) ranges within `start..end` of a local file, found with
+/// SEEK_DATA/SEEK_HOLE. Everything between them reads as zeros.
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+pub fn data_extents(file: &File, start: u64, end: u64) -> io::Result<Vec<(u64, u64)>> {
+    use std::os::unix::io::AsRawFd;
+
+    let fd = file.as_raw_fd();
+    let mut extents = Vec::new();
+    let mut offset = start;
+
+    while offset < end {
+        // SAFETY: lseek only moves the file offset, which positional I/O ignores
+        let data = unsafe { libc::lseek(fd, offset as libc::off_t, libc::SEEK_DATA) };
+        if data < 0 {
+            let err = io::Error::last_os_error();
+            return match err.raw_os_error() {
+                // No data past `offset`: the rest is a hole
+                Some(libc::ENXIO) => Ok(extents),
+                // Filesystem can't tell us; treat everything as data
+                Some(libc::EINVAL) | Some(libc::EOPNOTSUPP) => Ok(vec![(start, end)]),
+                _ => Err(err),
+            };
+        }
+        let data = data as u64;
+        if data >= end {
+            break;
+        }
+        let hole = unsafe { libc::lseek(fd, data as libc::off_t, libc::SEEK_HOLE) };
+        if hole < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let hole = std::cmp::min(hole as u64, end);
+        extents.push((data, hole));
+        offset = hole;
+    }
+
+    Ok(extents)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "freebsd")))]
+pub fn data_extents(_file: &File, start: u64, end: u64) -> io::Result<Vec<(u64, u64)>> {
+    Ok(vec![(start, end)])
+}
+
+/// Ranges of `buf` that are not made of whole zero blocks, merged together
+fn nonzero_runs(buf: &[u8]) -> Vec<(usize, usize)> {
+    let mut runs: Vec<(usize, usize)> = Vec::new();
+    for (i, block) in buf.chunks(ZERO_BLOCK_SIZE).enumerate() {
+        if block.iter().all(|&b| b == 0) {
+            continue;
+        }
+        let (block_start, block_end) = (i * ZERO_BLOCK_SIZE, i * ZERO_BLOCK_SIZE + block.len());
+        match runs.last_mut() {
+            Some(last) if last.1 == block_start => last.1 = block_end,
+            _ => runs.push((block_start, block_end)),
+        }
+    }
+    runs
+}
+
+/// BLAKE3 digests of each `block_size` block of `start..end` of a local file
+pub fn block_hashes_local(file: &File, start: u64, end: u64, block_size: u64) -> io::Result<Vec<[u8; 32]>> {
+    let mut hashes = Vec::new();
+    let mut offset = start;
+    while offset < end {
+        let block_end = std::cmp::min(end, offset + block_size);
+        hashes.push(hash_local_range(file, offset, block_end)?);
+        offset = block_end;
+    }
+    Ok(hashes)
+}
+
+/// Ranges of `start..end` whose blocks differ between the two hash lists,
+/// with neighbouring changed blocks merged into one range
+fn changed_ranges(ours: &[[u8; 32]], theirs: &[[u8; 32]], start: u64, end: u64) -> Vec<(u64, u64)> {
+    let mut ranges: Vec<(u64, u64)> = Vec::new();
+    for (i, (a, b)) in ours.iter().zip(theirs).enumerate() {
+        if a == b {
+            continue;
+        }
+        let block_start = start + i as u64 * DELTA_BLOCK_SIZE;
+        let block_end = std::cmp::min(end, block_start + DELTA_BLOCK_SIZE);
+        match ranges.last_mut() {
+            Some(last) if last.1 == block_start => last.1 = block_end,
+            _ => ranges.push((block_start, block_end)),
+        }
+    }
+    ranges
+}
+
+/// A helper conversation for a --delta stream, which cannot fall back to SFTP
+fn delta_helper(cfg: &SessionConfig, path: &str, flags: u8) -> io::Result<(HelperClient, Option<WireMeter>)> {
+    match stream_helper(cfg)? {
+        (Some(mut helper), meter) => {
+            helper.open(path, flags)?;
+            Ok((helper, meter))
+        },
+        (None, _) => Err(io::Error::new(io::ErrorKind::Unsupported, "--delta needs zap on the remote host")),
+    }
+}
+
+/// Pull only the blocks of `start..end` that differ from what the local file
+/// already holds, returning how many bytes were fetched
+#[allow(clippy::too_many_arguments)]
+fn pull_delta(cfg: &SessionConfig, remote_file: &str, local_file: &File, start: u64, end: u64, cancel: &CancelToken, progress: &StreamProgress) -> io::Result<(u64, Option<WireMeter>)> {
+    let (mut helper, meter) = delta_helper(cfg, remote_file, OPEN_READ)?;
+    progress.phase(Phase::Comparing);
+    let theirs = helper.block_hashes(start, end, DELTA_BLOCK_SIZE)?;
+    let ours = block_hashes_local(local_file, start, end, DELTA_BLOCK_SIZE)?;
+    let ranges = changed_ranges(&ours, &theirs, start, end);
+
+    let changed: u64 = ranges.iter().map(|(s, e)| e - s).sum();
+    progress.set_position(end - start - changed);
+    progress.phase(Phase::Transferring);
+
+    let mut buffer = vec![0u8; BUFFER_SIZE];
+    for (range_start, range_end) in ranges {
+        let mut reader = helper.into_reader(range_start, range_end)?;
+        let mut offset = range_start;
+        while offset < range_end {
+            cancel.check()?;
+            let n = reader.read(&mut buffer)?;
+            if n == 0 {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Unexpected EOF from remote file"));
+            }
+            let mut written = 0;
+            while written < n {
+                written += write_at_local(local_file, &buffer[written..n], offset + written as u64)?;
+            }
+            offset += n as u64;
+            progress.inc(n as u64);
+        }
+        helper = reader.finish()?;
+    }
+    Ok((changed, meter))
+}
+
+/// Push only the blocks of `start..end` that differ from what the remote
+/// file already holds, returning how many bytes were sent
+#[allow(clippy::too_many_arguments)]
+fn push_delta(cfg: &SessionConfig, local_file: &File, remote_file: &str, start: u64, end: u64, fsync: bool, cancel: &CancelToken, progress: &StreamProgress) -> io::Result<(u64, Option<WireMeter>)> {
+    let (mut helper, meter) = delta_helper(cfg, remote_file, OPEN_READ | OPEN_WRITE)?;
+    progress.phase(Phase::Comparing);
+    let theirs = helper.block_hashes(start, end, DELTA_BLOCK_SIZE)?;
+    let ours = block_hashes_local(local_file, start, end, DELTA_BLOCK_SIZE)?;
+    let ranges = changed_ranges(&ours, &theirs, start, end);
+
+    let changed: u64 = ranges.iter().map(|(s, e)| e - s).sum();
+    progress.set_position(end - start - changed);
+    progress.phase(Phase::Transferring);
+
+    let mut buffer = vec![0u8; BUFFER_SIZE];
+    for (range_start, range_end) in ranges {
+        let mut writer = helper.into_writer(range_start)?;
+        let mut offset = range_start;
+        while offset < range_end {
+            cancel.check()?;
+            let to_read = std::cmp::min(BUFFER_SIZE as u64, range_end - offset) as usize;
+            let n = read_at_local(local_file, &mut buffer[..to_read], offset)?;
+            if n == 0 {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Unexpected EOF from local file"));
+            }
+            writer.write_all(&buffer[..n])?;
+            offset += n as u64;
+            progress.inc(n as u64);
+        }
+        helper = writer.finish()?;
+    }
+    if fsync {
+        helper.fsync()?;
+    }
+    Ok((changed, meter))
+}
+
+/// Start a helper conversation for one stream: over the raw data channel if
+/// one was negotiated, otherwise over its own SSH session. None means SFTP.
+fn stream_helper(cfg: &SessionConfig) -> io::Result<(Option<HelperClient>, Option<WireMeter>)> {
+    if let Some(ref channel) = cfg.data_channel {
+        return Ok((Some(channel.connect()?), None));
+    }
+    match cfg.helper {
+        Some(ref command) => {
+            let (sess, meter) = connect_metered(cfg)?;
+            Ok((Some(HelperClient::exec(&sess, command)?), meter))
+        },
+        None => Ok((None, None)),
+    }
+}
+
+/// Where a pull worker reads its segment from
+enum RemoteSource {
+    Sftp(ssh2::File),
+    Helper(RangeReader),
+}
+
+impl RemoteSource {
+    fn open(cfg: &SessionConfig, path: &str, start: u64, end: u64) -> io::Result<(Self, Option<WireMeter>)> {
+        match stream_helper(cfg)? {
+            (Some(mut helper), meter) => {
+                helper.open(path, OPEN_READ)?;
+                Ok((RemoteSource::Helper(helper.into_reader(start, end)?), meter))
+            },
+            (None, _) => {
+                let (sess, meter) = connect_metered(cfg)?;
+                let sftp = open_sftp(&sess)?;
+                let mut remote = sftp.open(Path::new(path))
+                    .map_err(|source| ZapError::Sftp { op: "open remote file", path: path.to_string(), source })?;
+                remote.seek(SeekFrom::Start(start))?;
+                Ok((RemoteSource::Sftp(remote), meter))
+            },
+        }
+    }
+}
+
+impl RemoteSource {
+    /// Consume the rest of the current range so the helper isn't cut off
+    fn finish(self) -> io::Result<()> {
+        match self {
+            RemoteSource::Helper(reader) => reader.finish().map(|_| ()),
+            RemoteSource::Sftp(_) => Ok(()),
+        }
+    }
+
+    /// Reposition for reading `start..end` once the current range is done
+    fn reposition(self, start: u64, end: u64) -> io::Result<Self> {
+        match self {
+            RemoteSource::Helper(reader) => Ok(RemoteSource::Helper(reader.finish()?.into_reader(start, end)?)),
+            RemoteSource::Sftp(mut file) => {
+                file.seek(SeekFrom::Start(start))?;
+                Ok(RemoteSource::Sftp(file))
+            },
+        }
+    }
+
+    /// Next piece of the segment; only the helper can report holes
+    fn read_chunk(&mut self, buf: &mut [u8]) -> io::Result<Chunk> {
+        match self {
+            RemoteSource::Sftp(file) => file.read(buf).map(Chunk::Data),
+            RemoteSource::Helper(reader) => reader.read_chunk(buf),
+        }
+    }
+}
+
+impl Read for RemoteSource {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            RemoteSource::Sftp(file) => file.read(buf),
+            RemoteSource::Helper(reader) => reader.read(buf),
+        }
+    }
+}
+
+/// Where a push worker writes its segment to
+enum RemoteSink {
+    Sftp(ssh2::File),
+    Helper(RangeWriter),
+}
+
+impl RemoteSink {
+    fn open(cfg: &SessionConfig, path: &str, start: u64) -> io::Result<(Self, Option<WireMeter>)> {
+        match stream_helper(cfg)? {
+            (Some(mut helper), meter) => {
+                helper.open(path, OPEN_READ | OPEN_WRITE)?;
+                Ok((RemoteSink::Helper(helper.into_writer(start)?), meter))
+            },
+            (None, _) => {
+                // File should already exist and be extended
+                let (sess, meter) = connect_metered(cfg)?;
+                let sftp = open_sftp(&sess)?;
+                let mut remote = sftp.open_mode(
+                    Path::new(path),
+                    OpenFlags::READ | OpenFlags::WRITE,
+                    0o644,
+                    OpenType::File,
+                ).map_err(|source| ZapError::Sftp { op: "open remote file", path: path.to_string(), source })?;
+                remote.seek(SeekFrom::Start(start))?;
+                Ok((RemoteSink::Sftp(remote), meter))
+            },
+        }
+    }
+
+    /// Move past `len` bytes that stay a hole in the pre-extended file
+    fn skip(&mut self, len: u64) -> io::Result<()> {
+        match self {
+            RemoteSink::Sftp(file) => file.seek(SeekFrom::Current(len as i64)).map(|_| ()),
+            RemoteSink::Helper(writer) => writer.skip(len),
+        }
+    }
+
+    /// Wait until everything written so far has landed, then carry on
+    /// writing at `offset`
+    fn reposition(self, offset: u64) -> io::Result<Self> {
+        match self {
+            RemoteSink::Helper(writer) => Ok(RemoteSink::Helper(writer.finish()?.into_writer(offset)?)),
+            RemoteSink::Sftp(mut file) => {
+                file.flush()?;
+                file.seek(SeekFrom::Start(offset))?;
+                Ok(RemoteSink::Sftp(file))
+            },
+        }
+    }
+
+    /// Wait until everything written has landed (on disk, with `fsync`)
+    fn finish(self, fsync: bool) -> io::Result<()> {
+        match self {
+            RemoteSink::Helper(writer) => {
+                let mut helper = writer.finish()?;
+                if fsync {
+                    helper.fsync()?;
+                }
+                Ok(())
+            },
+            RemoteSink::Sftp(mut file) => {
+                file.flush()?;
+                if fsync {
+                    // Needs the fsync@openssh.com extension
+                    file.fsync().map_err(|e| io::Error::other(format!("Remote fsync failed: {}; use --no-fsync to skip it", e)))?;
+                }
+                Ok(())
+            },
+        }
+    }
+}
+
+impl Write for RemoteSink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            RemoteSink::Sftp(file) => file.write(buf),
+            RemoteSink::Helper(writer) => writer.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            RemoteSink::Sftp(file) => file.flush(),
+            RemoteSink::Helper(writer) => writer.flush(),
+        }
+    }
+}
+
+/// What one stream moved, for the transfer statistics
+#[derive(Debug, Clone, Copy)]
+pub struct StreamResult {
+    /// Payload bytes actually sent; less than the segment with --delta
+    pub bytes_sent: u64,
+    /// Bytes on the wire, when compression is metered
+    pub wire_bytes: Option<u64>,
+    /// Bytes of the segment that were holes in the source and never sent
+    pub holes_skipped: u64,
+    /// Bytes of zero blocks left unwritten at the destination
+    pub zeros_skipped: u64,
+}
+
 /// Calculate retry delay with exponential backoff and jitter
 fn calculate_retry_delay(attempt: u32) -> Duration {
     let delay_ms = std::cmp::min(
@@ -153,7 +1063,36 @@ fn calculate_retry_delay(attempt: u32) -> Duration {
     Duration::from_millis(final_delay)
 }
 
-/// Pull worker: stream data from remote to local using SFTP
+/// Run a stream, and run it again on failure up to `cfg.retries` times
+/// with backoff. A cancelled stream stops where it is.
+fn with_retries<F>(stream_num: usize, cfg: &SessionConfig, cancel: &CancelToken, progress: &StreamProgress, mut run: F) -> io::Result<StreamResult>
+where
+    F: FnMut() -> io::Result<StreamResult>,
+{
+    let mut attempt = 0;
+    loop {
+        match run() {
+            Ok(stream) => return Ok(stream),
+            Err(e) if cancel.is_cancelled() => return Err(e),
+            Err(e) => {
+                attempt += 1;
+                if attempt > cfg.retries {
+                    progress.failed(&e);
+                    return Err(ZapError::Stream { stream: stream_num, retries: cfg.retries, source: Box::new(e.into()) }.into());
+                }
+
+                progress.retry(attempt, &e);
+                let delay = calculate_retry_delay(attempt - 1);
+                thread::sleep(delay);
+            }
+        }
+    }
+}
+
+/// Pull worker: stream data from remote to local using the helper or SFTP.
+/// With `delta`, only blocks that differ from the local file are fetched.
+/// With `skip_zeros`, all-zero blocks are not written, leaving holes.
+#[allow(clippy::too_many_arguments)]
 pub fn pull_worker(
     stream_num: usize,
     start: u64,
@@ -161,185 +1100,580 @@ pub fn pull_worker(
     remote_file: &str,
     cfg: &SessionConfig,
     local_file: &File,
-    pb: ProgressBar,
-) -> io::Result<()> {
+    delta: bool,
+    skip_zeros: bool,
+    cancel: &CancelToken,
+    progress: StreamProgress,
+) -> io::Result<StreamResult> {
     let bytes_to_read = (end - start) as usize;
-    let mut attempt = 0;
-
-    while attempt <= cfg.retries {
-        let result = (|| -> io::Result<()> {
-            // Create new session for this stream
-            let sess = connect_and_auth(cfg)?;
-            let sftp = open_sftp(&sess)?;
-
-            // Open remote file
-            let mut remote = sftp.open(Path::new(remote_file))
-                .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("Failed to open remote file: {}", e)))?;
-
-            // Seek to start position
-            remote.seek(SeekFrom::Start(start))?;
+    with_retries(stream_num, cfg, cancel, &progress, || {
+        cancel.check()?;
+        if delta {
+            let (bytes_sent, meter) = pull_delta(cfg, remote_file, local_file, start, end, cancel, &progress)?;
+            progress.done();
+            let wire_bytes = meter.and_then(|m| m.counts()).map(|(_, received)| received);
+            return Ok(StreamResult { bytes_sent, wire_bytes, holes_skipped: 0, zeros_skipped: 0 });
+        }
 
-            // Read and write loop
-            let mut buffer = vec![0u8; BUFFER_SIZE];
-            let mut total_read = 0;
-            let start_time = std::time::Instant::now();
-            let mut last_update = start_time;
+        // Open remote file on a new connection, positioned at the start of our segment
+        let (mut remote, meter) = RemoteSource::open(cfg, remote_file, start, end)?;
 
-            while total_read < bytes_to_read {
-                let to_read = std::cmp::min(BUFFER_SIZE, bytes_to_read - total_read);
-                let n = remote.read(&mut buffer[..to_read])?;
+        // Read and write loop
+        let mut buffer = vec![0u8; BUFFER_SIZE];
+        let mut total_read = 0;
+        let mut holes_skipped = 0;
+        let mut zeros_skipped = 0;
 
-                if n == 0 {
+        while total_read < bytes_to_read {
+            cancel.check()?;
+            let to_read = std::cmp::min(BUFFER_SIZE, bytes_to_read - total_read);
+            let n = match remote.read_chunk(&mut buffer[..to_read])? {
+                Chunk::Data(0) => {
                     return Err(io::Error::new(
                         io::ErrorKind::UnexpectedEof,
                         "Unexpected EOF from remote file",
                     ));
-                }
+                },
+                Chunk::Data(n) => {
+                    // Positional write to local file, leaving zero blocks
+                    // as the holes set_len made if asked to
+                    let offset = start + total_read as u64;
+                    let runs = if skip_zeros { nonzero_runs(&buffer[..n]) } else { vec![(0, n)] };
+                    for &(run_start, run_end) in &runs {
+                        let mut written = run_start;
+                        while written < run_end {
+                            let w = write_at_local(local_file, &buffer[written..run_end], offset + written as u64)?;
+                            written += w;
+                        }
+                    }
+                    zeros_skipped += (n - runs.iter().map(|(s, e)| e - s).sum::<usize>()) as u64;
+                    n
+                },
+                // The local file was extended sparse, so holes need no write
+                Chunk::Hole(len) => {
+                    holes_skipped += len;
+                    len as usize
+                },
+            };
 
-                // Positional write to local file
-                let offset = start + total_read as u64;
-                let mut written = 0;
-                while written < n {
-                    let w = write_at_local(local_file, &buffer[written..n], offset + written as u64)?;
-                    written += w;
-                }
+            total_read += n;
+            progress.set_position(total_read as u64);
+        }
+
+        progress.done();
+        let wire_bytes = meter.and_then(|m| m.counts()).map(|(_, received)| received);
+        Ok(StreamResult { bytes_sent: end - start - holes_skipped, wire_bytes, holes_skipped, zeros_skipped })
+    })
+}
+
+/// Push worker: stream data from local to remote using the helper or SFTP.
+/// With `delta`, only blocks that differ from the remote file are sent.
+/// With `skip_zeros`, all-zero blocks are skipped like holes. With `fsync`,
+/// the segment is flushed to the remote disk before the stream reports done.
+#[allow(clippy::too_many_arguments)]
+pub fn push_worker(
+    stream_num: usize,
+    start: u64,
+    end: u64,
+    local_file_path: &str,
+    remote_file: &str,
+    cfg: &SessionConfig,
+    delta: bool,
+    skip_zeros: bool,
+    fsync: bool,
+    cancel: &CancelToken,
+    progress: StreamProgress,
+) -> io::Result<StreamResult> {
+    let bytes_to_write = (end - start) as usize;
+    with_retries(stream_num, cfg, cancel, &progress, || {
+        cancel.check()?;
+        if delta {
+            let local = File::open(local_file_path)?;
+            let (bytes_sent, meter) = push_delta(cfg, &local, remote_file, start, end, fsync, cancel, &progress)?;
+            progress.done();
+            let wire_bytes = meter.and_then(|m| m.counts()).map(|(sent, _)| sent);
+            return Ok(StreamResult { bytes_sent, wire_bytes, holes_skipped: 0, zeros_skipped: 0 });
+        }
+
+        // Open remote file on a new connection, positioned at the start of our segment
+        let (mut remote, meter) = RemoteSink::open(cfg, remote_file, start)?;
+
+        // Open local file and map its data; holes are skipped on the wire
+        let local = File::open(local_file_path)?;
+        let extents = data_extents(&local, start, end)?;
+        let mut next_extent = 0;
+
+        // Read and write loop
+        let mut buffer = vec![0u8; BUFFER_SIZE];
+        let mut total_written = 0;
+        let mut holes_skipped = 0;
+        let mut zeros_skipped = 0;
+
+        while total_written < bytes_to_write {
+            cancel.check()?;
+            let offset = start + total_written as u64;
+            while next_extent < extents.len() && extents[next_extent].1 <= offset {
+                next_extent += 1;
+            }
+            let data_start = extents.get(next_extent).map_or(end, |extent| std::cmp::max(extent.0, offset));
+            if data_start > offset {
+                let hole = data_start - offset;
+                remote.skip(hole)?;
+                holes_skipped += hole;
+                total_written += hole as usize;
+                progress.set_position(total_written as u64);
+                continue;
+            }
+
+            let to_read = std::cmp::min(BUFFER_SIZE as u64, extents[next_extent].1 - offset) as usize;
+            let n = read_at_local(&local, &mut buffer[..to_read], offset)?;
 
-                total_read += n;
-                pb.set_position(total_read as u64);
+            if n == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "Unexpected EOF from local file",
+                ));
+            }
 
-                // Update throughput display
-                let now = std::time::Instant::now();
-                if now.duration_since(last_update) > Duration::from_secs(1) {
-                    let elapsed = now.duration_since(start_time).as_secs_f64();
-                    let throughput = (total_read as f64 / 1024.0 / 1024.0) / elapsed;
-                    pb.set_message(format!("{:.2} MB/s", throughput));
-                    last_update = now;
+            // Write to remote file, skipping zero blocks if asked to
+            if skip_zeros {
+                let mut pos = 0;
+                for (run_start, run_end) in nonzero_runs(&buffer[..n]) {
+                    if run_start > pos {
+                        remote.skip((run_start - pos) as u64)?;
+                        zeros_skipped += (run_start - pos) as u64;
+                    }
+                    remote.write_all(&buffer[run_start..run_end])?;
+                    pos = run_end;
                 }
+                if n > pos {
+                    remote.skip((n - pos) as u64)?;
+                    zeros_skipped += (n - pos) as u64;
+                }
+            } else {
+                remote.write_all(&buffer[..n])?;
             }
 
-            pb.finish_with_message("done");
-            Ok(())
-        })();
+            total_written += n;
+            progress.set_position(total_written as u64);
+        }
 
-        match result {
-            Ok(_) => return Ok(()),
-            Err(e) => {
-                attempt += 1;
-                if attempt > cfg.retries {
-                    pb.finish_with_message("failed");
-                    return Err(io::Error::new(
-                        io::ErrorKind::Other,
-                        format!("Stream {} failed after {} retries: {}", stream_num, cfg.retries, e),
-                    ));
+        if fsync {
+            progress.phase(Phase::Syncing);
+        }
+        remote.finish(fsync)?;
+
+        progress.done();
+        let wire_bytes = meter.and_then(|m| m.counts()).map(|(sent, _)| sent);
+        Ok(StreamResult { bytes_sent: end - start - holes_skipped - zeros_skipped, wire_bytes, holes_skipped, zeros_skipped })
+    })
+}
+
+/// Report how a stream without retries ended
+fn finish_stream(progress: &StreamProgress, result: io::Result<StreamResult>) -> io::Result<StreamResult> {
+    match result {
+        Ok(_) => progress.done(),
+        Err(ref e) => progress.failed(e),
+    }
+    result
+}
+
+/// Local copy worker: copy `start..end` between two local files with
+/// positional reads and writes, for parallel copies between network mounts.
+/// Holes (and zero blocks, with `skip_zeros`) are left unwritten in the
+/// pre-extended destination.
+#[allow(clippy::too_many_arguments)]
+pub fn copy_worker(
+    start: u64,
+    end: u64,
+    source: &File,
+    dest: &File,
+    skip_zeros: bool,
+    cancel: &CancelToken,
+    progress: StreamProgress,
+) -> io::Result<StreamResult> {
+    let result = (|| -> io::Result<StreamResult> {
+        let extents = data_extents(source, start, end)?;
+        let mut buffer = vec![0u8; BUFFER_SIZE];
+        let mut holes_skipped = end - start;
+        let mut zeros_skipped = 0;
+
+        for (extent_start, extent_end) in extents {
+            holes_skipped -= extent_end - extent_start;
+            progress.set_position(extent_start - start);
+            let mut offset = extent_start;
+            while offset < extent_end {
+                cancel.check()?;
+                let to_read = std::cmp::min(BUFFER_SIZE as u64, extent_end - offset) as usize;
+                let n = read_at_local(source, &mut buffer[..to_read], offset)?;
+                if n == 0 {
+                    return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Unexpected EOF from source file"));
                 }
 
-                let delay = calculate_retry_delay(attempt - 1);
-                thread::sleep(delay);
+                let runs = if skip_zeros { nonzero_runs(&buffer[..n]) } else { vec![(0, n)] };
+                zeros_skipped += (n - runs.iter().map(|(s, e)| e - s).sum::<usize>()) as u64;
+                for (run_start, run_end) in runs {
+                    let mut written = run_start;
+                    while written < run_end {
+                        written += write_at_local(dest, &buffer[written..run_end], offset + written as u64)?;
+                    }
+                }
+                offset += n as u64;
+                progress.set_position(offset - start);
             }
         }
-    }
+        progress.set_position(end - start);
 
-    Err(io::Error::new(
-        io::ErrorKind::Other,
-        format!("Stream {} failed after {} retries", stream_num, cfg.retries),
-    ))
+        Ok(StreamResult { bytes_sent: end - start - holes_skipped - zeros_skipped, wire_bytes: None, holes_skipped, zeros_skipped })
+    })();
+    finish_stream(&progress, result)
 }
 
-/// Push worker: stream data from local to remote using SFTP
-pub fn push_worker(
+/// Stdout worker: read every `num_streams`th chunk of the remote file,
+/// starting with chunk `stream_num`, and hand them over in order. A retry
+/// reconnects at the first chunk not yet handed over.
+#[allow(clippy::too_many_arguments)]
+pub fn pull_chunks_worker(
     stream_num: usize,
-    start: u64,
-    end: u64,
-    local_file_path: &str,
+    num_streams: usize,
+    size: u64,
     remote_file: &str,
     cfg: &SessionConfig,
-    pb: ProgressBar,
-) -> io::Result<()> {
-    let bytes_to_write = (end - start) as usize;
-    let mut attempt = 0;
+    chunks: SyncSender<Vec<u8>>,
+    cancel: &CancelToken,
+    progress: StreamProgress,
+) -> io::Result<StreamResult> {
+    let chunk_range = |k: u64| {
+        let start = k * STREAM_CHUNK_SIZE as u64;
+        (start, std::cmp::min(size, start + STREAM_CHUNK_SIZE as u64))
+    };
+    let mut k = stream_num as u64;
+    let mut bytes_sent = 0;
+    let mut meters = Vec::new();
+    let mut closed = false;
+    let result = with_retries(stream_num, cfg, cancel, &progress, || {
+        cancel.check()?;
+        let (start, end) = chunk_range(k);
+        if start >= size {
+            return Ok(StreamResult { bytes_sent, wire_bytes: None, holes_skipped: 0, zeros_skipped: 0 });
+        }
 
-    while attempt <= cfg.retries {
-        let result = (|| -> io::Result<()> {
-            // Create new session for this stream
-            let sess = connect_and_auth(cfg)?;
-            let sftp = open_sftp(&sess)?;
-
-            // Open remote file with WRITE flag (file should already exist and be extended)
-            let mut remote = sftp.open_mode(
-                Path::new(remote_file),
-                OpenFlags::WRITE,
-                0o644,
-                OpenType::File,
-            ).map_err(|e| io::Error::new(io::ErrorKind::Other, format!("Failed to open remote file: {}", e)))?;
-
-            // Seek to start position
-            remote.seek(SeekFrom::Start(start))?;
-
-            // Open local file
-            let mut local = File::open(local_file_path)?;
-            local.seek(SeekFrom::Start(start))?;
-
-            // Read and write loop
-            let mut buffer = vec![0u8; BUFFER_SIZE];
-            let mut total_written = 0;
-            let start_time = std::time::Instant::now();
-            let mut last_update = start_time;
-
-            while total_written < bytes_to_write {
-                let to_read = std::cmp::min(BUFFER_SIZE, bytes_to_write - total_written);
-                let n = local.read(&mut buffer[..to_read])?;
+        let (mut source, meter) = RemoteSource::open(cfg, remote_file, start, end)?;
+        meters.push(meter);
+        loop {
+            cancel.check()?;
+            let (start, end) = chunk_range(k);
+            let mut chunk = vec![0u8; (end - start) as usize];
+            source.read_exact(&mut chunk)?;
+            k += num_streams as u64;
+            bytes_sent += chunk.len() as u64;
+            progress.inc(chunk.len() as u64);
+            // Nobody is reading any more, which no retry will change
+            if chunks.send(chunk).is_err() {
+                closed = true;
+                return Ok(StreamResult { bytes_sent, wire_bytes: None, holes_skipped: 0, zeros_skipped: 0 });
+            }
 
-                if n == 0 {
-                    return Err(io::Error::new(
-                        io::ErrorKind::UnexpectedEof,
-                        "Unexpected EOF from local file",
-                    ));
-                }
+            let (start, end) = chunk_range(k);
+            if start >= size {
+                break;
+            }
+            source = source.reposition(start, end)?;
+        }
+        source.finish()?;
 
-                // Write to remote file
-                let mut written = 0;
-                while written < n {
-                    let w = remote.write(&buffer[written..n])?;
-                    written += w;
-                }
+        let wire_bytes = meters.iter().map(|m| m.as_ref().and_then(|m| m.counts()).map(|(_, received)| received)).sum();
+        Ok(StreamResult { bytes_sent, wire_bytes, holes_skipped: 0, zeros_skipped: 0 })
+    })?;
+    if closed {
+        return finish_stream(&progress, Err(io::Error::new(io::ErrorKind::BrokenPipe, "Output closed")));
+    }
+    progress.done();
+    Ok(result)
+}
+
+/// Stdin and relay worker: write the chunks it is handed (every `num_streams`th chunk
+/// of the input, starting with chunk `stream_num`) at their offsets in the
+/// remote file. Each chunk is kept until the destination confirms it, so a
+/// retry reconnects and writes it again.
+#[allow(clippy::too_many_arguments)]
+pub fn push_chunks_worker(
+    stream_num: usize,
+    num_streams: usize,
+    remote_file: &str,
+    cfg: &SessionConfig,
+    chunks: Receiver<Vec<u8>>,
+    fsync: bool,
+    cancel: &CancelToken,
+    progress: StreamProgress,
+) -> io::Result<StreamResult> {
+    let stride = num_streams as u64 * STREAM_CHUNK_SIZE as u64;
+    let mut chunks = chunks.into_iter();
+    let mut offset = stream_num as u64 * STREAM_CHUNK_SIZE as u64;
+    let mut pending: Option<Vec<u8>> = None;
+    let mut bytes_sent = 0;
+    let mut meters = Vec::new();
+    with_retries(stream_num, cfg, cancel, &progress, || {
+        cancel.check()?;
+        let (mut remote, meter) = RemoteSink::open(cfg, remote_file, offset)?;
+        meters.push(meter);
+        loop {
+            cancel.check()?;
+            if pending.is_none() {
+                pending = chunks.next();
+            }
+            let Some(chunk) = &pending else { break };
+            remote.write_all(chunk)?;
+            let len = chunk.len() as u64;
+            remote = remote.reposition(offset + stride)?;
+            pending = None;
+            offset += stride;
+            bytes_sent += len;
+            progress.inc(len);
+        }
+        remote.finish(fsync)?;
+        progress.done();
+
+        let wire_bytes = meters.iter().map(|m| m.as_ref().and_then(|m| m.counts()).map(|(sent, _)| sent)).sum();
+        Ok(StreamResult { bytes_sent, wire_bytes, holes_skipped: 0, zeros_skipped: 0 })
+    })
+}
 
-                total_written += n;
-                pb.set_position(total_written as u64);
+/// Quote `arg` for a POSIX shell
+fn shell_quote(arg: &str) -> String {
+    format!("'{}'", arg.replace('\'', "'\\''"))
+}
 
-                // Update throughput display
-                let now = std::time::Instant::now();
-                if now.duration_since(last_update) > Duration::from_secs(1) {
-                    let elapsed = now.duration_since(start_time).as_secs_f64();
-                    let throughput = (total_written as f64 / 1024.0 / 1024.0) / elapsed;
-                    pb.set_message(format!("{:.2} MB/s", throughput));
-                    last_update = now;
-                }
+/// Run `command` with `args` on the remote host, passing its output
+/// (stderr merged into stdout) on to `out`, and return its exit status.
+/// It runs on a terminal, so cancelling types Ctrl-C into it, as with
+/// `ssh -t`, and it gets to clean up and exit on its own.
+pub fn run_remote_command(sess: &Session, command: &str, args: &[String], cancel: &CancelToken, out: &mut dyn Write) -> io::Result<i32> {
+    let mut channel = sess.channel_session()?;
+    let mut modes = PtyModes::new();
+    // Leave line endings alone in the output we pass through, and keep a
+    // typed Ctrl-C out of it
+    modes.set_boolean(PtyModeOpcode::ONLCR, false);
+    modes.set_boolean(PtyModeOpcode::ECHO, false);
+    modes.set_boolean(PtyModeOpcode::ISIG, true);
+    modes.set_character(PtyModeOpcode::VINTR, Some('\x03'));
+    channel.request_pty("dumb", Some(modes), None)?;
+    channel.handle_extended_data(ExtendedData::Merge)?;
+    let line = std::iter::once(command.to_string())
+        .chain(args.iter().map(|arg| shell_quote(arg)))
+        .collect::<Vec<_>>()
+        .join(" ");
+    channel.exec(&line)?;
+
+    // Poll, so a cancel is noticed while the remote side is quiet
+    sess.set_blocking(false);
+    let mut buf = vec![0u8; 64 * 1024];
+    let mut interrupted = false;
+    loop {
+        if cancel.is_cancelled() && !interrupted {
+            match channel.write(b"\x03") {
+                Ok(0) => {},
+                Ok(_) => interrupted = true,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {},
+                Err(e) => return Err(e),
             }
+        }
+        match channel.read(&mut buf) {
+            Ok(0) if channel.eof() => break,
+            Ok(0) => thread::sleep(Duration::from_millis(20)),
+            Ok(n) => {
+                out.write_all(&buf[..n])?;
+                out.flush()?;
+            },
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => thread::sleep(Duration::from_millis(20)),
+            Err(e) => return Err(e),
+        }
+    }
+    sess.set_blocking(true);
+    channel.wait_close()?;
+    Ok(channel.exit_status()?)
+}
 
-            pb.finish_with_message("done");
-            Ok(())
-        })();
+/// How often `KeepAlive` pings its sessions
+const KEEPALIVE_INTERVAL_SECS: u32 = 15;
 
-        match result {
-            Ok(_) => return Ok(()),
-            Err(e) => {
-                attempt += 1;
-                if attempt > cfg.retries {
-                    pb.finish_with_message("failed");
-                    return Err(io::Error::new(
-                        io::ErrorKind::Other,
-                        format!("Stream {} failed after {} retries: {}", stream_num, cfg.retries, e),
-                    ));
-                }
+/// Sends SSH keepalives on some sessions from a background thread until
+/// dropped. Sessions that sit idle while the streams run on connections
+/// of their own would otherwise be at the mercy of the server's and any
+/// firewall's idle timeouts.
+pub struct KeepAlive {
+    stop: Option<mpsc::Sender<()>>,
+    thread: Option<thread::JoinHandle<()>>,
+}
 
-                let delay = calculate_retry_delay(attempt - 1);
-                thread::sleep(delay);
+impl KeepAlive {
+    pub fn start(sessions: &[&Session]) -> Self {
+        let sessions: Vec<Session> = sessions.iter().map(|&sess| sess.clone()).collect();
+        for sess in &sessions {
+            sess.set_keepalive(false, KEEPALIVE_INTERVAL_SECS);
+        }
+        let (stop, stopped) = mpsc::channel::<()>();
+        let thread = thread::spawn(move || {
+            let interval = Duration::from_secs(KEEPALIVE_INTERVAL_SECS.into());
+            while let Err(mpsc::RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                for sess in &sessions {
+                    // A session that has dropped fails when it is next used
+                    let _ = sess.keepalive_send();
+                }
             }
+        });
+        KeepAlive { stop: Some(stop), thread: Some(thread) }
+    }
+}
+
+impl Drop for KeepAlive {
+    fn drop(&mut self) {
+        drop(self.stop.take());
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    const B: u64 = DELTA_BLOCK_SIZE;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("zap-ssh-{}-{}", std::process::id(), name))
+    }
+
+    fn hashes(tags: &[u8]) -> Vec<[u8; 32]> {
+        tags.iter().map(|&t| [t; 32]).collect()
+    }
+
+    #[test]
+    fn local_copies_are_independent() {
+        let (from, to) = (temp_path("copy-from"), temp_path("copy-to"));
+        fs::write(&from, b"original").unwrap();
+        fs::write(&to, b"stale contents").unwrap();
+        copy_local(&from, &to).unwrap();
+        assert_eq!(fs::read(&to).unwrap(), b"original");
+
+        write_at_local(&fs::OpenOptions::new().write(true).open(&to).unwrap(), b"patched", 0).unwrap();
+        let (kept, patched) = (fs::read(&from).unwrap(), fs::read(&to).unwrap());
+        fs::remove_file(&from).unwrap();
+        fs::remove_file(&to).unwrap();
+        assert_eq!(kept, b"original");
+        assert_eq!(patched, b"patchedl");
+    }
+
+    #[test]
+    fn identical_blocks_need_nothing() {
+        assert!(changed_ranges(&hashes(&[1, 2, 3]), &hashes(&[1, 2, 3]), 0, 3 * B).is_empty());
+    }
+
+    #[test]
+    fn neighbouring_changed_blocks_merge() {
+        let ours = hashes(&[1, 2, 3, 4, 5]);
+        let theirs = hashes(&[1, 9, 9, 4, 9]);
+        assert_eq!(changed_ranges(&ours, &theirs, 0, 5 * B), vec![(B, 3 * B), (4 * B, 5 * B)]);
+    }
+
+    #[test]
+    fn changed_ranges_are_offset_and_clipped_to_the_segment() {
+        // A segment starting mid-file whose last block is short
+        let start = 10 * B;
+        let end = start + 2 * B + 100;
+        let ranges = changed_ranges(&hashes(&[1, 2, 3]), &hashes(&[9, 2, 9]), start, end);
+        assert_eq!(ranges, vec![(start, start + B), (start + 2 * B, end)]);
+    }
+
+    #[test]
+    fn block_hashes_match_each_block() {
+        let path = temp_path("blocks");
+        let data: Vec<u8> = (0..B as usize * 2 + 10).map(|i| (i % 253) as u8).collect();
+        fs::write(&path, &data).unwrap();
+        let file = File::open(&path).unwrap();
+        let hashes = block_hashes_local(&file, 0, data.len() as u64, B).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let expected: Vec<[u8; 32]> = data.chunks(B as usize).map(|c| *blake3::hash(c).as_bytes()).collect();
+        assert_eq!(hashes, expected);
+    }
+
+    /// A file of `len` bytes with data only at `data`, or None if this
+    /// filesystem doesn't keep holes
+    #[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+    fn sparse_file(name: &str, len: u64, data: &[(u64, usize)]) -> Option<(File, PathBuf)> {
+        use std::os::unix::fs::MetadataExt;
+
+        let path = temp_path(name);
+        let file = fs::OpenOptions::new().create(true).truncate(true).read(true).write(true).open(&path).unwrap();
+        file.set_len(len).unwrap();
+        for &(offset, n) in data {
+            write_at_local(&file, &vec![0xab; n], offset).unwrap();
+        }
+        file.sync_all().unwrap();
+        if file.metadata().unwrap().blocks() * 512 >= len {
+            fs::remove_file(&path).unwrap();
+            return None;
         }
+        Some((file, path))
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+    #[test]
+    fn data_extents_skip_holes() {
+        let mb = 1024 * 1024;
+        let Some((file, path)) = sparse_file("extents", 8 * mb, &[(2 * mb, 4096), (5 * mb, 4096)]) else {
+            return;
+        };
+        let whole = data_extents(&file, 0, 8 * mb).unwrap();
+        let clipped = data_extents(&file, 2 * mb + 100, 5 * mb + 10).unwrap();
+        let empty = data_extents(&file, 6 * mb, 8 * mb).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        // Filesystems round extents to their block size, so only check that
+        // the data is covered and the big holes are not
+        assert_eq!(whole.len(), 2, "{:?}", whole);
+        assert!(whole[0].0 <= 2 * mb && whole[0].1 >= 2 * mb + 4096 && whole[0].1 < 5 * mb, "{:?}", whole);
+        assert!(whole[1].0 <= 5 * mb && whole[1].0 > 2 * mb && whole[1].1 >= 5 * mb + 4096 && whole[1].1 < 6 * mb, "{:?}", whole);
+        assert_eq!(clipped.first().unwrap().0, 2 * mb + 100);
+        assert_eq!(clipped.last().unwrap().1, 5 * mb + 10);
+        assert!(empty.is_empty(), "{:?}", empty);
     }
 
-    Err(io::Error::new(
-        io::ErrorKind::Other,
-        format!("Stream {} failed after {} retries", stream_num, cfg.retries),
-    ))
+    #[test]
+    fn data_extents_of_a_full_file_is_the_whole_range() {
+        let path = temp_path("dense");
+        fs::write(&path, vec![1u8; 10_000]).unwrap();
+        let extents = data_extents(&File::open(&path).unwrap(), 100, 9_000).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(extents, vec![(100, 9_000)]);
+    }
+
+    #[test]
+    fn nonzero_runs_skip_whole_zero_blocks() {
+        const Z: usize = ZERO_BLOCK_SIZE;
+        assert!(nonzero_runs(&[]).is_empty());
+        assert!(nonzero_runs(&vec![0u8; 3 * Z]).is_empty());
+
+        // Blocks 1 and 2 have data and merge; block 4 is on its own
+        let mut buf = vec![0u8; 5 * Z];
+        buf[Z] = 1;
+        buf[3 * Z - 1] = 1;
+        buf[4 * Z + 7] = 1;
+        assert_eq!(nonzero_runs(&buf), vec![(Z, 3 * Z), (4 * Z, 5 * Z)]);
+    }
+
+    #[test]
+    fn nonzero_runs_keep_a_short_tail() {
+        const Z: usize = ZERO_BLOCK_SIZE;
+        let mut buf = vec![0u8; 2 * Z + 10];
+        buf[2 * Z + 9] = 1;
+        assert_eq!(nonzero_runs(&buf), vec![(2 * Z, 2 * Z + 10)]);
+        // A single zero byte inside a block keeps the whole block
+        let mut buf = vec![1u8; Z];
+        buf[0] = 0;
+        assert_eq!(nonzero_runs(&buf), vec![(0, Z)]);
+    }
 }
@@ -0,0 +1,431 @@
+use std::fmt;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+use aes_gcm::aead::AeadInPlace;
+use aes_gcm::{Aes256Gcm, KeyInit, Nonce};
+use rand::RngCore;
+use rand::rngs::OsRng;
+use crate::protocol::{self, HelperClient, Split};
+use crate::server;
+
+const RECORD_SIZE: usize = 256 * 1024;
+const TAG_LEN: usize = 16;
+const SALT_LEN: usize = 16;
+const CONNECT_TIMEOUT_SECS: u64 = 30;
+/// How long a data connection may take to prove it knows the key
+const HANDSHAKE_TIMEOUT_SECS: u64 = 10;
+
+const MODE_PLAIN: u8 = 0;
+const MODE_AES_GCM: u8 = 1;
+
+/// A raw TCP listener set up by the remote helper for bulk data
+#[derive(Clone)]
+pub struct TcpChannel {
+    pub addr: SocketAddr,
+    pub key: [u8; 32],
+    pub encrypt: bool,
+}
+
+impl fmt::Debug for TcpChannel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TcpChannel")
+            .field("addr", &self.addr)
+            .field("encrypt", &self.encrypt)
+            .finish_non_exhaustive()
+    }
+}
+
+impl TcpChannel {
+    /// Ask the helper on `control` to open a listener, and work out where to
+    /// reach it. `control` must stay alive for as long as the data channel is used.
+    pub fn negotiate(control: &mut HelperClient, host: &str, encrypt: bool) -> io::Result<Self> {
+        let (port, key) = control.listen(encrypt)?;
+
+        let addr = (host, port)
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("Could not resolve hostname: {}", host)))?;
+
+        Ok(TcpChannel { addr, key, encrypt })
+    }
+
+    /// Open one data connection and start a helper conversation on it
+    pub fn connect(&self) -> io::Result<HelperClient> {
+        let tcp = TcpStream::connect_timeout(&self.addr, Duration::from_secs(CONNECT_TIMEOUT_SECS))?;
+        tcp.set_nodelay(true)?;
+        let mut tcp_write = tcp.try_clone()?;
+        let mut tcp_read = tcp;
+
+        let mut client_salt = [0u8; SALT_LEN];
+        OsRng.fill_bytes(&mut client_salt);
+        let mode = if self.encrypt { MODE_AES_GCM } else { MODE_PLAIN };
+        tcp_write.write_all(protocol::MAGIC)?;
+        tcp_write.write_all(&[mode])?;
+        tcp_write.write_all(&client_salt)?;
+
+        let mut server_salt = [0u8; SALT_LEN];
+        tcp_read.read_exact(&mut server_salt)?;
+        tcp_write.write_all(&derive(&self.key, b"auth", &client_salt, &server_salt))?;
+
+        let stream: Box<dyn protocol::Duplex> = if self.encrypt {
+            Box::new(Split {
+                reader: AeadReader::new(tcp_read, &derive(&self.key, b"s2c", &client_salt, &server_salt)),
+                writer: AeadWriter::new(tcp_write, &derive(&self.key, b"c2s", &client_salt, &server_salt)),
+            })
+        } else {
+            Box::new(Split {
+                reader: BufReader::with_capacity(RECORD_SIZE, tcp_read),
+                writer: BufWriter::with_capacity(RECORD_SIZE, tcp_write),
+            })
+        };
+
+        HelperClient::new(stream)
+    }
+}
+
+/// Per-connection secret, so no two streams or directions share a key
+fn derive(master: &[u8; 32], label: &[u8], client_salt: &[u8], server_salt: &[u8]) -> [u8; 32] {
+    *blake3::Hasher::new_keyed(master)
+        .update(label)
+        .update(client_salt)
+        .update(server_salt)
+        .finalize()
+        .as_bytes()
+}
+
+/// A data listener opened by `LISTEN`. It stops accepting when dropped,
+/// which the server does once the control connection that asked for it closes.
+pub struct Listener {
+    addr: SocketAddr,
+    closed: Arc<AtomicBool>,
+}
+
+impl Listener {
+    pub fn port(&self) -> u16 {
+        self.addr.port()
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        self.closed.store(true, Ordering::SeqCst);
+        // Wake the accept loop so it sees the flag
+        let mut wake = self.addr;
+        if wake.ip().is_unspecified() {
+            wake.set_ip(match wake.ip() {
+                IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::LOCALHOST),
+                IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::LOCALHOST),
+            });
+        }
+        let _ = TcpStream::connect_timeout(&wake, Duration::from_secs(1));
+    }
+}
+
+/// Server side of `LISTEN`: bind next to the SSH connection and serve every
+/// data connection that proves it knows the key
+pub fn listen(encrypt: bool) -> io::Result<(Listener, [u8; 32])> {
+    let listener = TcpListener::bind((server::ssh_local_ip(), 0))?;
+    let addr = listener.local_addr()?;
+    let closed = Arc::new(AtomicBool::new(false));
+
+    let mut key = [0u8; 32];
+    OsRng.fill_bytes(&mut key);
+
+    let accepting = Arc::clone(&closed);
+    thread::spawn(move || {
+        for tcp in listener.incoming() {
+            if accepting.load(Ordering::SeqCst) {
+                break;
+            }
+            let Ok(tcp) = tcp else { continue };
+            thread::spawn(move || {
+                if let Err(e) = serve_connection(tcp, &key, encrypt) {
+                    eprintln!("zap server: data connection: {}", e);
+                }
+            });
+        }
+    });
+
+    Ok((Listener { addr, closed }, key))
+}
+
+fn serve_connection(tcp: TcpStream, key: &[u8; 32], encrypt: bool) -> io::Result<()> {
+    tcp.set_nodelay(true)?;
+    // Nobody gets to hold a thread open without the key
+    tcp.set_read_timeout(Some(Duration::from_secs(HANDSHAKE_TIMEOUT_SECS)))?;
+    let mut tcp_write = tcp.try_clone()?;
+    let mut tcp_read = tcp;
+
+    let mut hello = [0u8; 5 + SALT_LEN];
+    tcp_read.read_exact(&mut hello)?;
+    let expected_mode = if encrypt { MODE_AES_GCM } else { MODE_PLAIN };
+    if &hello[..4] != protocol::MAGIC || hello[4] != expected_mode {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Unexpected data connection handshake"));
+    }
+    let client_salt = &hello[5..];
+
+    let mut server_salt = [0u8; SALT_LEN];
+    OsRng.fill_bytes(&mut server_salt);
+    tcp_write.write_all(&server_salt)?;
+
+    let mut token = [0u8; 32];
+    tcp_read.read_exact(&mut token)?;
+    let expected = derive(key, b"auth", client_salt, &server_salt);
+    if !protocol::constant_time_eq(&token, &expected) {
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied, "Data connection failed authentication"));
+    }
+    tcp_read.set_read_timeout(None)?;
+
+    if encrypt {
+        server::serve(
+            AeadReader::new(tcp_read, &derive(key, b"c2s", client_salt, &server_salt)),
+            AeadWriter::new(tcp_write, &derive(key, b"s2c", client_salt, &server_salt)),
+        )
+    } else {
+        server::serve(
+            BufReader::with_capacity(RECORD_SIZE, tcp_read),
+            BufWriter::with_capacity(RECORD_SIZE, tcp_write),
+        )
+    }
+}
+
+/// 96-bit GCM nonce from a per-direction record counter
+fn nonce(counter: u64) -> [u8; 12] {
+    let mut n = [0u8; 12];
+    n[4..].copy_from_slice(&counter.to_be_bytes());
+    n
+}
+
+/// Seals everything written into length-prefixed AES-256-GCM records
+pub struct AeadWriter<W: Write> {
+    inner: W,
+    cipher: Aes256Gcm,
+    counter: u64,
+    pending: Vec<u8>,
+}
+
+impl<W: Write> AeadWriter<W> {
+    pub fn new(inner: W, key: &[u8; 32]) -> Self {
+        AeadWriter {
+            inner,
+            cipher: Aes256Gcm::new(key.into()),
+            counter: 0,
+            pending: Vec::with_capacity(RECORD_SIZE + TAG_LEN),
+        }
+    }
+
+    fn seal(&mut self) -> io::Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        let nonce = nonce(self.counter);
+        self.counter += 1;
+        self.cipher
+            .encrypt_in_place(Nonce::from_slice(&nonce), b"", &mut self.pending)
+            .map_err(|_| io::Error::other("Failed to encrypt record"))?;
+        self.inner.write_all(&(self.pending.len() as u32).to_be_bytes())?;
+        self.inner.write_all(&self.pending)?;
+        self.pending.clear();
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for AeadWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = std::cmp::min(buf.len(), RECORD_SIZE - self.pending.len());
+        self.pending.extend_from_slice(&buf[..n]);
+        if self.pending.len() == RECORD_SIZE {
+            self.seal()?;
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.seal()?;
+        self.inner.flush()
+    }
+}
+
+/// Opens records produced by an `AeadWriter` with the matching key
+pub struct AeadReader<R: Read> {
+    inner: R,
+    cipher: Aes256Gcm,
+    counter: u64,
+    plain: Vec<u8>,
+    pos: usize,
+}
+
+impl<R: Read> AeadReader<R> {
+    pub fn new(inner: R, key: &[u8; 32]) -> Self {
+        AeadReader {
+            inner,
+            cipher: Aes256Gcm::new(key.into()),
+            counter: 0,
+            plain: Vec::with_capacity(RECORD_SIZE + TAG_LEN),
+            pos: 0,
+        }
+    }
+
+    /// Pull in the next record; false on a clean EOF between records
+    fn open_record(&mut self) -> io::Result<bool> {
+        let mut len = [0u8; 4];
+        match self.inner.read(&mut len[..1])? {
+            0 => return Ok(false),
+            _ => self.inner.read_exact(&mut len[1..])?,
+        }
+        let len = u32::from_be_bytes(len) as usize;
+        if !(TAG_LEN..=RECORD_SIZE + TAG_LEN).contains(&len) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Malformed encrypted record"));
+        }
+
+        self.plain.resize(len, 0);
+        self.inner.read_exact(&mut self.plain)?;
+        let nonce = nonce(self.counter);
+        self.counter += 1;
+        self.cipher
+            .decrypt_in_place(Nonce::from_slice(&nonce), b"", &mut self.plain)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Encrypted record failed authentication"))?;
+        self.pos = 0;
+        Ok(true)
+    }
+}
+
+impl<R: Read> Read for AeadReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.plain.len() {
+            if !self.open_record()? {
+                return Ok(0);
+            }
+        }
+        let n = std::cmp::min(buf.len(), self.plain.len() - self.pos);
+        buf[..n].copy_from_slice(&self.plain[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    const KEY: [u8; 32] = [7u8; 32];
+
+    fn seal(data: &[u8], chunk: usize) -> Vec<u8> {
+        let mut writer = AeadWriter::new(Vec::new(), &KEY);
+        for piece in data.chunks(chunk) {
+            writer.write_all(piece).unwrap();
+        }
+        writer.flush().unwrap();
+        writer.inner
+    }
+
+    fn open(sealed: Vec<u8>, key: &[u8; 32]) -> io::Result<Vec<u8>> {
+        let mut plain = Vec::new();
+        AeadReader::new(Cursor::new(sealed), key).read_to_end(&mut plain)?;
+        Ok(plain)
+    }
+
+    /// Offsets of each record's length prefix
+    fn records(sealed: &[u8]) -> Vec<usize> {
+        let mut offsets = Vec::new();
+        let mut pos = 0;
+        while pos < sealed.len() {
+            offsets.push(pos);
+            pos += 4 + u32::from_be_bytes(sealed[pos..pos + 4].try_into().unwrap()) as usize;
+        }
+        assert_eq!(pos, sealed.len());
+        offsets
+    }
+
+    #[test]
+    fn round_trip_across_records() {
+        let data: Vec<u8> = (0..RECORD_SIZE * 2 + 1234).map(|i| (i % 251) as u8).collect();
+        for chunk in [1000, RECORD_SIZE, RECORD_SIZE + 1] {
+            assert_eq!(open(seal(&data, chunk), &KEY).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn records_end_at_record_size_or_flush() {
+        assert_eq!(records(&seal(&[1u8; RECORD_SIZE], RECORD_SIZE)).len(), 1);
+        assert_eq!(records(&seal(&[1u8; RECORD_SIZE + 1], RECORD_SIZE + 1)).len(), 2);
+        assert!(seal(&[], 1).is_empty());
+
+        let mut writer = AeadWriter::new(Vec::new(), &KEY);
+        writer.write_all(b"one").unwrap();
+        writer.flush().unwrap();
+        writer.flush().unwrap();
+        writer.write_all(b"two").unwrap();
+        writer.flush().unwrap();
+        assert_eq!(records(&writer.inner), vec![0, 4 + 3 + TAG_LEN]);
+        assert_eq!(open(writer.inner, &KEY).unwrap(), b"onetwo");
+    }
+
+    #[test]
+    fn tampered_records_are_rejected() {
+        let mut sealed = seal(b"attack at dawn", 1024);
+        sealed[6] ^= 1;
+        assert_eq!(open(sealed, &KEY).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn wrong_key_is_rejected() {
+        let err = open(seal(b"secret", 1024), &[8u8; 32]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn reordered_records_are_rejected() {
+        let mut writer = AeadWriter::new(Vec::new(), &KEY);
+        writer.write_all(b"first").unwrap();
+        writer.flush().unwrap();
+        writer.write_all(b"second").unwrap();
+        writer.flush().unwrap();
+        let sealed = writer.inner;
+        let split = records(&sealed)[1];
+        let swapped = [&sealed[split..], &sealed[..split]].concat();
+        assert_eq!(open(swapped, &KEY).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn truncated_records_are_errors() {
+        let sealed = seal(b"cut short", 1024);
+        let err = open(sealed[..sealed.len() - 1].to_vec(), &KEY).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+
+        let mut oversized = ((RECORD_SIZE + TAG_LEN + 1) as u32).to_be_bytes().to_vec();
+        oversized.resize(64, 0);
+        assert_eq!(open(oversized, &KEY).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    fn channel(listener: &Listener, key: [u8; 32], encrypt: bool) -> TcpChannel {
+        TcpChannel { addr: SocketAddr::from((Ipv4Addr::LOCALHOST, listener.port())), key, encrypt }
+    }
+
+    #[test]
+    fn data_connections_need_the_key() {
+        for encrypt in [false, true] {
+            let (listener, key) = listen(encrypt).unwrap();
+            assert!(channel(&listener, key, encrypt).connect().is_ok());
+            assert!(channel(&listener, [0u8; 32], encrypt).connect().is_err());
+        }
+    }
+
+    #[test]
+    fn listener_stops_accepting_when_dropped() {
+        let (listener, key) = listen(true).unwrap();
+        let channel = channel(&listener, key, true);
+        drop(listener);
+
+        let deadline = std::time::Instant::now() + Duration::from_secs(5);
+        while channel.connect().is_ok() {
+            assert!(std::time::Instant::now() < deadline, "listener still accepting");
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+}
@@ -0,0 +1,608 @@
+use std::env;
+use std::io::Write;
+use std::path::Path;
+use crate::cancel::CancelToken;
+use crate::error::ZapError;
+use crate::progress::{Progress, ProgressSink, TransferEvent};
+use crate::ssh::{self, SessionConfig};
+use crate::transport::Transport;
+use crate::drivers::{
+    copy_direct, split_and_copy_between_remotes, split_and_copy_binary_file, split_and_copy_from_remote,
+    split_and_copy_local, split_and_copy_stdin, split_and_copy_to_stdout,
+};
+use crate::utils::{ExistingPolicy, MessageKind, Messages, Output, TransferOptions, TransferReport};
+
+fn parse_location(loc: &str) -> Option<(Option<(String, String)>, String)> {
+    // Check for Windows drive letter (C:\, D:\, etc.) - always local
+    if loc.len() >= 2 {
+        let chars: Vec<char> = loc.chars().collect();
+        // Could be Windows drive (C:\) or just happen to have : at position 1
+        // Check if followed by \ or / (Windows path separator)
+        if chars.len() >= 3 && chars[0].is_ascii_alphabetic() && chars[1] == ':' && (chars[2] == '\\' || chars[2] == '/') {
+            return Some((None, loc.to_string()));
+        }
+    }
+
+    // Check for UNC path (\\server\share) - always local on Windows
+    if loc.starts_with("\\\\") || loc.starts_with("//") {
+        return Some((None, loc.to_string()));
+    }
+
+    // Check for IPv6 literal: [user@][IPv6]:path
+    if let Some(bracket_start) = loc.find('[') {
+        if let Some(bracket_end) = loc.find(']') {
+            if bracket_end > bracket_start {
+                let before_bracket = &loc[..bracket_start];
+                let ipv6_host = &loc[bracket_start + 1..bracket_end];
+                let after_bracket = &loc[bracket_end + 1..];
+
+                // after_bracket should be ":path" or empty
+                if after_bracket.is_empty() || after_bracket.starts_with(':') {
+                    let path = if after_bracket.is_empty() {
+                        ".".to_string()
+                    } else {
+                        let p = &after_bracket[1..];
+                        if p.is_empty() { "." } else { p }.to_string()
+                    };
+
+                    // Extract user from before_bracket
+                    let user = if before_bracket.is_empty() {
+                        env::var("USER").ok()?
+                    } else if before_bracket.ends_with('@') {
+                        let u = before_bracket.trim_end_matches('@');
+                        if u.is_empty() {
+                            return None; // Empty user
+                        }
+                        u.to_string()
+                    } else {
+                        return None; // Invalid format
+                    };
+
+                    return Some((Some((user, ipv6_host.to_string())), path));
+                }
+            }
+        }
+    }
+
+    // Standard parsing: [user@]host:path
+    if loc.contains(':') {
+        let parts: Vec<&str> = loc.splitn(2, ':').collect();
+        if parts.len() != 2 {
+            return None;
+        }
+
+        let user_host = parts[0];
+        let path = if parts[1].is_empty() {
+            ".".to_string()
+        } else {
+            parts[1].to_string()
+        };
+
+        // IPv6 addresses need their brackets: a stray bracket, or a hex
+        // "host" with the rest of the address in the path, is a mistake
+        let host = user_host.rsplit('@').next().unwrap_or_default();
+        if host.contains(['[', ']']) || (host.chars().all(|c| c.is_ascii_hexdigit()) && parts[1].contains("::")) {
+            return None;
+        }
+
+        // Disallow empty user with @ present
+        if user_host.starts_with('@') {
+            return None;
+        }
+
+        // Disallow empty host with @ present
+        if user_host.ends_with('@') {
+            return None;
+        }
+
+        let host_parts: Vec<&str> = user_host.split('@').collect();
+        match host_parts.as_slice() {
+            [user, host] => {
+                if host.is_empty() {
+                    return None;
+                }
+                Some((Some((user.to_string(), host.to_string())), path))
+            }
+            [host] => {
+                if host.is_empty() {
+                    return None;
+                }
+                let user = env::var("USER").ok()?;
+                Some((Some((user, host.to_string())), path))
+            }
+            _ => None,
+        }
+    } else {
+        // No colon = local path
+        Some((None, loc.to_string()))
+    }
+}
+
+/// Local path meaning stdin as the source or stdout as the destination
+const STDIO_PATH: &str = "-";
+
+/// Options that need a real local file; they can't be used with `-`
+const FILE_ONLY_OPTIONS: &[&str] = &[
+    "--delta", "--sparse", "--preallocate", "--preserve", "--update", "--skip-identical",
+];
+
+/// Options that only make sense with a remote end
+const LOCAL_UNSUPPORTED_OPTIONS: &[&str] = &["--delta", "--compress"];
+
+/// Options that need a local file at one end; they can't be relayed
+const RELAY_UNSUPPORTED_OPTIONS: &[&str] = &["--delta", "--sparse"];
+
+fn validate_paths(source: &str, destination: &str, mkdir: bool) -> Result<(), ZapError> {
+    let (source_remote, source_path) = parse_location(source)
+        .ok_or_else(|| ZapError::Usage("Invalid source format. Expected either a local path or user@host:path".to_string()))?;
+
+    let (dest_remote, dest_path) = parse_location(destination)
+        .ok_or_else(|| ZapError::Usage("Invalid destination format. Expected either a local path or user@host:path".to_string()))?;
+
+    match (source_remote.is_some(), dest_remote.is_some()) {
+        (true, true) => Ok(()),
+        (false, false) if source_path == STDIO_PATH || dest_path == STDIO_PATH => {
+            Err(ZapError::Usage("Streaming with - needs a remote location at the other end".to_string()))
+        },
+        _ => {
+            // For local paths, verify they exist and are valid; `-` is
+            // stdin or stdout
+            if source_remote.is_none() && source_path != STDIO_PATH {
+                let path = Path::new(&source_path);
+                if !path.exists() {
+                    return Err(ZapError::NotFound { path: path.to_path_buf() });
+                }
+                if !path.is_file() {
+                    return Err(ZapError::Usage(format!("Source path '{}' is not a file", source_path)));
+                }
+            }
+
+            // A bare word that isn't a file yet is more likely a host whose
+            // colon was forgotten: always when it has a user@ part, and in a
+            // local copy, where nothing else is remote, always
+            if dest_remote.is_none() && dest_path != STDIO_PATH && !dest_path.contains(['/', '\\']) && !Path::new(&dest_path).exists()
+                && (dest_path.contains('@') || source_remote.is_none())
+            {
+                return Err(ZapError::Usage(format!(
+                    "'{}' has no ':', so it would be a local file; write {}: for the remote home directory or ./{} for a local file",
+                    dest_path, dest_path, dest_path,
+                )));
+            }
+
+            // Like scp: an existing directory receives the file under its
+            // own name, anything else is the target file name
+            if dest_remote.is_none() && dest_path != STDIO_PATH && !mkdir {
+                let path = Path::new(&dest_path);
+                if !path.is_dir() {
+                    if dest_path.ends_with('/') || dest_path.ends_with('\\') {
+                        return Err(ZapError::NotFound { path: path.to_path_buf() });
+                    }
+                    let parent = path.parent().filter(|p| !p.as_os_str().is_empty()).unwrap_or(Path::new("."));
+                    if !parent.is_dir() {
+                        return Err(ZapError::NotFound { path: parent.to_path_buf() });
+                    }
+                }
+            }
+
+            Ok(())
+        }
+    }
+}
+
+/// Everything needed to run one transfer. Locations are written as on the
+/// command line: a local path, `[user@]host:path`, or `-` for stdin or
+/// stdout. Defaults match the CLI's.
+#[derive(Debug, Clone)]
+pub struct TransferBuilder {
+    source: String,
+    destination: String,
+    port: u16,
+    key_path: Option<String>,
+    retries: u32,
+    remote_zap: String,
+    use_helper: bool,
+    ciphers: Option<String>,
+    macs: Option<String>,
+    compress: bool,
+    direct: bool,
+    direct_output: Output,
+    direct_json: bool,
+    direct_json_progress: bool,
+    opts: TransferOptions,
+}
+
+impl TransferBuilder {
+    pub fn new(source: &str, destination: &str) -> Self {
+        TransferBuilder {
+            source: source.to_string(),
+            destination: destination.to_string(),
+            port: 22,
+            key_path: None,
+            retries: 3,
+            remote_zap: "zap".to_string(),
+            use_helper: true,
+            ciphers: None,
+            macs: None,
+            compress: false,
+            direct: false,
+            direct_output: Output::default(),
+            direct_json: false,
+            direct_json_progress: false,
+            opts: TransferOptions {
+                quiet: false,
+                num_streams: 20,
+                transport: Transport::Ssh,
+                verbose: false,
+                preserve: false,
+                mkdir: false,
+                existing: ExistingPolicy::Overwrite,
+                delta: false,
+                sparse: false,
+                preallocate: false,
+                force: false,
+                fsync: true,
+                messages: Messages::default(),
+                progress: Progress::default(),
+                cancel: CancelToken::default(),
+                cipher: None,
+            },
+        }
+    }
+
+    /// Number of parallel streams
+    pub fn streams(mut self, streams: usize) -> Self {
+        self.opts.num_streams = streams;
+        self
+    }
+
+    /// How often each stream reconnects and resumes after an error
+    pub fn retries(mut self, retries: u32) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    /// SSH port on the remote end(s)
+    pub fn port(mut self, port: u16) -> Self {
+        self.port = port;
+        self
+    }
+
+    /// Private key tried before the default keys and the SSH agent
+    pub fn key_path(mut self, key_path: &str) -> Self {
+        self.key_path = Some(key_path.to_string());
+        self
+    }
+
+    /// Remote zap binary to run as a helper, and by `direct`
+    pub fn remote_zap(mut self, command: &str) -> Self {
+        self.remote_zap = command.to_string();
+        self
+    }
+
+    /// Use the remote zap helper when it is there; without it, plain SFTP
+    pub fn use_helper(mut self, use_helper: bool) -> Self {
+        self.use_helper = use_helper;
+        self
+    }
+
+    /// SSH cipher preference, comma separated
+    pub fn ciphers(mut self, ciphers: &str) -> Self {
+        self.ciphers = Some(ciphers.to_string());
+        self
+    }
+
+    /// SSH MAC preference, comma separated
+    pub fn macs(mut self, macs: &str) -> Self {
+        self.macs = Some(macs.to_string());
+        self
+    }
+
+    /// zlib compression on the SSH sessions
+    pub fn compress(mut self, compress: bool) -> Self {
+        self.compress = compress;
+        self
+    }
+
+    pub fn transport(mut self, transport: Transport) -> Self {
+        self.opts.transport = transport;
+        self
+    }
+
+    /// Between two remotes, run zap on the source host instead of relaying
+    /// through this machine
+    pub fn direct(mut self, direct: bool) -> Self {
+        self.direct = direct;
+        self
+    }
+
+    /// Receives the output of the zap that `direct` runs, as it comes;
+    /// without a writer it is dropped
+    pub fn direct_output<W: Write + Send + 'static>(mut self, out: W) -> Self {
+        self.direct_output = Output::new(out);
+        self
+    }
+
+    /// Run the zap that `direct` runs with --json. Its report comes back
+    /// in `TransferReport::remote`, or its error in
+    /// `ZapError::RemoteExit`, instead of in `direct_output`; any other
+    /// text it prints goes to the message callback.
+    pub fn direct_json(mut self, json: bool) -> Self {
+        self.direct_json = json;
+        self
+    }
+
+    /// Run the zap that `direct` runs with --json-progress, so
+    /// `direct_output` only gets its newline-delimited JSON progress
+    pub fn direct_json_progress(mut self, json_progress: bool) -> Self {
+        self.direct_json_progress = json_progress;
+        self
+    }
+
+    pub fn preserve(mut self, preserve: bool) -> Self {
+        self.opts.preserve = preserve;
+        self
+    }
+
+    pub fn mkdir(mut self, mkdir: bool) -> Self {
+        self.opts.mkdir = mkdir;
+        self
+    }
+
+    pub fn existing(mut self, existing: ExistingPolicy) -> Self {
+        self.opts.existing = existing;
+        self
+    }
+
+    pub fn delta(mut self, delta: bool) -> Self {
+        self.opts.delta = delta;
+        self
+    }
+
+    pub fn sparse(mut self, sparse: bool) -> Self {
+        self.opts.sparse = sparse;
+        self
+    }
+
+    pub fn preallocate(mut self, preallocate: bool) -> Self {
+        self.opts.preallocate = preallocate;
+        self
+    }
+
+    /// Go ahead even if the destination doesn't seem to have enough space
+    pub fn force(mut self, force: bool) -> Self {
+        self.opts.force = force;
+        self
+    }
+
+    /// Flush the destination to disk before reporting success
+    pub fn fsync(mut self, fsync: bool) -> Self {
+        self.opts.fsync = fsync;
+        self
+    }
+
+    /// No informational messages
+    pub fn quiet(mut self, quiet: bool) -> Self {
+        self.opts.quiet = quiet;
+        self
+    }
+
+    /// Also report negotiated SSH algorithms and other details
+    pub fn verbose(mut self, verbose: bool) -> Self {
+        self.opts.verbose = verbose;
+        self
+    }
+
+    /// Receives the transfer's messages as they happen; without a callback
+    /// they are dropped
+    pub fn on_message<F>(mut self, callback: F) -> Self
+    where
+        F: Fn(MessageKind, &str) + Send + Sync + 'static,
+    {
+        self.opts.messages = Messages::new(callback);
+        self
+    }
+
+    /// Receives the streams' progress as they run, e.g. an
+    /// `IndicatifProgress` or the sending end of a channel; without a sink
+    /// nothing is reported
+    pub fn progress<S: ProgressSink + 'static>(mut self, sink: S) -> Self {
+        self.opts.progress = Progress::new(sink);
+        self
+    }
+
+    /// Stops the transfer when `token` is cancelled. A pull or push that
+    /// uses the remote helper keeps its partial temp file for a later
+    /// `delta` run; other transfers remove it.
+    pub fn cancel(mut self, token: CancelToken) -> Self {
+        self.opts.cancel = token;
+        self
+    }
+
+    /// The first of `options` that is set, by its CLI name
+    fn find_option(&self, options: &[&str]) -> Option<&'static str> {
+        let opts = &self.opts;
+        [
+            (opts.delta, "--delta"),
+            (opts.sparse, "--sparse"),
+            (opts.preallocate, "--preallocate"),
+            (opts.preserve, "--preserve"),
+            (opts.existing == ExistingPolicy::Update, "--update"),
+            (opts.existing == ExistingPolicy::SkipIdentical, "--skip-identical"),
+            (self.compress, "--compress"),
+        ]
+        .into_iter()
+        .find(|(set, flag)| *set && options.contains(flag))
+        .map(|(_, flag)| flag)
+    }
+
+    /// Command line for the zap that `direct` runs on the source host: a
+    /// plain push of `source_path` (local there) to the destination. The
+    /// SSH key stays behind, since it is a path on this machine.
+    fn direct_args(&self, source_path: &str, user: &str, host: &str, dest_path: &str) -> Vec<String> {
+        let opts = &self.opts;
+        let flags = [
+            (opts.quiet, "--quiet"),
+            (opts.verbose, "--verbose"),
+            (self.compress, "--compress"),
+            (opts.mkdir, "--mkdir"),
+            (opts.existing == ExistingPolicy::NoClobber, "--no-clobber"),
+            (opts.existing == ExistingPolicy::Update, "--update"),
+            (opts.existing == ExistingPolicy::SkipIdentical, "--skip-identical"),
+            (opts.delta, "--delta"),
+            (opts.sparse, "--sparse"),
+            (opts.preallocate, "--preallocate"),
+            (opts.force, "--force"),
+            (!opts.fsync, "--no-fsync"),
+            (opts.preserve, "--preserve"),
+            (!self.use_helper, "--no-helper"),
+            (self.direct_json, "--json"),
+            (self.direct_json_progress, "--json-progress"),
+            (opts.transport == Transport::Tcp { encrypt: false }, "--unencrypted"),
+        ];
+        let mut args: Vec<String> = flags.iter()
+            .filter(|(set, _)| *set)
+            .map(|(_, flag)| flag.to_string())
+            .collect();
+        let transport = match opts.transport {
+            Transport::Tcp { .. } => "tcp",
+            Transport::Quic { .. } => "quic",
+            Transport::Ssh | Transport::Local => "ssh",
+        };
+        let mut values = vec![
+            ("--streams", opts.num_streams.to_string()),
+            ("--retries", self.retries.to_string()),
+            ("--port", self.port.to_string()),
+            ("--remote-zap", self.remote_zap.clone()),
+            ("--transport", transport.to_string()),
+        ];
+        if let Some(ref ciphers) = self.ciphers {
+            values.push(("--cipher", ciphers.clone()));
+        }
+        if let Some(ref macs) = self.macs {
+            values.push(("--mac", macs.clone()));
+        }
+        if let Transport::Quic { congestion } = opts.transport {
+            values.push(("--congestion", congestion.name().to_string()));
+        }
+        for (flag, value) in values {
+            args.push(flag.to_string());
+            args.push(value);
+        }
+        // Spell out the user, since the source host's $USER may differ
+        let destination = if host.contains(':') {
+            format!("{}@[{}]:{}", user, host, dest_path)
+        } else {
+            format!("{}@{}:{}", user, host, dest_path)
+        };
+        args.push("--".to_string());
+        args.push(source_path.to_string());
+        args.push(destination);
+        args
+    }
+
+    fn session_config(&self, user: String, host: String) -> SessionConfig {
+        SessionConfig {
+            host,
+            port: self.port,
+            user,
+            key_path: self.key_path.clone(),
+            retries: self.retries,
+            helper: self.use_helper.then(|| self.remote_zap.clone()),
+            data_channel: None,
+            ciphers: self.ciphers.clone(),
+            macs: self.macs.clone(),
+            compress: self.compress,
+            messages: self.opts.messages.clone(),
+        }
+    }
+
+    /// Check the options against each other and the locations, then run the
+    /// transfer to completion
+    pub fn run(&self) -> Result<TransferReport, ZapError> {
+        let invalid = ZapError::Usage;
+
+        validate_paths(&self.source, &self.destination, self.opts.mkdir)?;
+        let (source_remote, source_path) = parse_location(&self.source).unwrap();
+        let (dest_remote, dest_path) = parse_location(&self.destination).unwrap();
+
+        if self.opts.num_streams == 0 {
+            return Err(invalid("streams must be at least 1".to_string()));
+        }
+        if self.port == 0 {
+            return Err(invalid("port must be between 1 and 65535".to_string()));
+        }
+        ssh::validate_methods(self.ciphers.as_deref(), self.macs.as_deref()).map_err(|e| invalid(e.to_string()))?;
+
+        let streaming = (source_remote.is_none() && source_path == STDIO_PATH)
+            || (dest_remote.is_none() && dest_path == STDIO_PATH);
+        if streaming {
+            if let Some(flag) = self.find_option(FILE_ONLY_OPTIONS) {
+                return Err(invalid(format!("{} can't be used when streaming from stdin or to stdout", flag)));
+            }
+        }
+
+        let relay = source_remote.is_some() && dest_remote.is_some();
+        if self.direct && !relay {
+            return Err(invalid("--direct only applies to remote-to-remote transfers".to_string()));
+        }
+        if relay && !self.direct {
+            if let Some(flag) = self.find_option(RELAY_UNSUPPORTED_OPTIONS) {
+                return Err(invalid(format!("{} can't be used when relaying between two remotes (try --direct)", flag)));
+            }
+        }
+        if self.opts.delta && !self.use_helper {
+            return Err(invalid("--delta needs zap on the remote host, so it can't be used with --no-helper".to_string()));
+        }
+
+        let mut opts = self.opts.clone();
+        opts.progress = opts.progress.restart();
+        let local_copy = source_remote.is_none() && dest_remote.is_none();
+        if local_copy {
+            if let Some(flag) = self.find_option(LOCAL_UNSUPPORTED_OPTIONS) {
+                return Err(invalid(format!("{} only applies to transfers with a remote end", flag)));
+            }
+            if opts.transport != Transport::Ssh {
+                return Err(invalid("--transport only applies to transfers with a remote end".to_string()));
+            }
+            opts.transport = Transport::Local;
+        }
+
+        let result = match (source_remote, dest_remote) {
+            (Some((src_user, src_host)), Some((dest_user, dest_host))) if self.direct => {
+                // Remote to remote, run on the source host
+                let args = self.direct_args(&source_path, &dest_user, &dest_host, &dest_path);
+                let cfg = self.session_config(src_user, src_host);
+                let json = self.direct_json || self.direct_json_progress;
+                copy_direct(&self.remote_zap, &args, json, &cfg, &opts, &self.direct_output, self.source.clone(), self.destination.clone())
+            },
+            (Some((src_user, src_host)), Some((dest_user, dest_host))) => {
+                // Remote to remote, relayed through this machine
+                let src_cfg = self.session_config(src_user, src_host);
+                let dest_cfg = self.session_config(dest_user, dest_host);
+                split_and_copy_between_remotes(&source_path, &src_cfg, &dest_path, &dest_cfg, &opts)
+            },
+            (Some((remote_user, remote_host)), None) if dest_path == STDIO_PATH => {
+                // Pull transfer to stdout
+                split_and_copy_to_stdout(&source_path, &self.session_config(remote_user, remote_host), &opts)
+            },
+            (None, Some((remote_user, remote_host))) if source_path == STDIO_PATH => {
+                // Push transfer from stdin
+                split_and_copy_stdin(&dest_path, &self.session_config(remote_user, remote_host), &opts)
+            },
+            (Some((remote_user, remote_host)), None) => {
+                // Pull transfer
+                split_and_copy_from_remote(&source_path, &dest_path, &self.session_config(remote_user, remote_host), &opts)
+            },
+            (None, Some((remote_user, remote_host))) => {
+                // Push transfer
+                split_and_copy_binary_file(&source_path, &dest_path, &self.session_config(remote_user, remote_host), &opts)
+            },
+            (None, None) => {
+                // Local copy, e.g. between two network filesystem mounts
+                split_and_copy_local(&source_path, &dest_path, &opts)
+            },
+        };
+        opts.progress.send(TransferEvent::Done);
+        Ok(result?)
+    }
+}
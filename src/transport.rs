@@ -0,0 +1,116 @@
+use std::io;
+use crate::protocol::HelperClient;
+use crate::tcp::TcpChannel;
+#[cfg(feature = "quic")]
+use crate::quic::QuicChannel;
+
+/// Congestion controller for the QUIC transport
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Congestion {
+    Cubic,
+    NewReno,
+    Bbr,
+}
+
+impl Congestion {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "cubic" => Some(Congestion::Cubic),
+            "newreno" => Some(Congestion::NewReno),
+            "bbr" => Some(Congestion::Bbr),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Congestion::Cubic => "cubic",
+            Congestion::NewReno => "newreno",
+            Congestion::Bbr => "bbr",
+        }
+    }
+
+    /// Wire encoding used in QUIC_LISTEN
+    #[cfg(feature = "quic")]
+    pub fn to_u8(self) -> u8 {
+        match self {
+            Congestion::Cubic => 0,
+            Congestion::NewReno => 1,
+            Congestion::Bbr => 2,
+        }
+    }
+
+    pub fn from_u8(v: u8) -> io::Result<Self> {
+        match v {
+            0 => Ok(Congestion::Cubic),
+            1 => Ok(Congestion::NewReno),
+            2 => Ok(Congestion::Bbr),
+            _ => Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Unknown congestion controller {}", v))),
+        }
+    }
+}
+
+/// How bulk data moves once SSH has authenticated us
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transport {
+    /// Every stream is its own SSH session (helper exec channel or SFTP)
+    Ssh,
+    /// Raw TCP connections to the remote helper, optionally AES-256-GCM sealed
+    Tcp { encrypt: bool },
+    /// One QUIC connection to the remote helper carrying a stream per segment
+    Quic { congestion: Congestion },
+    /// Both ends are on this machine; streams copy between local files
+    Local,
+}
+
+impl Transport {
+    pub fn describe(&self) -> String {
+        match self {
+            Transport::Ssh => "ssh".to_string(),
+            Transport::Tcp { encrypt: true } => "tcp (AES-256-GCM)".to_string(),
+            Transport::Tcp { encrypt: false } => "tcp (unencrypted)".to_string(),
+            Transport::Quic { congestion } => format!("quic ({})", congestion.name()),
+            Transport::Local => "local".to_string(),
+        }
+    }
+}
+
+/// A data path to the remote helper negotiated over an SSH control connection
+#[derive(Debug, Clone)]
+pub enum DataChannel {
+    Tcp(TcpChannel),
+    #[cfg(feature = "quic")]
+    Quic(QuicChannel),
+}
+
+impl DataChannel {
+    /// Ask the helper on `control` to open a listener for `transport`.
+    /// `control` must stay alive for as long as the data channel is used.
+    pub fn negotiate(control: &mut HelperClient, host: &str, transport: Transport) -> io::Result<Option<Self>> {
+        match transport {
+            Transport::Ssh | Transport::Local => Ok(None),
+            Transport::Tcp { encrypt } => TcpChannel::negotiate(control, host, encrypt).map(|c| Some(DataChannel::Tcp(c))),
+            #[cfg(feature = "quic")]
+            Transport::Quic { congestion } => QuicChannel::negotiate(control, host, congestion).map(|c| Some(DataChannel::Quic(c))),
+            #[cfg(not(feature = "quic"))]
+            Transport::Quic { .. } => Err(io::Error::new(io::ErrorKind::Unsupported, "zap was built without QUIC support")),
+        }
+    }
+
+    /// Start a helper conversation for one stream
+    pub fn connect(&self) -> io::Result<HelperClient> {
+        match self {
+            DataChannel::Tcp(channel) => channel.connect(),
+            #[cfg(feature = "quic")]
+            DataChannel::Quic(channel) => channel.connect(),
+        }
+    }
+
+    pub fn port(&self) -> u16 {
+        match self {
+            DataChannel::Tcp(channel) => channel.addr.port(),
+            #[cfg(feature = "quic")]
+            DataChannel::Quic(channel) => channel.addr.port(),
+        }
+    }
+}
@@ -1,16 +1,164 @@
 use std::sync::{Arc, Mutex};
-use std::thread;
-use std::fs;
-use std::io;
-use std::path::Path;
-use std::time::Instant;
-use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
-use crate::ssh::{SessionConfig, connect_and_auth, open_sftp, stat_remote_file, extend_remote_file, pull_worker, push_worker};
-
-struct TransferStats {
-    start_time: Instant,
-    total_bytes: usize,
-    streams_completed: usize,
+use std::fmt;
+use std::io::{self, Write};
+use std::time::Duration;
+use crate::cancel::CancelToken;
+use crate::json;
+use crate::progress::{Progress, StreamReport};
+use crate::protocol::RemoteStat;
+use crate::transport::Transport;
+
+/// Knobs shared by push and pull transfers
+#[derive(Debug, Clone)]
+pub struct TransferOptions {
+    /// No informational messages
+    pub quiet: bool,
+    pub num_streams: usize,
+    pub transport: Transport,
+    pub verbose: bool,
+    /// Carry mode bits and times over, plus ownership when allowed to
+    pub preserve: bool,
+    /// Create missing destination directories
+    pub mkdir: bool,
+    pub existing: ExistingPolicy,
+    /// Only send blocks that differ from what the destination already has
+    pub delta: bool,
+    /// Leave all-zero blocks unwritten so the destination stays sparse
+    pub sparse: bool,
+    /// Reserve the destination's disk space before any data moves
+    pub preallocate: bool,
+    /// Go ahead even if the destination doesn't seem to have enough space
+    pub force: bool,
+    /// Flush the destination to disk before reporting success
+    pub fsync: bool,
+    /// Where informational lines, warnings and stream errors go
+    pub messages: Messages,
+    /// Where the streams report their progress
+    pub progress: Progress,
+    /// Stops the streams when cancelled
+    pub cancel: CancelToken,
+    /// SSH cipher the first session negotiated, filled in once connected
+    pub cipher: Option<String>,
+}
+
+/// What to do when the destination file already exists
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExistingPolicy {
+    /// Replace it
+    Overwrite,
+    /// Never replace it
+    NoClobber,
+    /// Replace it only if the source is newer
+    Update,
+    /// Leave it if size and mtime, or size and BLAKE3 hash, match
+    SkipIdentical,
+}
+
+/// What a transfer message is about
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageKind {
+    /// What the transfer is doing
+    Info,
+    /// Something was downgraded or left out; the transfer carries on
+    Warning,
+    /// A stream failed, and with it the transfer
+    Error,
+}
+
+type MessageCallback = dyn Fn(MessageKind, &str) + Send + Sync;
+
+/// Callback for transfer messages; without one they are dropped
+#[derive(Clone, Default)]
+pub struct Messages(Option<Arc<MessageCallback>>);
+
+impl Messages {
+    pub fn new<F>(callback: F) -> Self
+    where
+        F: Fn(MessageKind, &str) + Send + Sync + 'static,
+    {
+        Messages(Some(Arc::new(callback)))
+    }
+
+    fn send(&self, kind: MessageKind, msg: &str) {
+        if let Some(ref callback) = self.0 {
+            callback(kind, msg);
+        }
+    }
+
+    pub(crate) fn info(&self, msg: &str) {
+        self.send(MessageKind::Info, msg);
+    }
+
+    pub(crate) fn warn(&self, msg: &str) {
+        self.send(MessageKind::Warning, msg);
+    }
+
+    pub(crate) fn error(&self, msg: &str) {
+        self.send(MessageKind::Error, msg);
+    }
+}
+
+impl fmt::Debug for Messages {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(if self.0.is_some() { "Messages(callback)" } else { "Messages(none)" })
+    }
+}
+
+/// Shared writer for output that comes from elsewhere as it is, such as
+/// a remote zap's; without one it is dropped
+#[derive(Clone, Default)]
+pub struct Output(Option<Arc<Mutex<dyn Write + Send>>>);
+
+impl Output {
+    pub fn new<W: Write + Send + 'static>(out: W) -> Self {
+        Output(Some(Arc::new(Mutex::new(out))))
+    }
+}
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.0 {
+            Some(ref out) => out.lock().unwrap().write(buf),
+            None => Ok(buf.len()),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.0 {
+            Some(ref out) => out.lock().unwrap().flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl fmt::Debug for Output {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(if self.0.is_some() { "Output(writer)" } else { "Output(none)" })
+    }
+}
+
+/// Why an existing destination should be left alone, if it should.
+/// `same_hash` is only asked when sizes match but mtimes differ, and may
+/// answer None when hashing the remote side is not practical.
+pub(crate) fn skip_reason<F>(policy: ExistingPolicy, src: &RemoteStat, dst: Option<RemoteStat>, same_hash: F) -> io::Result<Option<&'static str>>
+where
+    F: FnOnce() -> io::Result<Option<bool>>,
+{
+    let Some(dst) = dst else {
+        return Ok(None);
+    };
+    Ok(match policy {
+        ExistingPolicy::Overwrite => None,
+        ExistingPolicy::NoClobber => Some("destination exists"),
+        ExistingPolicy::Update if dst.mtime >= src.mtime => Some("destination is not older than source"),
+        ExistingPolicy::Update => None,
+        ExistingPolicy::SkipIdentical if dst.size != src.size => None,
+        ExistingPolicy::SkipIdentical if dst.mtime == src.mtime => Some("same size and modification time"),
+        ExistingPolicy::SkipIdentical => match same_hash()? {
+            Some(true) => Some("same size and BLAKE3 hash"),
+            _ => None,
+        },
+    })
 }
 
 fn format_speed(bytes_per_second: f64) -> String {
@@ -25,7 +173,7 @@ fn format_speed(bytes_per_second: f64) -> String {
     }
 }
 
-fn format_size(bytes: usize) -> String {
+pub(crate) fn format_size(bytes: usize) -> String {
     if bytes >= 1_000_000_000 {
         format!("{:.2} GB", bytes as f64 / 1_000_000_000.0)
     } else if bytes >= 1_000_000 {
@@ -37,319 +185,202 @@ fn format_size(bytes: usize) -> String {
     }
 }
 
-fn print_transfer_stats(stats: &TransferStats, num_streams: usize) {
-    let duration = stats.start_time.elapsed();
-    let duration_secs = duration.as_secs() as f64 + duration.subsec_nanos() as f64 * 1e-9;
-    let speed = stats.total_bytes as f64 / duration_secs;
-
-    println!("\nTransfer Statistics");
-    println!("Total Size:    {}", format_size(stats.total_bytes));
-    println!("Streams:       {}", num_streams);
-    println!("Duration:      {:.2} seconds", duration_secs);
-    println!("Average Speed: {}", format_speed(speed));
+/// How a transfer went
+#[derive(Debug, Clone)]
+pub struct TransferReport {
+    pub source: String,
+    pub destination: String,
+    pub outcome: TransferOutcome,
+    /// Size of the file
+    pub total_bytes: u64,
+    /// Payload bytes that actually crossed the network
+    pub bytes_sent: u64,
+    pub duration: Duration,
+    pub streams: usize,
+    /// Transport the data went over, after any fallback to SSH
+    pub transport: Transport,
+    /// Bytes never sent because they were holes in the source
+    pub holes_skipped: u64,
+    /// Bytes of zero blocks left as holes at the destination, for sparse transfers
+    pub zeros_skipped: Option<u64>,
+    /// Only blocks that differed were sent
+    pub delta: bool,
+    /// Per stream (payload bytes, bytes on the wire), when compression was metered
+    pub wire_bytes: Vec<Option<(u64, u64)>>,
+    /// What each stream did
+    pub stream_reports: Vec<StreamReport>,
+    /// Fastest throughput over one second, in bytes per second; the
+    /// average for shorter transfers
+    pub peak_speed: f64,
+    /// SSH cipher negotiated with the (first) remote host
+    pub cipher: Option<String>,
+    /// The JSON report of the zap that ran a `direct` transfer, when it
+    /// was asked for one
+    pub remote: Option<String>,
 }
 
-/// Pull transfer: remote → local using SFTP
-pub fn split_and_copy_from_remote(
-    quiet_mode: bool,
-    remote_file: &str,
-    num_streams: usize,
-    remote_user: &str,
-    remote_host: &str,
-    local_path: &str,
-    ssh_key_path: Option<&str>,
-    retries: u32,
-    ssh_port: u16,
-) -> io::Result<()> {
-    if !quiet_mode {
-        println!("Preparing to transfer {}...", remote_file);
-    }
-
-    // Create session config
-    let cfg = SessionConfig {
-        host: remote_host.to_string(),
-        port: ssh_port,
-        user: remote_user.to_string(),
-        key_path: ssh_key_path.map(|s| s.to_string()),
-        retries,
-    };
-
-    // Get remote file size
-    let file_size = {
-        let sess = connect_and_auth(&cfg)?;
-        let sftp = open_sftp(&sess)?;
-        stat_remote_file(&sftp, remote_file)?
-    };
+/// Whether a transfer moved any data
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TransferOutcome {
+    /// The file was copied and moved into place
+    Completed,
+    /// The existing destination was left alone, for the given reason
+    Skipped(String),
+    /// zap on the source host did the transfer (`--direct`) and reported on
+    /// its own output, or in `remote`
+    Delegated,
+}
 
-    let stats = Arc::new(Mutex::new(TransferStats {
-        start_time: Instant::now(),
-        total_bytes: file_size as usize,
-        streams_completed: 0,
-    }));
-
-    if !quiet_mode {
-        println!("Remote file size: {} ({})", format_size(file_size as usize), file_size);
-        let stream_size = file_size / num_streams as u64;
-        println!("Using {} streams of approximately {} each",
-                 num_streams,
-                 format_size(stream_size as usize));
-        let extra_bytes = file_size % num_streams as u64;
-        if extra_bytes > 0 {
-            println!("Last stream will have an additional {} bytes", extra_bytes);
+impl TransferReport {
+    pub(crate) fn skipped(opts: &TransferOptions, source: String, destination: String, reason: &str) -> Self {
+        TransferReport {
+            source,
+            destination,
+            outcome: TransferOutcome::Skipped(reason.to_string()),
+            total_bytes: 0,
+            bytes_sent: 0,
+            duration: Duration::ZERO,
+            streams: 0,
+            transport: opts.transport,
+            holes_skipped: 0,
+            zeros_skipped: None,
+            delta: false,
+            wire_bytes: Vec::new(),
+            stream_reports: Vec::new(),
+            peak_speed: 0.0,
+            cipher: opts.cipher.clone(),
+            remote: None,
         }
-        println!("Initializing transfer...");
     }
 
-    // Determine output file path
-    let file_name = Path::new(remote_file)
-        .file_name()
-        .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "Invalid remote file path"))?
-        .to_str()
-        .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "Invalid file name"))?;
-    let output_path = Path::new(local_path).join(file_name);
-
-    // Create local file and extend to full size (sparse)
-    let local_file = fs::OpenOptions::new()
-        .create(true)
-        .write(true)
-        .read(true)
-        .open(&output_path)?;
-    local_file.set_len(file_size)?;
-    let local_file = Arc::new(local_file);
-
-    // Calculate segments
-    let stream_size = file_size / num_streams as u64;
-    let extra_bytes = file_size % num_streams as u64;
-
-    // Setup progress bars
-    let m = if !quiet_mode {
-        MultiProgress::new()
-    } else {
-        MultiProgress::with_draw_target(indicatif::ProgressDrawTarget::hidden())
-    };
-    let style = ProgressStyle::with_template(
-        "[{elapsed_precise}] {bar:40.cyan/blue} {pos:>7}/{len:7} {msg}",
-    )
-    .unwrap()
-    .progress_chars("##-");
-
-    let retry_flag = Arc::new(Mutex::new(vec![false; num_streams]));
-    let mut handles = Vec::with_capacity(num_streams);
-
-    // Spawn worker threads
-    for stream_num in 0..num_streams {
-        let cfg_clone = cfg.clone();
-        let remote_file = remote_file.to_string();
-        let local_file_clone = Arc::clone(&local_file);
-        let retry_flag_clone = Arc::clone(&retry_flag);
-        let stats_clone = Arc::clone(&stats);
-
-        let start = stream_num as u64 * stream_size;
-        let mut end = start + stream_size;
-        if stream_num == num_streams - 1 {
-            end += extra_bytes;
+    pub(crate) fn delegated(opts: &TransferOptions, source: String, destination: String, duration: Duration, remote: Option<String>) -> Self {
+        TransferReport {
+            outcome: TransferOutcome::Delegated,
+            duration,
+            remote,
+            ..TransferReport::skipped(opts, source, destination, "")
         }
-        let segment_len = end - start;
-
-        let pb = m.add(ProgressBar::new(segment_len));
-        pb.set_style(style.clone());
-        pb.set_message(format!("Stream {}", stream_num));
-
-        let handle = thread::spawn(move || {
-            match pull_worker(
-                stream_num,
-                start,
-                end,
-                &remote_file,
-                &cfg_clone,
-                &local_file_clone,
-                pb,
-            ) {
-                Ok(_) => {
-                    let mut stats = stats_clone.lock().unwrap();
-                    stats.streams_completed += 1;
-                },
-                Err(e) => {
-                    eprintln!("{}", e);
-                    let mut flags = retry_flag_clone.lock().unwrap();
-                    flags[stream_num] = true;
-                }
-            }
-        });
-
-        handles.push(handle);
     }
 
-    // Wait for all threads
-    for handle in handles {
-        let _ = handle.join();
+    /// The report as one line of JSON, as `--json` prints it. Durations are
+    /// in seconds and speeds in bytes per second.
+    pub fn to_json(&self) -> String {
+        let (outcome, reason) = match self.outcome {
+            TransferOutcome::Completed => ("completed", None),
+            TransferOutcome::Skipped(ref reason) => ("skipped", Some(reason.as_str())),
+            TransferOutcome::Delegated => ("delegated", None),
+        };
+        let duration_secs = self.duration.as_secs_f64();
+        let streams: Vec<json::Object> = self.stream_reports.iter()
+            .map(|s| json::Object::new()
+                .field("stream", &s.stream)
+                .field("bytes", &s.bytes)
+                .field("duration", &s.duration.as_secs_f64())
+                .field("retries", &s.retries))
+            .collect();
+
+        json::Object::new()
+            .field("source", &self.source)
+            .field("destination", &self.destination)
+            .field("outcome", outcome)
+            .field("reason", &reason)
+            .field("size", &self.total_bytes)
+            .field("bytes_sent", &self.bytes_sent)
+            .field("duration", &duration_secs)
+            .field("average_speed", &(self.total_bytes as f64 / duration_secs))
+            .field("peak_speed", &self.peak_speed)
+            .field("stream_count", &self.streams)
+            .field("streams", &streams)
+            .field("transport", &self.transport.describe())
+            .field("cipher", &self.cipher)
+            .field("holes_skipped", &self.holes_skipped)
+            .field("zeros_skipped", &self.zeros_skipped)
+            .field("delta", &self.delta)
+            .field("remote", &self.remote.as_deref().map(json::Raw))
+            .finish()
     }
-
-    // Check for failures
-    let flags = retry_flag.lock().unwrap();
-    if flags.iter().any(|&flag| flag) {
-        return Err(io::Error::new(
-            io::ErrorKind::Other,
-            "Some streams failed to transfer after retries."
-        ));
-    }
-
-    // Sync file to disk
-    local_file.sync_all()?;
-
-    println!("Transfer completed successfully!");
-
-    // Print final statistics
-    let stats = stats.lock().unwrap();
-    print_transfer_stats(&stats, num_streams);
-
-    Ok(())
 }
 
-/// Push transfer: local → remote using SFTP
-pub fn split_and_copy_binary_file(
-    quiet_mode: bool,
-    input_file: &str,
-    num_streams: usize,
-    remote_user: &str,
-    remote_host: &str,
-    remote_path: &str,
-    ssh_key_path: Option<&str>,
-    retries: u32,
-    ssh_port: u16,
-) -> io::Result<()> {
-    if !quiet_mode {
-        println!("Preparing to transfer {}...", input_file);
-    }
-
-    // Get local file size
-    let file_size = fs::metadata(input_file)?.len();
-
-    let stats = Arc::new(Mutex::new(TransferStats {
-        start_time: Instant::now(),
-        total_bytes: file_size as usize,
-        streams_completed: 0,
-    }));
-
-    if !quiet_mode {
-        println!("Local file size: {} ({})", format_size(file_size as usize), file_size);
-        let stream_size = file_size / num_streams as u64;
-        println!("Using {} streams of approximately {} each",
-                 num_streams,
-                 format_size(stream_size as usize));
-        let extra_bytes = file_size % num_streams as u64;
-        if extra_bytes > 0 {
-            println!("Last stream will have an additional {} bytes", extra_bytes);
+/// The statistics block printed after a transfer
+impl fmt::Display for TransferReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let duration_secs = self.duration.as_secs_f64();
+        let speed = self.total_bytes as f64 / duration_secs;
+
+        writeln!(f, "Transfer Statistics")?;
+        writeln!(f, "Total Size:    {}", format_size(self.total_bytes as usize))?;
+        writeln!(f, "Streams:       {}", self.streams)?;
+        writeln!(f, "Transport:     {}", self.transport.describe())?;
+        writeln!(f, "Duration:      {:.2} seconds", duration_secs)?;
+        write!(f, "Average Speed: {}", format_speed(speed))?;
+        if self.holes_skipped > 0 {
+            write!(f, "\nHoles skipped: {}", format_size(self.holes_skipped as usize))?;
+        }
+        if let Some(zeros) = self.zeros_skipped {
+            write!(f, "\nZeros skipped: {}", format_size(zeros as usize))?;
+        }
+        if self.delta {
+            write!(f, "\nDelta:         {} of {} changed", format_size(self.bytes_sent as usize), format_size(self.total_bytes as usize))?;
         }
-        println!("Initializing transfer...");
+        if self.wire_bytes.iter().all(Option::is_none) {
+            return Ok(());
+        }
+        let ratio = |payload: u64, wire: u64| if wire == 0 { 0.0 } else { payload as f64 / wire as f64 };
+
+        let (payload, wire) = self.wire_bytes.iter().flatten().fold((0, 0), |(p, w), &(sp, sw)| (p + sp, w + sw));
+        write!(f, "\nCompression:   {} sent as {} ({:.2}x)",
+               format_size(payload as usize), format_size(wire as usize), ratio(payload, wire))?;
+        for (stream_num, counts) in self.wire_bytes.iter().enumerate() {
+            if let Some((payload, wire)) = *counts {
+                write!(f, "\n  Stream {:<4}   {} -> {} ({:.2}x)",
+                       stream_num, format_size(payload as usize), format_size(wire as usize), ratio(payload, wire))?;
+            }
+        }
+        Ok(())
     }
+}
 
-    // Create session config
-    let cfg = SessionConfig {
-        host: remote_host.to_string(),
-        port: ssh_port,
-        user: remote_user.to_string(),
-        key_path: ssh_key_path.map(|s| s.to_string()),
-        retries,
-    };
-
-    // Determine remote file path
-    let file_name = Path::new(input_file)
-        .file_name()
-        .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "Invalid input file path"))?
-        .to_str()
-        .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "Invalid file name"))?;
-    let remote_file = format!("{}/{}", remote_path, file_name);
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    // Create and extend remote file
-    {
-        let sess = connect_and_auth(&cfg)?;
-        let sftp = open_sftp(&sess)?;
-        extend_remote_file(&sftp, &remote_file, file_size)?;
+    fn stat(size: u64, mtime: u64) -> RemoteStat {
+        RemoteStat { size, mode: 0o100644, mtime, atime: mtime, uid: 0, gid: 0, is_dir: false }
     }
 
-    // Calculate segments
-    let stream_size = file_size / num_streams as u64;
-    let extra_bytes = file_size % num_streams as u64;
+    fn never_hashed() -> io::Result<Option<bool>> {
+        panic!("hash asked for when size or mtime already decide")
+    }
 
-    // Setup progress bars
-    let m = if !quiet_mode {
-        MultiProgress::new()
-    } else {
-        MultiProgress::with_draw_target(indicatif::ProgressDrawTarget::hidden())
-    };
-    let style = ProgressStyle::with_template(
-        "[{elapsed_precise}] {bar:40.cyan/blue} {pos:>7}/{len:7} {msg}",
-    )
-    .unwrap()
-    .progress_chars("##-");
-
-    let retry_flag = Arc::new(Mutex::new(vec![false; num_streams]));
-    let mut handles = Vec::with_capacity(num_streams);
-
-    // Spawn worker threads
-    for stream_num in 0..num_streams {
-        let cfg_clone = cfg.clone();
-        let input_file = input_file.to_string();
-        let remote_file = remote_file.clone();
-        let retry_flag_clone = Arc::clone(&retry_flag);
-        let stats_clone = Arc::clone(&stats);
-
-        let start = stream_num as u64 * stream_size;
-        let mut end = start + stream_size;
-        if stream_num == num_streams - 1 {
-            end += extra_bytes;
+    #[test]
+    fn missing_destination_is_never_skipped() {
+        for policy in [ExistingPolicy::Overwrite, ExistingPolicy::NoClobber, ExistingPolicy::Update, ExistingPolicy::SkipIdentical] {
+            assert_eq!(skip_reason(policy, &stat(10, 100), None, never_hashed).unwrap(), None);
         }
-        let segment_len = end - start;
-
-        let pb = m.add(ProgressBar::new(segment_len));
-        pb.set_style(style.clone());
-        pb.set_message(format!("Stream {}", stream_num));
-
-        let handle = thread::spawn(move || {
-            match push_worker(
-                stream_num,
-                start,
-                end,
-                &input_file,
-                &remote_file,
-                &cfg_clone,
-                pb,
-            ) {
-                Ok(_) => {
-                    let mut stats = stats_clone.lock().unwrap();
-                    stats.streams_completed += 1;
-                },
-                Err(e) => {
-                    eprintln!("{}", e);
-                    let mut flags = retry_flag_clone.lock().unwrap();
-                    flags[stream_num] = true;
-                }
-            }
-        });
-
-        handles.push(handle);
     }
 
-    // Wait for all threads
-    for handle in handles {
-        let _ = handle.join();
+    #[test]
+    fn overwrite_and_no_clobber() {
+        assert_eq!(skip_reason(ExistingPolicy::Overwrite, &stat(10, 100), Some(stat(10, 100)), never_hashed).unwrap(), None);
+        assert_eq!(skip_reason(ExistingPolicy::NoClobber, &stat(10, 100), Some(stat(3, 1)), never_hashed).unwrap(), Some("destination exists"));
     }
 
-    // Check for failures
-    let flags = retry_flag.lock().unwrap();
-    if flags.iter().any(|&flag| flag) {
-        return Err(io::Error::new(
-            io::ErrorKind::Other,
-            "Some streams failed to transfer after retries."
-        ));
+    #[test]
+    fn update_skips_unless_the_destination_is_older() {
+        let src = stat(10, 100);
+        assert!(skip_reason(ExistingPolicy::Update, &src, Some(stat(10, 100)), never_hashed).unwrap().is_some());
+        assert!(skip_reason(ExistingPolicy::Update, &src, Some(stat(99, 200)), never_hashed).unwrap().is_some());
+        assert_eq!(skip_reason(ExistingPolicy::Update, &src, Some(stat(10, 99)), never_hashed).unwrap(), None);
     }
 
-    println!("Transfer completed successfully!");
-
-    // Print final statistics
-    let stats = stats.lock().unwrap();
-    print_transfer_stats(&stats, num_streams);
-
-    Ok(())
+    #[test]
+    fn skip_identical_checks_size_then_mtime_then_hash() {
+        let src = stat(10, 100);
+        assert_eq!(skip_reason(ExistingPolicy::SkipIdentical, &src, Some(stat(11, 100)), never_hashed).unwrap(), None);
+        assert_eq!(skip_reason(ExistingPolicy::SkipIdentical, &src, Some(stat(10, 100)), never_hashed).unwrap(), Some("same size and modification time"));
+        assert_eq!(skip_reason(ExistingPolicy::SkipIdentical, &src, Some(stat(10, 50)), || Ok(Some(true))).unwrap(), Some("same size and BLAKE3 hash"));
+        assert_eq!(skip_reason(ExistingPolicy::SkipIdentical, &src, Some(stat(10, 50)), || Ok(Some(false))).unwrap(), None);
+        assert_eq!(skip_reason(ExistingPolicy::SkipIdentical, &src, Some(stat(10, 50)), || Ok(None)).unwrap(), None);
+        assert!(skip_reason(ExistingPolicy::SkipIdentical, &src, Some(stat(10, 50)), || Err(io::Error::other("boom"))).is_err());
+    }
 }
@@ -10,6 +10,16 @@ total_tests=0
 passed_tests=0
 failed_tests=0
 
+# Locations without user@ log in as $USER
+export USER=${USER:-zap-test}
+
+# Exit codes, as documented in the README
+EXIT_OK=0
+EXIT_USAGE=2
+EXIT_RESOLVE=3
+EXIT_CONNECT=4
+EXIT_NOT_FOUND=7
+
 # Color codes for output
 RED='\033[0;31m'
 GREEN='\033[0;32m'
@@ -39,6 +49,31 @@ test_case() {
     fi
 }
 
+# Like test_case, and stdout must also be exactly one JSON object
+json_case() {
+    ((total_tests++))
+    local description="$1"
+    shift
+    local expected_exit="$1"
+    shift
+    local args=("$@")
+
+    local output
+    output=$(./target/debug/zap "${args[@]}" 2>/dev/null)
+    exit_code=$?
+
+    if [ "$exit_code" -eq "$expected_exit" ] && jq -se 'length == 1 and (.[0] | type == "object")' <<< "$output" >/dev/null 2>&1; then
+        echo -e "${GREEN}✓${NC} Test $total_tests: $description"
+        ((passed_tests++))
+    else
+        echo -e "${RED}✗${NC} Test $total_tests: $description"
+        echo -e "  Expected exit code $expected_exit and one JSON object, got $exit_code and:"
+        echo "$output" | sed 's/^/    /'
+        echo -e "  Args: ${args[*]}"
+        ((failed_tests++))
+    fi
+}
+
 echo "========================================="
 echo "Zap Local Test Suite"
 echo "========================================="
@@ -48,71 +83,71 @@ echo ""
 # SECTION 1: Missing Files
 # ==========================================
 echo -e "${YELLOW}[1] Missing Files Tests${NC}"
-test_case "Source file doesn't exist" 1 "nonexistent_file.bin" "test_dir/"
-test_case "Destination directory doesn't exist (local)" 1 "test_file.bin" "nonexistent_dir/"
+test_case "Source file doesn't exist" $EXIT_NOT_FOUND "nonexistent_file.bin" "test_dir/"
+test_case "Destination directory doesn't exist (local)" $EXIT_NOT_FOUND "test_file.bin" "nonexistent_dir/"
 
 # ==========================================
 # SECTION 2: Invalid Remote Format
 # ==========================================
 echo -e "\n${YELLOW}[2] Invalid Remote Format Tests${NC}"
-test_case "Remote without colon" 1 "test_file.bin" "user@localhost"
-test_case "Empty user with @" 1 "test_file.bin" "@localhost:path"
-test_case "Empty host with @" 1 "test_file.bin" "user@:path"
-test_case "Host ending with @" 1 "test_file.bin" "localhost@:path"
-test_case "Only localhost (no colon or @)" 1 "test_file.bin" "localhost"
-test_case "Colon but empty host" 1 "test_file.bin" ":path"
+test_case "Remote without colon" $EXIT_USAGE "test_file.bin" "user@localhost"
+test_case "Empty user with @" $EXIT_USAGE "test_file.bin" "@localhost:path"
+test_case "Empty host with @" $EXIT_USAGE "test_file.bin" "user@:path"
+test_case "Host ending with @" $EXIT_USAGE "test_file.bin" "localhost@:path"
+test_case "Only localhost (no colon or @)" $EXIT_USAGE "test_file.bin" "localhost"
+test_case "Colon but empty host" $EXIT_USAGE "test_file.bin" ":path"
 
 # ==========================================
 # SECTION 3: Both Local or Both Remote
 # ==========================================
 echo -e "\n${YELLOW}[3] Invalid Source/Dest Combinations${NC}"
-test_case "Both local paths" 1 "test_file.bin" "test_dir/"
-test_case "Both remote paths" 1 "user@host1:/file" "user@host2:/path"
+test_case "Both local paths copy locally" $EXIT_OK "test_file.bin" "test_dir/"
+test_case "Both remote paths" $EXIT_RESOLVE "user@zap-test1.invalid:/file" "user@zap-test2.invalid:/path"
 
 # ==========================================
 # SECTION 4: IPv6 Address Parsing
 # ==========================================
 echo -e "\n${YELLOW}[4] IPv6 Address Format Tests${NC}"
-test_case "IPv6 without brackets (invalid)" 1 "test_file.bin" "user@2001:db8::1:/path"
-test_case "IPv6 with user@ prefix" 1 "test_file.bin" "user@[::1]:"
-test_case "IPv6 without user@ prefix" 1 "test_file.bin" "[::1]:"
-test_case "IPv6 malformed - no closing bracket" 1 "test_file.bin" "user@[2001:db8::1:/path"
-test_case "IPv6 malformed - no opening bracket" 1 "test_file.bin" "user@2001:db8::1]:/path"
+test_case "IPv6 without brackets (invalid)" $EXIT_USAGE "test_file.bin" "user@2001:db8::1:/path"
+test_case "IPv6 with user@ prefix" $EXIT_CONNECT -p 1 "test_file.bin" "user@[::1]:"
+test_case "IPv6 without user@ prefix" $EXIT_CONNECT -p 1 "test_file.bin" "[::1]:"
+test_case "IPv6 malformed - no closing bracket" $EXIT_USAGE "test_file.bin" "user@[2001:db8::1:/path"
+test_case "IPv6 malformed - no opening bracket" $EXIT_USAGE "test_file.bin" "user@2001:db8::1]:/path"
 
 # ==========================================
 # SECTION 5: Port Validation
 # ==========================================
 echo -e "\n${YELLOW}[5] Port Validation Tests${NC}"
-test_case "Port 0 (invalid)" 1 -p 0 "test_file.bin" "user@localhost:"
-test_case "Port 65536 (too high)" 1 -p 65536 "test_file.bin" "user@localhost:"
-test_case "Port 99999 (too high)" 1 -p 99999 "test_file.bin" "user@localhost:"
-test_case "Port -1 (negative)" 2 -p -1 "test_file.bin" "user@localhost:"
-test_case "Port with letters" 1 -p abc "test_file.bin" "user@localhost:"
+test_case "Port 0 (invalid)" $EXIT_USAGE -p 0 "test_file.bin" "user@localhost:"
+test_case "Port 65536 (too high)" $EXIT_USAGE -p 65536 "test_file.bin" "user@localhost:"
+test_case "Port 99999 (too high)" $EXIT_USAGE -p 99999 "test_file.bin" "user@localhost:"
+test_case "Port -1 (negative)" $EXIT_USAGE -p -1 "test_file.bin" "user@localhost:"
+test_case "Port with letters" $EXIT_USAGE -p abc "test_file.bin" "user@localhost:"
 
 # ==========================================
 # SECTION 6: Streams Validation
 # ==========================================
 echo -e "\n${YELLOW}[6] Streams Validation Tests${NC}"
-test_case "Streams 0 (invalid)" 1 -s 0 "test_file.bin" "user@localhost:"
-test_case "Streams negative" 2 -s -5 "test_file.bin" "user@localhost:"
-test_case "Streams with letters" 1 -s abc "test_file.bin" "user@localhost:"
+test_case "Streams 0 (invalid)" $EXIT_USAGE -s 0 "test_file.bin" "user@localhost:"
+test_case "Streams negative" $EXIT_USAGE -s -5 "test_file.bin" "user@localhost:"
+test_case "Streams with letters" $EXIT_USAGE -s abc "test_file.bin" "user@localhost:"
 
 # ==========================================
 # SECTION 7: Retries Validation
 # ==========================================
 echo -e "\n${YELLOW}[7] Retries Validation Tests${NC}"
-test_case "Retries negative" 2 -r -1 "test_file.bin" "user@localhost:"
-test_case "Retries with letters" 1 -r xyz "test_file.bin" "user@localhost:"
+test_case "Retries negative" $EXIT_USAGE -r -1 "test_file.bin" "user@localhost:"
+test_case "Retries with letters" $EXIT_USAGE -r xyz "test_file.bin" "user@localhost:"
 
 # ==========================================
 # SECTION 8: Path Parsing Edge Cases
 # ==========================================
 echo -e "\n${YELLOW}[8] Path Parsing Edge Cases${NC}"
-test_case "Multiple @ symbols" 1 "test_file.bin" "user@name@host:/path"
-test_case "Multiple colons in host:path" 1 "test_file.bin" "host:path:extra"
-test_case "Empty path after colon" 1 "test_file.bin" "user@host:"
-test_case "Just @ symbol" 1 "test_file.bin" "@"
-test_case "Just colon" 1 "test_file.bin" ":"
+test_case "Multiple @ symbols" $EXIT_USAGE "test_file.bin" "user@name@host:/path"
+test_case "Multiple colons in host:path" $EXIT_RESOLVE "test_file.bin" "zap-test.invalid:path:extra"
+test_case "Empty path after colon" $EXIT_RESOLVE "test_file.bin" "user@zap-test.invalid:"
+test_case "Just @ symbol" $EXIT_USAGE "test_file.bin" "@"
+test_case "Just colon" $EXIT_USAGE "test_file.bin" ":"
 
 # ==========================================
 # SECTION 9: Windows Path Detection (on non-Windows)
@@ -122,82 +157,241 @@ if [[ "$OSTYPE" != "msys" && "$OSTYPE" != "win32" ]]; then
     # These would be treated as local Windows paths and fail because:
     # 1. On Unix, C:\ paths don't exist
     # 2. They're detected as local (not remote) so validation fails
-    test_case "Windows C: drive path" 1 "C:\\Users\\file.txt" "test_dir/"
-    test_case "Windows D: drive path" 1 "D:\\path\\file.txt" "test_dir/"
-    test_case "Windows UNC path" 1 "\\\\server\\share\\file.txt" "test_dir/"
+    test_case "Windows C: drive path" $EXIT_NOT_FOUND "C:\\Users\\file.txt" "test_dir/"
+    test_case "Windows D: drive path" $EXIT_NOT_FOUND "D:\\path\\file.txt" "test_dir/"
+    test_case "Windows UNC path" $EXIT_NOT_FOUND "\\\\server\\share\\file.txt" "test_dir/"
 fi
 
 # ==========================================
 # SECTION 10: Special Characters in Paths
 # ==========================================
 echo -e "\n${YELLOW}[10] Special Characters Tests${NC}"
-test_case "Space in remote path" 1 "test_file.bin" "user@host:/path with spaces"
-test_case "Tab in remote path" 1 "test_file.bin" "user@host:/path	tab"
-test_case "Null username" 1 "test_file.bin" "@host:/path"
+test_case "Space in remote path" $EXIT_RESOLVE "test_file.bin" "user@zap-test.invalid:/path with spaces"
+test_case "Tab in remote path" $EXIT_RESOLVE "test_file.bin" "user@zap-test.invalid:/path	tab"
+test_case "Null username" $EXIT_USAGE "test_file.bin" "@host:/path"
 
 # ==========================================
 # SECTION 11: Mixed Valid/Invalid Arguments
 # ==========================================
 echo -e "\n${YELLOW}[11] Mixed Argument Tests${NC}"
-test_case "Valid format but source missing + bad port" 1 -p 0 "nonexistent.bin" "user@localhost:"
-test_case "Valid format but invalid streams" 2 -s -1 "test_file.bin" "user@localhost:"
-test_case "Multiple invalid flags" 2 -p 99999 -s -1 -r -5 "test_file.bin" "user@localhost:"
+test_case "Valid format but source missing + bad port" $EXIT_NOT_FOUND -p 0 "nonexistent.bin" "user@localhost:"
+test_case "Valid format but invalid streams" $EXIT_USAGE -s -1 "test_file.bin" "user@localhost:"
+test_case "Multiple invalid flags" $EXIT_USAGE -p 99999 -s -1 -r -5 "test_file.bin" "user@localhost:"
 
 # ==========================================
 # SECTION 12: File vs Directory Validation
 # ==========================================
 echo -e "\n${YELLOW}[12] File/Directory Type Tests${NC}"
-test_case "Source is directory not file" 1 "test_dir" "user@localhost:"
-test_case "Local dest is file not directory" 1 "user@localhost:/file.bin" "test_file.bin"
+test_case "Source is directory not file" $EXIT_USAGE "test_dir" "user@localhost:"
+test_case "Local dest is an existing file" $EXIT_CONNECT -p 1 "user@127.0.0.1:/file.bin" "test_file.bin"
+test_case "Local dest is a new file name" $EXIT_CONNECT -p 1 "user@127.0.0.1:/file.bin" "test_dir/renamed.bin"
+test_case "Local dest file in missing directory" $EXIT_NOT_FOUND "user@localhost:/file.bin" "nonexistent_dir/renamed.bin"
 
 # ==========================================
 # SECTION 13: Empty Arguments
 # ==========================================
 echo -e "\n${YELLOW}[13] Empty/Missing Arguments Tests${NC}"
 test_case "No arguments at all" 2
-test_case "Only source, no dest" 2 "test_file.bin"
-test_case "Empty string source" 1 "" "test_dir/"
-test_case "Empty string dest" 1 "test_file.bin" ""
+test_case "Only source, no dest" $EXIT_USAGE "test_file.bin"
+test_case "Empty string source" $EXIT_NOT_FOUND "" "test_dir/"
+test_case "Empty string dest" $EXIT_USAGE "test_file.bin" ""
 
 # ==========================================
 # SECTION 14: Colon Edge Cases
 # ==========================================
 echo -e "\n${YELLOW}[14] Colon Position Edge Cases${NC}"
-test_case "Leading colon" 1 "test_file.bin" ":host/path"
-test_case "Trailing colon only" 1 "test_file.bin" "host:"
-test_case "Double colon (not IPv6)" 1 "test_file.bin" "host::/path"
-test_case "Colon in middle of path" 1 "test_file.bin" "user@host:/path:subpath"
+test_case "Leading colon" $EXIT_USAGE "test_file.bin" ":host/path"
+test_case "Trailing colon only" $EXIT_RESOLVE "test_file.bin" "zap-test.invalid:"
+test_case "Double colon (not IPv6)" $EXIT_RESOLVE "test_file.bin" "zap-test.invalid::/path"
+test_case "Colon in middle of path" $EXIT_RESOLVE "test_file.bin" "user@zap-test.invalid:/path:subpath"
 
 # ==========================================
 # SECTION 15: Hostname Validation
 # ==========================================
 echo -e "\n${YELLOW}[15] Hostname Edge Cases${NC}"
-test_case "Hostname with spaces" 1 "test_file.bin" "user@host name:/path"
-test_case "Very long hostname" 1 "test_file.bin" "user@$(printf 'a%.0s' {1..300}):/path"
-test_case "Hostname with special chars" 1 "test_file.bin" "user@host!@#:/path"
+test_case "Hostname with spaces" $EXIT_RESOLVE "test_file.bin" "user@zap test.invalid:/path"
+test_case "Very long hostname" $EXIT_RESOLVE "test_file.bin" "user@$(printf 'a%.0s' {1..300}).invalid:/path"
+test_case "Hostname with special chars" $EXIT_USAGE "test_file.bin" "user@host!@#:/path"
 
 # ==========================================
 # SECTION 16: Username Validation
 # ==========================================
 echo -e "\n${YELLOW}[16] Username Edge Cases${NC}"
-test_case "Username with spaces" 1 "test_file.bin" "user name@host:/path"
-test_case "Username with @" 1 "test_file.bin" "user@email.com@host:/path"
-test_case "Very long username" 1 "test_file.bin" "$(printf 'u%.0s' {1..300})@host:/path"
+test_case "Username with spaces" $EXIT_RESOLVE "test_file.bin" "user name@zap-test.invalid:/path"
+test_case "Username with @" $EXIT_USAGE "test_file.bin" "user@email.com@host:/path"
+test_case "Very long username" $EXIT_RESOLVE "test_file.bin" "$(printf 'u%.0s' {1..300})@zap-test.invalid:/path"
 
 # ==========================================
 # SECTION 17: Quiet Mode (should still fail validation)
 # ==========================================
 echo -e "\n${YELLOW}[17] Quiet Mode Tests${NC}"
-test_case "Quiet mode with invalid source" 1 -q "nonexistent.bin" "user@localhost:"
-test_case "Quiet mode with invalid format" 1 -q "test_file.bin" "user@localhost"
-test_case "Quiet mode with bad port" 1 -q -p 99999 "test_file.bin" "user@localhost:"
+test_case "Quiet mode with invalid source" $EXIT_NOT_FOUND -q "nonexistent.bin" "user@localhost:"
+test_case "Quiet mode with invalid format" $EXIT_USAGE -q "test_file.bin" "user@localhost"
+test_case "Quiet mode with bad port" $EXIT_USAGE -q -p 99999 "test_file.bin" "user@localhost:"
 
 # ==========================================
 # SECTION 18: SSH Key Path (file doesn't exist)
 # ==========================================
 echo -e "\n${YELLOW}[18] SSH Key Path Tests${NC}"
-test_case "Non-existent SSH key" 1 -i "/nonexistent/key" "test_file.bin" "user@localhost:"
-test_case "SSH key is directory" 1 -i "/tmp" "test_file.bin" "user@localhost:"
+test_case "Non-existent SSH key" $EXIT_CONNECT -p 1 -i "/nonexistent/key" "test_file.bin" "user@127.0.0.1:"
+test_case "SSH key is directory" $EXIT_CONNECT -p 1 -i "/tmp" "test_file.bin" "user@127.0.0.1:"
+
+# ==========================================
+# SECTION 19: Helper Options
+# ==========================================
+echo -e "\n${YELLOW}[19] Helper Tests${NC}"
+test_case "No-helper with invalid format" $EXIT_USAGE --no-helper "test_file.bin" "user@localhost"
+test_case "Remote-zap without value" $EXIT_USAGE "test_file.bin" "user@localhost:" --remote-zap
+
+# ==========================================
+# SECTION 20: Transport Options
+# ==========================================
+echo -e "\n${YELLOW}[20] Transport Tests${NC}"
+# Without the quic feature, asking for QUIC is a usage error before anything else
+if ./target/debug/zap -t quic "test_file.bin" "test_dir/" 2>&1 | grep -q "without QUIC support"; then
+    EXIT_QUIC_MISSING_SOURCE=$EXIT_USAGE
+else
+    EXIT_QUIC_MISSING_SOURCE=$EXIT_NOT_FOUND
+fi
+test_case "Unknown transport" $EXIT_USAGE -t carrier-pigeon "test_file.bin" "user@localhost:"
+test_case "Unencrypted without tcp transport" $EXIT_USAGE --unencrypted "test_file.bin" "user@localhost:"
+test_case "Tcp transport with missing source" $EXIT_NOT_FOUND -t tcp "nonexistent.bin" "user@localhost:"
+test_case "Quic transport with missing source" $EXIT_QUIC_MISSING_SOURCE -t quic "nonexistent.bin" "user@localhost:"
+test_case "Congestion without quic transport" $EXIT_USAGE --congestion bbr "test_file.bin" "user@localhost:"
+test_case "Unknown congestion controller" $EXIT_USAGE -t quic --congestion vegas "test_file.bin" "user@localhost:"
+
+# ==========================================
+# SECTION 21: Cipher and MAC Selection
+# ==========================================
+echo -e "\n${YELLOW}[21] Cipher and MAC Tests${NC}"
+test_case "Unsupported cipher" $EXIT_USAGE -c no-such-cipher "test_file.bin" "user@localhost:"
+test_case "Unsupported MAC" $EXIT_USAGE --mac no-such-mac "test_file.bin" "user@localhost:"
+test_case "Unsupported cipher in list" $EXIT_USAGE -c aes128-ctr,no-such-cipher "test_file.bin" "user@localhost:"
+test_case "Cipher without value" $EXIT_USAGE "test_file.bin" "user@localhost:" -c
+test_case "Compression flag accepted" $EXIT_CONNECT -p 1 -C "test_file.bin" "user@127.0.0.1:"
+test_case "Compression takes no value" $EXIT_USAGE --compress=yes "test_file.bin" "user@localhost:"
+
+# ==========================================
+# SECTION 22: Preserve Attributes
+# ==========================================
+echo -e "\n${YELLOW}[22] Preserve Tests${NC}"
+test_case "Preserve flag accepted" $EXIT_CONNECT -p 1 --preserve "test_file.bin" "user@127.0.0.1:"
+test_case "Preserve takes no value" $EXIT_USAGE --preserve=yes "test_file.bin" "user@localhost:"
+test_case "Preserve with missing source" $EXIT_NOT_FOUND --preserve "nonexistent_file.bin" "user@localhost:"
+
+# ==========================================
+# SECTION 23: Create Destination Directories
+# ==========================================
+echo -e "\n${YELLOW}[23] Mkdir Tests${NC}"
+test_case "Missing local dir with -D gets past validation" $EXIT_CONNECT -p 1 -D "user@127.0.0.1:/file.bin" "new_dir/sub/"
+test_case "Missing local dir with --mkdir gets past validation" $EXIT_CONNECT -p 1 --mkdir "user@127.0.0.1:/file.bin" "new_dir/sub/file.bin"
+test_case "Mkdir takes no value" $EXIT_USAGE --mkdir=yes "user@localhost:/file.bin" "new_dir/"
+
+# ==========================================
+# SECTION 24: Existing Destination Policies
+# ==========================================
+echo -e "\n${YELLOW}[24] Existing Destination Tests${NC}"
+test_case "No-clobber accepted" $EXIT_CONNECT -p 1 --no-clobber "test_file.bin" "user@127.0.0.1:"
+test_case "Update accepted" $EXIT_CONNECT -p 1 --update "test_file.bin" "user@127.0.0.1:"
+test_case "Skip-identical accepted" $EXIT_CONNECT -p 1 --skip-identical "test_file.bin" "user@127.0.0.1:"
+test_case "No-clobber conflicts with update" $EXIT_USAGE --no-clobber --update "test_file.bin" "user@localhost:"
+test_case "Update conflicts with skip-identical" $EXIT_USAGE --update --skip-identical "test_file.bin" "user@localhost:"
+
+# ==========================================
+# SECTION 25: Delta Transfers
+# ==========================================
+echo -e "\n${YELLOW}[25] Delta Tests${NC}"
+test_case "Delta push accepted" $EXIT_CONNECT -p 1 --delta "test_file.bin" "user@127.0.0.1:"
+test_case "Delta pull accepted" $EXIT_CONNECT -p 1 --delta "user@127.0.0.1:/file.bin" "test_dir/"
+test_case "Delta takes no value" $EXIT_USAGE --delta=yes "test_file.bin" "user@localhost:"
+test_case "Delta without the helper rejected" $EXIT_USAGE --delta --no-helper "test_file.bin" "user@localhost:"
+
+# ==========================================
+# SECTION 26: Sparse Destinations
+# ==========================================
+echo -e "\n${YELLOW}[26] Sparse Tests${NC}"
+test_case "Sparse push accepted" $EXIT_CONNECT -p 1 --sparse "test_file.bin" "user@127.0.0.1:"
+test_case "Sparse pull accepted" $EXIT_CONNECT -p 1 --sparse "user@127.0.0.1:/file.bin" "test_dir/"
+test_case "Sparse takes no value" $EXIT_USAGE --sparse=yes "test_file.bin" "user@localhost:"
+
+# ==========================================
+# SECTION 27: Preallocation
+# ==========================================
+echo -e "\n${YELLOW}[27] Preallocate Tests${NC}"
+test_case "Preallocate push accepted" $EXIT_CONNECT -p 1 --preallocate "test_file.bin" "user@127.0.0.1:"
+test_case "Preallocate pull accepted" $EXIT_CONNECT -p 1 --preallocate "user@127.0.0.1:/file.bin" "test_dir/"
+test_case "Preallocate conflicts with sparse" $EXIT_USAGE --preallocate --sparse "test_file.bin" "user@localhost:"
+test_case "Preallocate takes no value" $EXIT_USAGE --preallocate=yes "test_file.bin" "user@localhost:"
+
+# ==========================================
+# SECTION 28: Free-Space Check
+# ==========================================
+echo -e "\n${YELLOW}[28] Force Tests${NC}"
+test_case "Force push accepted" $EXIT_CONNECT -p 1 --force "test_file.bin" "user@127.0.0.1:"
+test_case "Force pull accepted" $EXIT_CONNECT -p 1 --force "user@127.0.0.1:/file.bin" "test_dir/"
+test_case "Force takes no value" $EXIT_USAGE --force=yes "test_file.bin" "user@localhost:"
+
+# ==========================================
+# SECTION 29: Fsync
+# ==========================================
+echo -e "\n${YELLOW}[29] Fsync Tests${NC}"
+test_case "No-fsync push accepted" $EXIT_CONNECT -p 1 --no-fsync "test_file.bin" "user@127.0.0.1:"
+test_case "No-fsync pull accepted" $EXIT_CONNECT -p 1 --no-fsync "user@127.0.0.1:/file.bin" "test_dir/"
+test_case "No-fsync takes no value" $EXIT_USAGE --no-fsync=yes "test_file.bin" "user@localhost:"
+
+# ==========================================
+# SECTION 30: Stdin/Stdout Streaming
+# ==========================================
+echo -e "\n${YELLOW}[30] Streaming Tests${NC}"
+test_case "Stdin source accepted" $EXIT_CONNECT -p 1 - "user@127.0.0.1:/file.bin"
+test_case "Stdout destination accepted" $EXIT_CONNECT -p 1 "user@127.0.0.1:/file.bin" -
+test_case "Stdin to stdout rejected" $EXIT_USAGE - -
+test_case "Stdin with --sparse rejected" $EXIT_USAGE --sparse - "user@localhost:/file.bin"
+test_case "Stdout with --delta rejected" $EXIT_USAGE --delta "user@localhost:/file.bin" -
+test_case "Stdout with --preserve rejected" $EXIT_USAGE --preserve "user@localhost:/file.bin" -
+
+# ==========================================
+# SECTION 31: Remote to Remote
+# ==========================================
+echo -e "\n${YELLOW}[31] Remote to Remote Tests${NC}"
+test_case "Relayed transfer accepted" $EXIT_CONNECT -p 1 "user@127.0.0.1:/file.bin" "user@127.0.0.1:/dest.bin"
+test_case "Direct transfer accepted" $EXIT_CONNECT -p 1 --direct "user@127.0.0.1:/file.bin" "user@127.0.0.1:/dest.bin"
+test_case "Relay with --delta rejected" $EXIT_USAGE --delta "user@localhost:/file.bin" "user@localhost:/dest.bin"
+test_case "Relay with --sparse rejected" $EXIT_USAGE --sparse "user@localhost:/file.bin" "user@localhost:/dest.bin"
+test_case "Direct on a push rejected" $EXIT_USAGE --direct "test_file.bin" "user@localhost:"
+test_case "Direct takes no value" $EXIT_USAGE --direct=yes "user@localhost:/file.bin" "user@localhost:/dest.bin"
+
+# ==========================================
+# SECTION 32: Local Copies
+# ==========================================
+echo -e "\n${YELLOW}[32] Local Copy Tests${NC}"
+test_case "Local copy to a new file name" $EXIT_OK -s 4 "test_file.bin" "test_dir/copy.bin"
+test_case "Local copy with --mkdir" $EXIT_OK -D "test_file.bin" "test_dir/new/sub/"
+test_case "Local copy to a path named like a host" $EXIT_OK -q "test_file.bin" "test_dir/user@localhost"
+test_case "Local copy onto itself rejected" $EXIT_USAGE "test_file.bin" "test_file.bin"
+test_case "Local copy with --delta rejected" $EXIT_USAGE --delta "test_file.bin" "test_dir/"
+test_case "Local copy with --transport rejected" $EXIT_USAGE -t tcp "test_file.bin" "test_dir/"
+test_case "Stdin to a local file rejected" $EXIT_USAGE - "test_dir/"
+
+# ==========================================
+# SECTION 33: Exit Codes
+# ==========================================
+# Host key, auth and partial (11) failures need a real server; the unit
+# tests in src/drivers.rs produce 11 from failed streams
+echo -e "\n${YELLOW}[33] Exit Code Tests${NC}"
+test_case "Unknown flag is a usage error" $EXIT_USAGE --no-such-flag "test_file.bin" "test_dir/"
+test_case "Missing arguments is a usage error" $EXIT_USAGE "test_file.bin"
+test_case "Bad stream count is a usage error" $EXIT_USAGE -s many "test_file.bin" "test_dir/"
+test_case "Missing source is not found" $EXIT_NOT_FOUND "nonexistent_file.bin" "user@localhost:/tmp/"
+test_case "Missing destination directory is not found" $EXIT_NOT_FOUND "test_file.bin" "test_dir/missing/"
+test_case "Unresolvable host" $EXIT_RESOLVE "test_file.bin" "user@zap-test.invalid:/tmp/"
+test_case "Refused connection" $EXIT_CONNECT -p 1 "test_file.bin" "user@127.0.0.1:/tmp/"
+test_case "Refused connection on a pull" $EXIT_CONNECT -p 1 "user@127.0.0.1:/tmp/file" "test_dir/"
+test_case "Local copy succeeds" $EXIT_OK "test_file.bin" "test_dir/copy.bin"
+json_case "JSON report on a local copy" $EXIT_OK --json "test_file.bin" "test_dir/copy_json.bin"
+test_case "JSON progress on a local copy" $EXIT_OK --json-progress "test_file.bin" "test_dir/copy_ndjson.bin"
+json_case "JSON error keeps the exit code" $EXIT_NOT_FOUND --json "nonexistent_file.bin" "test_dir/"
+json_case "JSON error from a failed --direct run" $EXIT_CONNECT --json --direct -p 1 "user@127.0.0.1:/tmp/file" "user@127.0.0.1:/tmp/"
 
 # ==========================================
 # Clean up